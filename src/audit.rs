@@ -0,0 +1,79 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use chrono::DateTime;
+use chrono::SecondsFormat;
+use chrono::Utc;
+
+use crate::commands::Command;
+use crate::error::Error;
+
+/// Одно действие администратора: кто, когда, с какого адреса и какую команду выполнил.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub time: SystemTime,
+    pub actor: String,
+    pub address: IpAddr,
+    pub command: Command,
+}
+
+/// Выводит запись одной строкой: `<время в RFC 3339> <кто> <адрес> <команда>`.
+impl Display for AuditEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            DateTime::<Utc>::from(self.time).to_rfc3339_opts(SecondsFormat::Secs, true),
+            self.actor,
+            self.address,
+            self.command
+        )
+    }
+}
+
+/// Журнал действий администраторов. Записи только добавляются и никогда не изменяются.
+pub trait AuditLog: Send + Sync {
+    fn record(&self, entry: &AuditEntry) -> Result<(), Error>;
+}
+
+impl<T: AuditLog + ?Sized> AuditLog for Box<T> {
+    fn record(&self, entry: &AuditEntry) -> Result<(), Error> {
+        (**self).record(entry)
+    }
+}
+
+/// Журнал в текстовом файле, по записи на строку. Каждая запись сразу дописывается в конец файла,
+/// чтобы она не потерялась, даже если сервер упадет.
+pub struct FileAuditLog {
+    file: Mutex<File>,
+}
+
+impl FileAuditLog {
+    /// Открывает файл на дописывание. Если файла нет, он будет создан.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(Error::IO)?;
+
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl AuditLog for FileAuditLog {
+    fn record(&self, entry: &AuditEntry) -> Result<(), Error> {
+        let mut file = self.file.lock().unwrap();
+        file.write_all(format!("{entry}\n").as_bytes())
+            .map_err(Error::IO)?;
+        file.sync_data().map_err(Error::IO)
+    }
+}
@@ -0,0 +1,74 @@
+use simple_chat::commands::Command;
+
+use crate::files::SendFile;
+use crate::local::LocalCommand;
+
+/// Результат дополнения по Tab.
+pub enum Completion {
+    /// Слово под курсором нужно заменить на это.
+    Replace {
+        start: usize,
+        word: String,
+    },
+    /// Подходит несколько вариантов, общий префикс у них уже введен.
+    Candidates(Vec<String>),
+    None,
+}
+
+/// Дополняет слово перед курсором: `%команду` и `/команду` в начале строки, `@имя` - где угодно.
+pub fn complete(input: &[char], cursor: usize, users: &[String]) -> Completion {
+    let start = input[..cursor]
+        .iter()
+        .rposition(|c| *c == ' ')
+        .map_or(0, |i| i + 1);
+    let word: String = input[start..cursor].iter().collect();
+
+    let (prefix, candidates): (char, Vec<&str>) = match word.chars().next() {
+        Some('%') if start == 0 => (
+            '%',
+            Command::NAMES
+                .iter()
+                .copied()
+                .chain([SendFile::COMMAND_NAME])
+                .collect(),
+        ),
+        Some('/') if start == 0 => ('/', LocalCommand::NAMES.to_vec()),
+        Some('@') => ('@', users.iter().map(String::as_str).collect()),
+        _ => return Completion::None,
+    };
+
+    let typed = &word[prefix.len_utf8()..];
+    let mut matches: Vec<&str> = candidates
+        .into_iter()
+        .filter(|candidate| candidate.starts_with(typed))
+        .collect();
+    matches.sort();
+    matches.dedup();
+
+    match matches.as_slice() {
+        [] => Completion::None,
+        [single] => Completion::Replace {
+            start,
+            word: format!("{prefix}{single} "),
+        },
+        [first, rest @ ..] => {
+            let common = rest.iter().fold(first.to_string(), |common, candidate| {
+                common
+                    .chars()
+                    .zip(candidate.chars())
+                    .take_while(|(a, b)| a == b)
+                    .map(|(a, _)| a)
+                    .collect()
+            });
+
+            if common.len() > typed.len() {
+                Completion::Replace {
+                    start,
+                    word: format!("{prefix}{common}"),
+                }
+            } else {
+                Completion::Candidates(matches.iter().map(|m| format!("{prefix}{m}")).collect())
+            }
+        }
+    }
+}
@@ -0,0 +1,460 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::thread::spawn;
+use std::time::Duration;
+
+use sha2::Digest;
+use sha2::Sha256;
+use simple_chat::commands::Command;
+use simple_chat::commands::FileCancel;
+use simple_chat::commands::FileChunk;
+use simple_chat::commands::FileEnd;
+use simple_chat::commands::FileOffer;
+use simple_chat::commands::MAX_FILE_CHUNK_SIZE;
+use simple_chat::error::Error;
+
+use crate::session::Writer;
+
+/// Пауза между кусками файла. Сообщения, которые пользователь вводит во время передачи,
+/// успевают уйти на сервер между кусками, а сервер не упирается в ограничение частоты.
+const CHUNK_INTERVAL: Duration = Duration::from_millis(4);
+
+/// Команда клиента `%send_file <user> <path>`. На сервер уходит не она, а `%file_offer`
+/// с размером и контрольной суммой файла.
+pub struct SendFile {
+    user: String,
+    path: PathBuf,
+}
+
+impl SendFile {
+    pub const COMMAND_NAME: &'static str = "send_file";
+
+    /// Возвращает `None`, если строка не является командой `%send_file`.
+    pub fn new(input: &str) -> Option<Result<Self, Error>> {
+        let input = input.strip_prefix('%')?;
+        let (name, arguments) = input.split_once(' ').unwrap_or((input, ""));
+        if name != Self::COMMAND_NAME {
+            return None;
+        }
+
+        Some(match arguments.trim().split_once(' ') {
+            Some((user, path)) if !path.trim().is_empty() => Ok(Self {
+                user: user.to_string(),
+                path: PathBuf::from(path.trim()),
+            }),
+            _ => Err(Error::MissingArgument),
+        })
+    }
+}
+
+/// Что клиенту делать со строкой от сервера после того, как ее посмотрели передачи файлов.
+pub enum FileEvent {
+    /// Строка не относится к передаче файлов.
+    Other,
+    /// Служебная строка (кусок файла), показывать ее не нужно.
+    Hidden,
+    /// Вместо строки нужно показать это сообщение.
+    Notice(String),
+}
+
+struct Outgoing {
+    to: String,
+    path: PathBuf,
+    name: String,
+    checksum: String,
+}
+
+struct Incoming {
+    from: String,
+    name: String,
+    size: u64,
+    checksum: String,
+    //
+    // Недокачанный файл (`<name>.<id>.part` в каталоге загрузок). Создается с первым куском.
+    //
+    part: Option<(PathBuf, File)>,
+    received: u64,
+    hasher: Sha256,
+}
+
+#[derive(Default)]
+struct State {
+    //
+    // Файлы, предложенные командой %send_file, которым сервер еще не присвоил идентификатор.
+    // Ответ %file_offered сопоставляем с ними по контрольной сумме и имени.
+    //
+    offered: Vec<Outgoing>,
+    outgoing: HashMap<u64, Outgoing>,
+    incoming: HashMap<u64, Incoming>,
+}
+
+/// Передачи файлов на стороне клиента. Общие для потока ввода, потока чтения сообщений от сервера
+/// и потоков, отправляющих файлы.
+#[derive(Clone)]
+pub struct Files {
+    downloads: PathBuf,
+    writer: Writer,
+    state: Arc<Mutex<State>>,
+}
+
+impl Files {
+    /// Принятые файлы сохраняются в каталог `downloads`, он создается при первой загрузке.
+    pub fn new(downloads: impl Into<PathBuf>, writer: Writer) -> Self {
+        Self {
+            downloads: downloads.into(),
+            writer,
+            state: Arc::default(),
+        }
+    }
+
+    /// Считает размер и контрольную сумму файла и возвращает предложение, которое нужно отправить на сервер.
+    pub fn offer(&self, cmd: SendFile) -> Result<Command, Error> {
+        let name = cmd
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or(Error::InvalidInput)?;
+
+        let mut file = File::open(&cmd.path).map_err(Error::IO)?;
+        let mut hasher = Sha256::new();
+        let size = std::io::copy(&mut file, &mut hasher).map_err(Error::IO)?;
+        let checksum = format!("{:x}", hasher.finalize());
+
+        self.state.lock().unwrap().offered.push(Outgoing {
+            to: cmd.user.clone(),
+            path: cmd.path,
+            name: name.clone(),
+            checksum: checksum.clone(),
+        });
+
+        Ok(Command::FileOffer(FileOffer {
+            user: cmd.user,
+            size,
+            checksum,
+            name,
+        }))
+    }
+
+    /// Учитывает команду, которую пользователь отправил на сервер: после отказа от файла
+    /// или отмены передачи сервер нам уже ничего о ней не пришлет.
+    pub fn sent(&self, cmd: &Command) {
+        match cmd {
+            Command::FileDecline(cmd) => self.forget(cmd.id),
+            Command::FileCancel(cmd) => self.forget(cmd.id),
+            _ => {}
+        }
+    }
+
+    /// Разбирает строку от сервера, если она относится к передаче файлов.
+    pub fn on_server_message(&self, line: &str) -> FileEvent {
+        let Some((name, arguments)) = line
+            .strip_prefix("%file_")
+            .map(|line| line.split_once(' ').unwrap_or((line, "")))
+        else {
+            return FileEvent::Other;
+        };
+        let mut parts = arguments.splitn(2, ' ');
+        let id = parts.next().and_then(|id| id.parse::<u64>().ok());
+
+        match (name, id) {
+            ("chunk", Some(_)) => match Command::new(line) {
+                Ok(Command::FileChunk(chunk)) => self.on_chunk(chunk),
+                _ => FileEvent::Hidden,
+            },
+            ("offer", _) => self.offered_to_us(arguments),
+            ("offered", _) => self.offered_by_us(arguments),
+            ("accepted", Some(id)) => self.accepted(id),
+            ("end", Some(id)) => self.end(id),
+            ("sent", Some(id)) => match self.state.lock().unwrap().outgoing.remove(&id) {
+                Some(outgoing) => {
+                    FileEvent::Notice(format!("{} sent to {}", outgoing.name, outgoing.to))
+                }
+                None => FileEvent::Hidden,
+            },
+            ("declined", Some(id)) => self.finished(id, "declined"),
+            ("cancelled", Some(id)) => self.finished(id, "cancelled"),
+            ("failed", Some(id)) => {
+                let reason = parts.next().unwrap_or("unknown error");
+                self.finished(id, &format!("failed: {reason}"))
+            }
+            _ => FileEvent::Other,
+        }
+    }
+
+    //
+    // "%file_offer <from> <id> <size> <checksum> <name>": нам предлагают файл.
+    //
+
+    fn offered_to_us(&self, arguments: &str) -> FileEvent {
+        let mut parts = arguments.splitn(5, ' ');
+        let (Some(from), Some(Ok(id)), Some(Ok(size)), Some(checksum), Some(name)) = (
+            parts.next(),
+            parts.next().map(str::parse::<u64>),
+            parts.next().map(str::parse::<u64>),
+            parts.next(),
+            parts.next(),
+        ) else {
+            return FileEvent::Other;
+        };
+
+        self.state.lock().unwrap().incoming.insert(
+            id,
+            Incoming {
+                from: from.to_string(),
+                name: name.to_string(),
+                size,
+                checksum: checksum.to_string(),
+                part: None,
+                received: 0,
+                hasher: Sha256::new(),
+            },
+        );
+
+        FileEvent::Notice(format!(
+            "{from} wants to send you {name} ({size} bytes): %file_accept {id} or %file_decline {id}"
+        ))
+    }
+
+    //
+    // "%file_offered <to> <id> <size> <checksum> <name>": сервер принял наше предложение.
+    //
+
+    fn offered_by_us(&self, arguments: &str) -> FileEvent {
+        let mut parts = arguments.splitn(5, ' ');
+        let (Some(to), Some(Ok(id)), Some(_), Some(checksum), Some(name)) = (
+            parts.next(),
+            parts.next().map(str::parse::<u64>),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+        ) else {
+            return FileEvent::Other;
+        };
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(index) = state
+            .offered
+            .iter()
+            .position(|offer| offer.checksum == checksum && offer.name == name)
+        {
+            let offer = state.offered.remove(index);
+            state.outgoing.insert(id, offer);
+        }
+
+        FileEvent::Notice(format!(
+            "offered {name} to {to}, waiting for an answer (transfer {id})"
+        ))
+    }
+
+    //
+    // Получатель согласился: отправляем файл кусками в отдельном потоке, чтобы не мешать переписке.
+    //
+
+    fn accepted(&self, id: u64) -> FileEvent {
+        let Some((name, path)) = self
+            .state
+            .lock()
+            .unwrap()
+            .outgoing
+            .get(&id)
+            .map(|outgoing| (outgoing.name.clone(), outgoing.path.clone()))
+        else {
+            return FileEvent::Hidden;
+        };
+
+        let files = self.clone();
+        spawn(move || {
+            if files.send_chunks(id, &path).is_err() {
+                files.forget(id);
+                files
+                    .writer
+                    .send_command(&Command::FileCancel(FileCancel { id }))
+                    .ok();
+            }
+        });
+
+        FileEvent::Notice(format!("sending {name}..."))
+    }
+
+    fn send_chunks(&self, id: u64, path: &Path) -> Result<(), Error> {
+        let mut file = File::open(path).map_err(Error::IO)?;
+        let mut buffer = vec![0; MAX_FILE_CHUNK_SIZE];
+
+        loop {
+            let read = file.read(&mut buffer).map_err(Error::IO)?;
+            if read == 0 {
+                break;
+            }
+
+            //
+            // Передачу отменили или она завершилась ошибкой на сервере - дальше не отправляем.
+            //
+
+            if !self.state.lock().unwrap().outgoing.contains_key(&id) {
+                return Ok(());
+            }
+
+            self.writer.send_command(&Command::FileChunk(FileChunk {
+                id,
+                data: buffer[..read].to_vec(),
+            }))?;
+            sleep(CHUNK_INTERVAL);
+        }
+
+        self.writer.send_command(&Command::FileEnd(FileEnd { id }))
+    }
+
+    /// Кусок файла: построчно он приходит командой `%file_chunk`, а кадром - как есть.
+    pub fn on_chunk(&self, cmd: FileChunk) -> FileEvent {
+        let mut state = self.state.lock().unwrap();
+        let Some(incoming) = state.incoming.get_mut(&cmd.id) else {
+            return FileEvent::Hidden;
+        };
+
+        match self.write_chunk(cmd.id, incoming, &cmd.data) {
+            Ok(()) => FileEvent::Hidden,
+            Err(error) => {
+                let name = incoming.name.clone();
+                drop(state);
+                self.forget(cmd.id);
+                self.writer
+                    .send_command(&Command::FileCancel(FileCancel { id: cmd.id }))
+                    .ok();
+                FileEvent::Notice(format!("could not save {name}: {error}"))
+            }
+        }
+    }
+
+    fn write_chunk(&self, id: u64, incoming: &mut Incoming, data: &[u8]) -> Result<(), Error> {
+        if incoming.received + data.len() as u64 > incoming.size {
+            return Err(Error::FileTooLarge);
+        }
+
+        if incoming.part.is_none() {
+            fs::create_dir_all(&self.downloads).map_err(Error::IO)?;
+            let path = self.downloads.join(format!("{}.{id}.part", incoming.name));
+            let file = File::create(&path).map_err(Error::IO)?;
+            incoming.part = Some((path, file));
+        }
+
+        let (_, file) = incoming.part.as_mut().unwrap();
+        file.write_all(data).map_err(Error::IO)?;
+        incoming.received += data.len() as u64;
+        incoming.hasher.update(data);
+        Ok(())
+    }
+
+    //
+    // Отправитель передал все куски: сверяем размер и контрольную сумму и даем файлу настоящее имя.
+    //
+
+    fn end(&self, id: u64) -> FileEvent {
+        let Some(incoming) = self.state.lock().unwrap().incoming.remove(&id) else {
+            return FileEvent::Hidden;
+        };
+
+        let checksum = format!("{:x}", incoming.hasher.finalize());
+        if incoming.received != incoming.size || checksum != incoming.checksum {
+            if let Some((path, _)) = incoming.part {
+                fs::remove_file(path).ok();
+            }
+            return FileEvent::Notice(format!(
+                "{} from {} is corrupted: {}",
+                incoming.name,
+                incoming.from,
+                Error::ChecksumMismatch
+            ));
+        }
+
+        //
+        // Пустой файл приходит без кусков, и файла .part у него нет.
+        //
+
+        let saved = unique_path(&self.downloads, &incoming.name);
+        let result = match incoming.part {
+            Some((part, file)) => {
+                drop(file);
+                fs::rename(part, &saved)
+            }
+            None => {
+                fs::create_dir_all(&self.downloads).and_then(|_| File::create(&saved).map(drop))
+            }
+        };
+
+        match result {
+            Ok(()) => FileEvent::Notice(format!(
+                "received {} from {}, saved to {}",
+                incoming.name,
+                incoming.from,
+                saved.display()
+            )),
+            Err(error) => FileEvent::Notice(format!("could not save {}: {error}", incoming.name)),
+        }
+    }
+
+    fn finished(&self, id: u64, status: &str) -> FileEvent {
+        let name = {
+            let state = self.state.lock().unwrap();
+            let outgoing = state
+                .outgoing
+                .get(&id)
+                .map(|outgoing| outgoing.name.clone());
+            outgoing.or_else(|| {
+                state
+                    .incoming
+                    .get(&id)
+                    .map(|incoming| incoming.name.clone())
+            })
+        };
+        self.forget(id);
+
+        match name {
+            Some(name) => FileEvent::Notice(format!("transfer of {name} {status}")),
+            None => FileEvent::Hidden,
+        }
+    }
+
+    fn forget(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.outgoing.remove(&id);
+
+        if let Some((path, _)) = state
+            .incoming
+            .remove(&id)
+            .and_then(|incoming| incoming.part)
+        {
+            fs::remove_file(path).ok();
+        }
+    }
+}
+
+//
+// Не перезаписываем уже скачанные файлы: "report.log", "report (1).log", "report (2).log" и так далее.
+//
+
+fn unique_path(directory: &Path, name: &str) -> PathBuf {
+    let path = directory.join(name);
+    if !path.exists() {
+        return path;
+    }
+
+    let name = Path::new(name);
+    let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = name
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+
+    (1..)
+        .map(|n| directory.join(format!("{stem} ({n}){extension}")))
+        .find(|path| !path.exists())
+        .unwrap()
+}
@@ -0,0 +1,32 @@
+use std::path::PathBuf;
+
+use simple_chat::error::Error;
+
+/// Команды клиента, которые обрабатываются на месте и не отправляются на сервер.
+/// Начинаются с `/`, чтобы не путаться с командами сервера (`%`).
+pub enum LocalCommand {
+    Clear,
+    Quit,
+    Reconnect,
+    Log(PathBuf),
+}
+
+impl LocalCommand {
+    pub const NAMES: &'static [&'static str] = &["clear", "quit", "reconnect", "log"];
+
+    /// Возвращает `None`, если строка не является локальной командой.
+    pub fn new(input: &str) -> Option<Result<Self, Error>> {
+        let input = input.strip_prefix('/')?;
+        let (name, argument) = input.split_once(' ').unwrap_or((input, ""));
+        let argument = argument.trim();
+
+        Some(match name {
+            "clear" => Ok(Self::Clear),
+            "quit" => Ok(Self::Quit),
+            "reconnect" => Ok(Self::Reconnect),
+            "log" if argument.is_empty() => Err(Error::MissingArgument),
+            "log" => Ok(Self::Log(PathBuf::from(argument))),
+            _ => Err(Error::UnknownCommand),
+        })
+    }
+}
@@ -0,0 +1,61 @@
+use chrono::DateTime;
+use chrono::Local;
+
+/// Время из сообщения сервера (UTC, RFC 3339) в местном часовом поясе: для сегодняшних сообщений
+/// только часы и минуты, для более старых - еще и дата.
+pub fn local_time(time: &str) -> Option<String> {
+    let time = DateTime::parse_from_rfc3339(time)
+        .ok()?
+        .with_timezone(&Local);
+
+    let format = if time.date_naive() == Local::now().date_naive() {
+        "%H:%M"
+    } else {
+        "%Y-%m-%d %H:%M"
+    };
+    Some(time.format(format).to_string())
+}
+
+/// Заменяет время в сообщении общего чата (`#<id> <время> ...`, в том числе с пометками
+/// `%sent` и `%mention`) на местное. Остальные строки, например личные сообщения со знаком `#`
+/// в тексте, возвращает как есть.
+pub fn localize(line: &str) -> String {
+    let head = ["%sent ", "%mention ", ""]
+        .into_iter()
+        .find(|head| line.starts_with(&format!("{head}#")));
+    let Some(head) = head else {
+        return line.to_string();
+    };
+    let rest = &line[head.len()..];
+    let mut parts = rest.splitn(3, ' ');
+
+    match (
+        parts.next(),
+        parts.next().and_then(local_time),
+        parts.next(),
+    ) {
+        (Some(id), Some(time), Some(rest)) => format!("{head}{id} [{time}] {rest}"),
+        _ => line.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn localize_chat_lines_only() {
+        for line in [
+            "#1 2024-05-01T12:30:00Z alex: привет",
+            "%sent #1 2024-05-01T12:30:00Z alex: привет",
+            "%mention #1 2024-05-01T12:30:00Z alex: привет, @roma",
+        ] {
+            let localized = localize(line);
+            assert!(!localized.contains("2024-05-01T12:30:00Z"), "{localized}");
+            assert!(localized.contains("#1 ["), "{localized}");
+        }
+
+        let dm = "%dm alex roma смотри #1 2024-05-01T12:30:00Z alex: привет";
+        assert_eq!(localize(dm), dm);
+    }
+}
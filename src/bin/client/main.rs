@@ -0,0 +1,225 @@
+mod completion;
+mod files;
+mod local;
+mod local_time;
+mod session;
+mod tui;
+
+use files::FileEvent;
+use files::Files;
+use files::SendFile;
+use local::LocalCommand;
+use session::Frames;
+use session::Log;
+use session::Session;
+use simple_chat::block;
+use simple_chat::codec::Frame;
+use simple_chat::codec::Framing;
+use simple_chat::commands::Bye;
+use simple_chat::commands::Command;
+use std::env;
+use std::io::stdin;
+use std::io::stdout;
+use std::io::IsTerminal;
+use std::io::Write;
+use std::sync::mpsc::channel;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::thread::spawn;
+
+fn main() {
+    //
+    // Устанавливаем TCP-соединение с сервером и здороваемся с ним. С флагом --framed вместо строк,
+    // разделенных переводом строки, обмениваемся с сервером кадрами с длиной в начале.
+    //
+
+    let framing = if env::args().any(|arg| arg == "--framed") {
+        Framing::Frames
+    } else {
+        Framing::Lines
+    };
+    let session = match Session::connect("localhost:8889", framing) {
+        Ok(session) => session,
+        Err(error) => {
+            eprintln!("cannot connect: {error}");
+            return;
+        }
+    };
+
+    //
+    // Принятые файлы сохраняем в каталог из флага --downloads, по умолчанию - в ./downloads.
+    //
+
+    let downloads = env::args()
+        .skip_while(|arg| arg != "--downloads")
+        .nth(1)
+        .unwrap_or_else(|| "downloads".to_string());
+    let files = Files::new(downloads, session.writer());
+
+    //
+    // По умолчанию запускаем полноэкранный интерфейс. Построчный режим включается флагом --line
+    // и используется автоматически, если ввод идет не из терминала (например, через pipe).
+    //
+
+    if !env::args().any(|arg| arg == "--line") && stdin().is_terminal() {
+        if let Err(error) = tui::run(session, files) {
+            eprintln!("{error}");
+        }
+        return;
+    }
+
+    //
+    // Запускаем первый поток, читающий сообщения от сервера.
+    // Соединения он получает через канал: после /reconnect туда отправляется новое соединение.
+    //
+
+    let (connections_tx, connections_rx) = channel();
+    connections_tx.send(session.reader().unwrap()).unwrap();
+
+    let log = session.log();
+    let server_files = files.clone();
+    let read_thread = spawn(move || {
+        read_messages_from_server_write_to_terminal(connections_rx, log, server_files)
+    });
+
+    //
+    // Запускаем второй поток, читающий сообщения из терминала и отравляющий их на сервер.
+    //
+
+    spawn(move || read_messages_from_terminal_write_to_server(session, connections_tx, files));
+
+    //
+    // Блокируем программу до тех пор, пока поток, читающий сообщения от сервера, не завершится.
+    // А завершится этот поток, когда соединение с сервером разорвано и переподключаться уже не нужно.
+    //
+
+    read_thread.join().unwrap();
+}
+
+fn read_messages_from_server_write_to_terminal(
+    connections: Receiver<Frames>,
+    log: Log,
+    files: Files,
+) {
+    for frames in connections {
+        for frame in frames {
+            //
+            // Куски файлов не показываем и в журнал не пишем, остальные события передачи файлов
+            // показываем понятным текстом.
+            //
+
+            let message = match frame {
+                Frame::FileChunk(chunk) => {
+                    if let FileEvent::Notice(notice) = files.on_chunk(chunk) {
+                        println!("{notice}");
+                    }
+                    continue;
+                }
+                frame => frame.to_string(),
+            };
+
+            match files.on_server_message(&message) {
+                FileEvent::Other => {}
+                FileEvent::Hidden => continue,
+                FileEvent::Notice(notice) => {
+                    log.write(&message);
+                    println!("{notice}");
+                    continue;
+                }
+            }
+
+            log.write(&message);
+
+            //
+            // Сообщение, в котором нас упомянули, сопровождаем звуковым сигналом терминала.
+            //
+
+            if message.starts_with("%mention ") {
+                print!("\x07");
+            }
+            println!("{}", local_time::localize(&message));
+        }
+
+        eprintln!("disconnected from server");
+    }
+}
+
+fn read_messages_from_terminal_write_to_server(
+    mut session: Session,
+    connections: Sender<Frames>,
+    files: Files,
+) {
+    let mut lines = stdin().lines().map(|maybe_message| maybe_message.unwrap());
+
+    while let Some(message) = lines.next() {
+        //
+        // "%begin" включает режим вставки: все строки до "%end" уходят одним многострочным сообщением.
+        //
+
+        if message.trim_end() == block::BEGIN {
+            eprintln!("multi-line message, finish it with {}", block::END);
+            let text: Vec<String> = lines
+                .by_ref()
+                .take_while(|line| line.trim_end() != block::END)
+                .collect();
+
+            if let Err(error) = session.send_command(&Command::text(text.join("\n"))) {
+                eprintln!("{error}");
+            }
+            continue;
+        }
+
+        //
+        // Строки, начинающиеся с '/', - локальные команды, на сервер они не попадают.
+        //
+
+        if let Some(cmd) = LocalCommand::new(&message) {
+            let result = match cmd {
+                Ok(LocalCommand::Clear) => {
+                    print!("\x1b[2J\x1b[H");
+                    stdout().flush().ok();
+                    Ok(())
+                }
+                //
+                // Прощаемся с сервером и перестаем читать терминал. Сервер закроет соединение,
+                // а вместе с ним завершится и поток, читающий сообщения от сервера.
+                //
+                Ok(LocalCommand::Quit) => {
+                    session.send_command(&Command::Bye(Bye)).ok();
+                    return;
+                }
+                Ok(LocalCommand::Reconnect) => session
+                    .reconnect()
+                    .and_then(|_| session.reader())
+                    .map(|reader| connections.send(reader).unwrap()),
+                Ok(LocalCommand::Log(path)) => session.log().open(&path),
+                Err(error) => Err(error),
+            };
+
+            if let Err(error) = result {
+                eprintln!("{error}");
+            }
+            continue;
+        }
+
+        //
+        // Разбираем введенную строку в команду, чтобы не отправлять на сервер заведомо неверные данные,
+        // и отправляем ее в том виде, в котором она передается по сети.
+        //
+
+        if let Some(cmd) = SendFile::new(&message) {
+            if let Err(error) = cmd
+                .and_then(|cmd| files.offer(cmd))
+                .and_then(|offer| session.send_command(&offer))
+            {
+                eprintln!("{error}");
+            }
+            continue;
+        }
+
+        match session.send(&message) {
+            Ok(cmd) => files.sent(&cmd),
+            Err(error) => eprintln!("{error}"),
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use simple_chat::codec::Frame;
+use simple_chat::codec::Framing;
+use simple_chat::codec::FRAMED_CAPABILITY;
+use simple_chat::commands::Command;
+use simple_chat::commands::Hello;
+use simple_chat::error::Error;
+
+/// Сколько байт клиент готов принять от сервера одной строкой, блоком или кадром.
+const MAX_FRAME_LENGTH: usize = 1024 * 1024;
+
+/// Соединение с сервером, которое можно переустановить командой `/reconnect`.
+pub struct Session {
+    address: String,
+    connection: Writer,
+    log: Log,
+}
+
+impl Session {
+    /// Подключается к серверу и здоровается с ним. Кадры используются, только если
+    /// их поддерживает сервер, иначе - строки. Строки используются и с сервером,
+    /// который не знает `%hello`.
+    pub fn connect(address: impl Into<String>, framing: Framing) -> Result<Self, Error> {
+        let address = address.into();
+        let (connection, framing) = open(&address, framing)?;
+
+        Ok(Self {
+            address,
+            connection: Writer {
+                connection: Arc::new(Mutex::new(connection)),
+                framing,
+            },
+            log: Log::default(),
+        })
+    }
+
+    /// Кадры от сервера из текущего соединения, для потока, читающего сообщения от сервера.
+    pub fn reader(&self) -> Result<Frames, Error> {
+        let connection = self
+            .connection
+            .connection
+            .lock()
+            .unwrap()
+            .try_clone()
+            .map_err(Error::IO)?;
+
+        Ok(Frames {
+            reader: BufReader::new(connection),
+            framing: self.connection.framing,
+        })
+    }
+
+    /// Общая половина соединения для записи. Остается рабочей и после `/reconnect`.
+    pub fn writer(&self) -> Writer {
+        self.connection.clone()
+    }
+
+    /// Закрывает текущее соединение и устанавливает новое с тем же сервером.
+    /// Поток, читавший старое соединение, получит конец потока и завершится.
+    /// Переключаться между строками и кадрами на ходу нельзя, поэтому сервер, который
+    /// не поддерживает кадры, на которых работало прежнее соединение, не подходит.
+    pub fn reconnect(&mut self) -> Result<(), Error> {
+        let (connection, framing) = open(&self.address, self.connection.framing)?;
+        if framing != self.connection.framing {
+            return Err(Error::InvalidInput);
+        }
+
+        let mut current = self.connection.connection.lock().unwrap();
+        current.shutdown(Shutdown::Both).ok();
+        *current = connection;
+        Ok(())
+    }
+
+    /// Разбирает строку, введенную пользователем, и отправляет получившуюся команду на сервер.
+    pub fn send(&mut self, line: &str) -> Result<Command, Error> {
+        let cmd = Command::new(line)?;
+        self.send_command(&cmd)?;
+        Ok(cmd)
+    }
+
+    pub fn send_command(&mut self, cmd: &Command) -> Result<(), Error> {
+        self.connection.send_command(cmd)?;
+        self.log.write(&format!("> {cmd}"));
+        Ok(())
+    }
+
+    pub fn log(&self) -> Log {
+        self.log.clone()
+    }
+}
+
+/// Соединение для отправки команд на сервер. Общее для потока ввода и потоков, отправляющих файлы:
+/// каждая команда записывается целиком под блокировкой, поэтому строки разных потоков не перемешиваются.
+#[derive(Clone)]
+pub struct Writer {
+    connection: Arc<Mutex<TcpStream>>,
+    framing: Framing,
+}
+
+impl Writer {
+    pub fn send_command(&self, cmd: &Command) -> Result<(), Error> {
+        let bytes = self.framing.encode(&Frame::command(cmd));
+        self.connection
+            .lock()
+            .unwrap()
+            .write_all(&bytes)
+            .map_err(Error::IO)
+    }
+}
+
+/// Кадры от сервера, пока соединение не закроется.
+pub struct Frames {
+    reader: BufReader<TcpStream>,
+    framing: Framing,
+}
+
+impl Iterator for Frames {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        self.framing
+            .read(&mut self.reader, MAX_FRAME_LENGTH, MAX_FRAME_LENGTH)
+            .ok()
+            .flatten()
+    }
+}
+
+/// Возможности клиента, которые он называет серверу в `%hello`, кроме кадров.
+const CAPABILITIES: &[&str] = &["multi_line", "files"];
+
+//
+// Устанавливает соединение и здоровается с сервером: называет версию протокола и свои возможности,
+// а кадры просит, только если они нужны. Ответ читаем по байту: если сервер согласился на кадры,
+// все, что придет после него, - уже кадры, и они должны достаться потоку чтения.
+// Возвращает соединение и то, как по нему договорились обмениваться командами.
+//
+
+fn open(address: &str, framing: Framing) -> Result<(TcpStream, Framing), Error> {
+    let mut connection = TcpStream::connect(address).map_err(Error::IO)?;
+
+    let mut capabilities: Vec<String> = CAPABILITIES.iter().map(|c| c.to_string()).collect();
+    if framing == Framing::Frames {
+        capabilities.push(FRAMED_CAPABILITY.to_string());
+    }
+    let hello = Hello {
+        version: Hello::VERSION,
+        capabilities,
+    };
+    connection
+        .write_all(Command::Hello(hello).to_wire().as_bytes())
+        .map_err(Error::IO)?;
+
+    let mut reply = Vec::new();
+    let mut byte = [0];
+    while byte[0] != b'\n' {
+        connection.read_exact(&mut byte).map_err(Error::IO)?;
+        reply.push(byte[0]);
+    }
+
+    //
+    // Сервер другой версии ответит своим %hello и ошибкой, с ним разговаривать не о чем.
+    // Сервер, который не знает %hello, ответит ошибкой, но строками с ним разговаривать можно:
+    // его ответ пропускаем и продолжаем без кадров.
+    //
+
+    let reply = String::from_utf8_lossy(&reply);
+    let server = match Command::new(&reply) {
+        Ok(Command::Hello(server)) if server.version == Hello::VERSION => server,
+        Ok(Command::Hello(_)) => return Err(Error::UnsupportedVersion),
+        _ => return Ok((connection, Framing::Lines)),
+    };
+
+    match framing {
+        Framing::Frames if server.has(FRAMED_CAPABILITY) => Ok((connection, Framing::Frames)),
+        _ => Ok((connection, Framing::Lines)),
+    }
+}
+
+/// Файл, куда записывается переписка после команды `/log <file>`.
+/// Общий для потока чтения и потока записи.
+#[derive(Clone, Default)]
+pub struct Log(Arc<Mutex<Option<File>>>);
+
+impl Log {
+    pub fn open(&self, path: &Path) -> Result<(), Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(Error::IO)?;
+        *self.0.lock().unwrap() = Some(file);
+        Ok(())
+    }
+
+    pub fn write(&self, line: &str) {
+        if let Some(file) = self.0.lock().unwrap().as_mut() {
+            writeln!(file, "{line}").ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::BufRead;
+    use std::net::TcpListener;
+    use std::thread::spawn;
+
+    #[test]
+    fn server_without_hello() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = spawn(move || {
+            let (connection, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(connection);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            reader.get_mut().write_all(b"unknown command\n").unwrap();
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            line
+        });
+
+        let (mut connection, framing) = open(&address, Framing::Frames).unwrap();
+        assert_eq!(framing, Framing::Lines);
+        connection.write_all(b"%show_users\n").unwrap();
+        assert_eq!(server.join().unwrap(), "%show_users\n");
+    }
+}
@@ -0,0 +1,984 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::io;
+use std::io::stdout;
+use std::io::Write;
+use std::sync::mpsc::channel;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::thread::spawn;
+use std::time::Duration;
+
+use crossterm::cursor::MoveTo;
+use crossterm::event;
+use crossterm::event::DisableBracketedPaste;
+use crossterm::event::EnableBracketedPaste;
+use crossterm::event::Event;
+use crossterm::event::KeyCode;
+use crossterm::event::KeyEvent;
+use crossterm::event::KeyEventKind;
+use crossterm::event::KeyModifiers;
+use crossterm::execute;
+use crossterm::queue;
+use crossterm::style::Attribute;
+use crossterm::style::Color;
+use crossterm::style::Print;
+use crossterm::style::ResetColor;
+use crossterm::style::SetAttribute;
+use crossterm::style::SetForegroundColor;
+use crossterm::terminal;
+use crossterm::terminal::Clear;
+use crossterm::terminal::ClearType;
+use crossterm::terminal::EnterAlternateScreen;
+use crossterm::terminal::LeaveAlternateScreen;
+use simple_chat::codec::Frame;
+use simple_chat::commands::mentions;
+use simple_chat::commands::Bye;
+use simple_chat::commands::Command;
+use simple_chat::commands::FileChunk;
+use simple_chat::commands::Login;
+use simple_chat::commands::ShowUsers;
+use simple_chat::error::Error;
+
+use crate::completion::complete;
+use crate::completion::Completion;
+use crate::files::FileEvent;
+use crate::files::Files;
+use crate::files::SendFile;
+use crate::local::LocalCommand;
+use crate::local_time::local_time;
+use crate::session::Frames;
+use crate::session::Session;
+
+const SIDEBAR_WIDTH: u16 = 20;
+const QUOTE_LENGTH: usize = 30;
+const NICK_COLORS: [Color; 6] = [
+    Color::Cyan,
+    Color::Green,
+    Color::Magenta,
+    Color::Blue,
+    Color::DarkYellow,
+    Color::DarkCyan,
+];
+
+/// Полноэкранный режим клиента: окно сообщений, список пользователей справа и строка ввода внизу.
+pub fn run(session: Session, files: Files) -> Result<(), Error> {
+    let _terminal = Terminal::enter().map_err(Error::IO)?;
+    let mut app = App::new(session, files)?;
+    app.refresh_users();
+
+    loop {
+        while let Ok(event) = app.events.try_recv() {
+            match event {
+                ServerEvent::Message(message) => app.on_server_message(&message),
+                ServerEvent::FileChunk(chunk) => {
+                    if let FileEvent::Notice(notice) = app.files.on_chunk(chunk) {
+                        app.push(vec![Span::new(notice, Color::DarkCyan)]);
+                    }
+                }
+                //
+                // Закрытие соединения, которое мы сами заменили командой /reconnect, не считается разрывом.
+                //
+                ServerEvent::Closed(generation) if generation == app.generation => {
+                    app.disconnected = true;
+                    app.push(vec![Span::new(
+                        "disconnected from server, type /reconnect to connect again",
+                        Color::Red,
+                    )]);
+                }
+                ServerEvent::Closed(_) => {}
+            }
+        }
+
+        if app.dirty {
+            app.draw().map_err(Error::IO)?;
+        }
+
+        if event::poll(Duration::from_millis(50)).map_err(Error::IO)? {
+            let quit = match event::read().map_err(Error::IO)? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => !app.on_key(key),
+                Event::Paste(text) => {
+                    app.on_paste(&text);
+                    false
+                }
+                Event::Resize(_, _) => {
+                    app.dirty = true;
+                    false
+                }
+                _ => false,
+            };
+
+            if quit {
+                return Ok(());
+            }
+        }
+    }
+}
+
+//
+// События от потоков, читающих сообщения от сервера. У каждого соединения свой номер (generation),
+// чтобы после /reconnect отличать закрытие старого соединения от разрыва текущего.
+//
+
+enum ServerEvent {
+    Message(String),
+    FileChunk(FileChunk),
+    Closed(u64),
+}
+
+fn spawn_reader(frames: Frames, generation: u64, events: Sender<ServerEvent>) {
+    spawn(move || {
+        for frame in frames {
+            let event = match frame {
+                Frame::FileChunk(chunk) => ServerEvent::FileChunk(chunk),
+                frame => ServerEvent::Message(frame.to_string()),
+            };
+            if events.send(event).is_err() {
+                return;
+            }
+        }
+        events.send(ServerEvent::Closed(generation)).ok();
+    });
+}
+
+//
+// Переводит терминал в "сырой" режим и альтернативный экран, а при уничтожении возвращает все обратно,
+// даже если программа завершилась паникой.
+//
+
+struct Terminal;
+
+impl Terminal {
+    fn enter() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(stdout(), EnterAlternateScreen, EnableBracketedPaste)?;
+        Ok(Self)
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        execute!(stdout(), DisableBracketedPaste, LeaveAlternateScreen).ok();
+        terminal::disable_raw_mode().ok();
+    }
+}
+
+#[derive(Clone)]
+struct Span {
+    text: String,
+    color: Option<Color>,
+    bold: bool,
+}
+
+impl Span {
+    fn new(text: impl Into<String>, color: Color) -> Self {
+        Self {
+            text: text.into(),
+            color: Some(color),
+            bold: false,
+        }
+    }
+
+    fn plain(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            color: None,
+            bold: false,
+        }
+    }
+}
+
+struct App {
+    session: Session,
+    files: Files,
+    /// Последний `%login`, вместе с токеном: после переподключения он повторяется.
+    login: Option<Login>,
+    disconnected: bool,
+    events: Receiver<ServerEvent>,
+    events_tx: Sender<ServerEvent>,
+    generation: u64,
+
+    //
+    // Окно сообщений: каждая строка уже разбита на раскрашенные куски.
+    // scroll - на сколько экранных строк окно прокручено вверх от самого низа.
+    //
+    messages: Vec<Vec<Span>>,
+    scroll: usize,
+
+    //
+    // Сообщения общего чата, которые есть в окне сообщений, по идентификатору.
+    // Нужны, чтобы применять события %edit и %delete и цитировать сообщение в ответах на него.
+    //
+    posted: HashMap<u64, Shown>,
+
+    //
+    // Строка ввода и история введенных строк.
+    //
+    input: Vec<char>,
+    cursor: usize,
+    history: Vec<String>,
+    history_index: Option<usize>,
+
+    //
+    // Список пользователей в сети. Сначала получаем его командой %show_users,
+    // а дальше обновляем по событиям %join и %leave.
+    // Ответы на запросы, которые клиент отправил сам, в окно сообщений не выводятся.
+    //
+    users: Vec<String>,
+    silent_users_replies: usize,
+
+    dirty: bool,
+}
+
+impl App {
+    fn new(session: Session, files: Files) -> Result<Self, Error> {
+        let (events_tx, events) = channel();
+        spawn_reader(session.reader()?, 0, events_tx.clone());
+
+        Ok(Self {
+            session,
+            files,
+            login: None,
+            disconnected: false,
+            events,
+            events_tx,
+            generation: 0,
+            messages: Vec::new(),
+            scroll: 0,
+            posted: HashMap::new(),
+            input: Vec::new(),
+            cursor: 0,
+            history: Vec::new(),
+            history_index: None,
+            users: Vec::new(),
+            silent_users_replies: 0,
+            dirty: true,
+        })
+    }
+
+    fn push(&mut self, line: Vec<Span>) {
+        self.messages.push(line);
+        self.dirty = true;
+    }
+
+    fn refresh_users(&mut self) {
+        if self
+            .session
+            .send_command(&Command::ShowUsers(ShowUsers::new()))
+            .is_ok()
+        {
+            self.silent_users_replies += 1;
+        }
+    }
+
+    fn on_server_message(&mut self, message: &str) {
+        //
+        // Куски файлов не показываем и в журнал не пишем.
+        //
+
+        match self.files.on_server_message(message) {
+            FileEvent::Other => {}
+            FileEvent::Hidden => return,
+            FileEvent::Notice(notice) => {
+                self.session.log().write(message);
+                self.push(vec![Span::new(notice, Color::DarkCyan)]);
+                return;
+            }
+        }
+
+        self.session.log().write(message);
+
+        if let Some(users) = message.strip_prefix("%users") {
+            self.users = users.split_whitespace().map(String::from).collect();
+            self.dirty = true;
+
+            if self.silent_users_replies > 0 {
+                self.silent_users_replies -= 1;
+            } else {
+                self.push(vec![Span::new(
+                    format!("online: {}", self.users.join(", ")),
+                    Color::DarkGrey,
+                )]);
+            }
+            return;
+        }
+
+        if let Some(user) = message.strip_prefix("%join ") {
+            if !self.users.iter().any(|u| u == user) {
+                self.users.push(user.to_string());
+                self.users.sort();
+            }
+            self.push(vec![Span::new(format!("{user} joined"), Color::DarkGrey)]);
+            return;
+        }
+
+        if let Some(user) = message.strip_prefix("%leave ") {
+            self.users.retain(|u| u != user);
+            self.push(vec![Span::new(format!("{user} left"), Color::DarkGrey)]);
+            return;
+        }
+
+        if let Some(notice) = message.strip_prefix("%notice ") {
+            self.push(vec![Span::new(notice, Color::DarkGrey)]);
+            return;
+        }
+
+        //
+        // Сообщение, в котором упомянули текущего пользователя: выделяем его и подаем звуковой сигнал.
+        //
+
+        if let Some(post) = message.strip_prefix("%mention ").and_then(parse_posted) {
+            let mut line = vec![Span {
+                text: "@ ".to_string(),
+                color: Some(Color::Yellow),
+                bold: true,
+            }];
+            line.extend(self.posted_line(&post));
+            self.push_posted(&post, line);
+
+            print!("\x07");
+            stdout().flush().ok();
+            return;
+        }
+
+        //
+        // Собственные сообщения сервер возвращает с пометкой %sent, уже с идентификатором.
+        //
+
+        if let Some(post) = message.strip_prefix("%sent ").and_then(parse_posted) {
+            let line = self.posted_line(&post);
+            self.push_posted(&post, line);
+            return;
+        }
+
+        if let Some(edit) = message.strip_prefix("%edit ") {
+            let (id, text) = edit.split_once(' ').unwrap_or((edit, ""));
+            let posted = id
+                .parse()
+                .ok()
+                .and_then(|id| Some((id, self.posted.get_mut(&id)?)));
+
+            if let Some((id, shown)) = posted {
+                shown.text = text.to_string();
+                shown.edited = true;
+                self.redraw(id);
+            }
+            return;
+        }
+
+        //
+        // Реакции показываем в конце строки сообщения: "👍 2 🍕 1".
+        //
+
+        if let Some(reaction) = message.strip_prefix("%reaction ") {
+            let mut parts = reaction.splitn(3, ' ');
+            if let (Some(Ok(id)), Some(reaction), Some(Ok(count))) = (
+                parts.next().map(str::parse),
+                parts.next(),
+                parts.next().map(str::parse),
+            ) {
+                if let Some(shown) = self.posted.get_mut(&id) {
+                    if count == 0 {
+                        shown.reactions.remove(reaction);
+                    } else {
+                        shown.reactions.insert(reaction.to_string(), count);
+                    }
+                    self.redraw(id);
+                }
+            }
+            return;
+        }
+
+        if let Some(id) = message.strip_prefix("%delete ") {
+            if let Some(shown) = id.parse().ok().and_then(|id| self.posted.remove(&id)) {
+                self.messages[shown.index] = vec![Span::new(
+                    format!("#{id} {}: message deleted", shown.from),
+                    Color::DarkGrey,
+                )];
+                self.dirty = true;
+            }
+            return;
+        }
+
+        if let Some(count) = message.strip_prefix("%history ") {
+            self.push(vec![Span::new(
+                format!("history: {count} messages"),
+                Color::DarkGrey,
+            )]);
+            return;
+        }
+
+        if let Some(count) = message.strip_prefix("%thread ") {
+            self.push(vec![Span::new(
+                format!("thread: {count} messages"),
+                Color::DarkGrey,
+            )]);
+            return;
+        }
+
+        if let Some(count) = message.strip_prefix("%mentions ") {
+            self.push(vec![Span::new(
+                format!("unread mentions: {count}"),
+                Color::DarkGrey,
+            )]);
+            return;
+        }
+
+        if let Some(dm) = message.strip_prefix("%dm ") {
+            let mut parts = dm.splitn(3, ' ');
+            if let (Some(from), Some(to), Some(text)) = (parts.next(), parts.next(), parts.next()) {
+                self.push(vec![
+                    Span::new(format!("[dm {from} → {to}] "), Color::Magenta),
+                    Span::plain(text),
+                ]);
+                return;
+            }
+        }
+
+        if let Some(users) = message.strip_prefix("%dms") {
+            let users: Vec<&str> = users.split_whitespace().collect();
+            self.push(vec![Span::new(
+                format!("conversations: {}", users.join(", ")),
+                Color::DarkGrey,
+            )]);
+            return;
+        }
+
+        if let Some(stats) = message.strip_prefix("%stats ") {
+            self.push(vec![Span::new(format!("stats: {stats}"), Color::DarkGrey)]);
+            return;
+        }
+
+        //
+        // Обычное сообщение приходит в виде "#<id> <время> <имя>: <текст>", ответ - "#<id> <время> ^<parent> <имя>: <текст>".
+        // Все остальное - ответы сервера об ошибках.
+        //
+
+        match parse_posted(message) {
+            Some(post) => {
+                let line = self.posted_line(&post);
+                self.push_posted(&post, line);
+            }
+            None => self.push(vec![Span::new(message, Color::Red)]),
+        }
+    }
+
+    fn push_posted(&mut self, post: &Post, line: Vec<Span>) {
+        self.posted.insert(
+            post.id,
+            Shown {
+                index: self.messages.len(),
+                time: post.time.to_string(),
+                parent: post.parent,
+                from: post.from.to_string(),
+                text: post.text.to_string(),
+                edited: false,
+                reactions: BTreeMap::new(),
+            },
+        );
+        self.push(line);
+    }
+
+    //
+    // Перерисовывает показанное сообщение после исправления или изменения реакций.
+    //
+
+    fn redraw(&mut self, id: u64) {
+        let Some(shown) = self.posted.get(&id) else {
+            return;
+        };
+
+        let mut line = self.posted_line(&Post {
+            id,
+            time: &shown.time,
+            parent: shown.parent,
+            from: &shown.from,
+            text: &shown.text,
+        });
+        if shown.edited {
+            line.push(Span::new(" (edited)", Color::DarkGrey));
+        }
+        for (reaction, count) in &shown.reactions {
+            line.push(Span::new(format!(" {reaction} {count}"), Color::DarkYellow));
+        }
+
+        self.messages[shown.index] = line;
+        self.dirty = true;
+    }
+
+    fn posted_line(&self, post: &Post) -> Vec<Span> {
+        let time = local_time(post.time).unwrap_or_default();
+        let mut line = vec![Span::new(format!("{time} #{} ", post.id), Color::DarkGrey)];
+        if let Some(parent) = post.parent {
+            line.push(self.quote(parent));
+        }
+        line.extend(self.chat_line(post.from, post.text));
+        line
+    }
+
+    //
+    // Начало сообщения, на которое отвечают. Если его нет в окне (например, после /clear), - только идентификатор.
+    //
+
+    fn quote(&self, parent: u64) -> Span {
+        let text = match self.posted.get(&parent) {
+            Some(shown) => {
+                let mut snippet: String = shown
+                    .text
+                    .chars()
+                    .take(QUOTE_LENGTH)
+                    .map(|c| if c == '\n' { ' ' } else { c })
+                    .collect();
+                if shown.text.chars().count() > QUOTE_LENGTH {
+                    snippet.push('…');
+                }
+                format!("↪ {}: \"{snippet}\" ", shown.from)
+            }
+            None => format!("↪ #{parent} "),
+        };
+
+        Span::new(text, Color::DarkGrey)
+    }
+
+    fn chat_line(&self, from: &str, text: &str) -> Vec<Span> {
+        let mut line = vec![
+            Span {
+                text: from.to_string(),
+                color: Some(nick_color(from)),
+                bold: true,
+            },
+            Span::plain(": "),
+        ];
+
+        //
+        // Подсвечиваем упоминания (@имя), а упоминания текущего пользователя - еще и жирным.
+        //
+
+        for (i, word) in text.split(' ').enumerate() {
+            if i > 0 {
+                line.push(Span::plain(" "));
+            }
+
+            match mentions(word).first() {
+                Some(name) => line.push(Span {
+                    text: word.to_string(),
+                    color: Some(Color::Yellow),
+                    bold: self.login.as_ref().map(|login| &login.id) == Some(name),
+                }),
+                None => line.push(Span::plain(word)),
+            }
+        }
+
+        line
+    }
+
+    /// Возвращает `false`, если пользователь хочет выйти.
+    fn on_key(&mut self, key: KeyEvent) -> bool {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let alt = key.modifiers.contains(KeyModifiers::ALT);
+        self.dirty = true;
+
+        match key.code {
+            KeyCode::Esc => return false,
+            KeyCode::Char('c') | KeyCode::Char('d') if ctrl => return false,
+            KeyCode::Char('a') if ctrl => self.cursor = 0,
+            KeyCode::Char('e') if ctrl => self.cursor = self.input.len(),
+            KeyCode::Char('u') if ctrl => {
+                self.input.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            KeyCode::Char(c) if !ctrl => {
+                self.input.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Delete if self.cursor < self.input.len() => {
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.len(),
+            KeyCode::Up => self.history_move(true),
+            KeyCode::Down => self.history_move(false),
+            KeyCode::PageUp => self.scroll += 10,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::Tab => self.complete(),
+            //
+            // Alt+Enter переносит строку: так набирается многострочное сообщение.
+            //
+            KeyCode::Enter if alt => {
+                self.input.insert(self.cursor, '\n');
+                self.cursor += 1;
+            }
+            KeyCode::Enter => return self.submit(),
+            _ => self.dirty = false,
+        }
+
+        true
+    }
+
+    //
+    // Вставленный текст (например, стектрейс) попадает в строку ввода целиком, вместе с переводами строк,
+    // и отправляется одним сообщением.
+    //
+
+    fn on_paste(&mut self, text: &str) {
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        let length = text.chars().count();
+        self.input.splice(self.cursor..self.cursor, text.chars());
+        self.cursor += length;
+        self.dirty = true;
+    }
+
+    fn complete(&mut self) {
+        match complete(&self.input, self.cursor, &self.users) {
+            Completion::Replace { start, word } => {
+                let word: Vec<char> = word.chars().collect();
+                let length = word.len();
+                self.input.splice(start..self.cursor, word);
+                self.cursor = start + length;
+            }
+            Completion::Candidates(candidates) => {
+                self.push(vec![Span::new(candidates.join("  "), Color::DarkGrey)]);
+            }
+            Completion::None => {}
+        }
+    }
+
+    fn history_move(&mut self, back: bool) {
+        let index = match (self.history_index, back) {
+            (None, true) if !self.history.is_empty() => Some(self.history.len() - 1),
+            (Some(i), true) => Some(i.saturating_sub(1)),
+            (Some(i), false) if i + 1 < self.history.len() => Some(i + 1),
+            (Some(_), false) => None,
+            (index, _) => index,
+        };
+
+        self.history_index = index;
+        self.input = match index {
+            Some(i) => self.history[i].chars().collect(),
+            None => Vec::new(),
+        };
+        self.cursor = self.input.len();
+    }
+
+    /// Возвращает `false`, если пользователь хочет выйти.
+    fn submit(&mut self) -> bool {
+        let line: String = self.input.drain(..).collect();
+        self.cursor = 0;
+        self.history_index = None;
+        self.scroll = 0;
+
+        if line.is_empty() {
+            return true;
+        }
+
+        self.history.push(line.clone());
+
+        //
+        // Многострочный ввод - всегда сообщение в общий чат, даже если он начинается с '/' или '%'.
+        //
+
+        if line.contains('\n') {
+            if let Err(error) = self.session.send_command(&Command::text(line)) {
+                self.push(vec![Span::new(error.to_string(), Color::Red)]);
+            }
+            return true;
+        }
+
+        //
+        // Строки, начинающиеся с '/', - локальные команды, на сервер они не попадают.
+        //
+
+        if let Some(cmd) = LocalCommand::new(&line) {
+            let result = match cmd {
+                Ok(LocalCommand::Clear) => {
+                    self.messages.clear();
+                    self.posted.clear();
+                    Ok(())
+                }
+                Ok(LocalCommand::Quit) => {
+                    self.session.send_command(&Command::Bye(Bye)).ok();
+                    return false;
+                }
+                Ok(LocalCommand::Reconnect) => self.reconnect(),
+                Ok(LocalCommand::Log(path)) => self.session.log().open(&path),
+                Err(error) => Err(error),
+            };
+
+            if let Err(error) = result {
+                self.push(vec![Span::new(error.to_string(), Color::Red)]);
+            }
+            return true;
+        }
+
+        //
+        // Собственные сообщения здесь не показываем: сервер вернет их с идентификатором (%sent).
+        //
+
+        if let Some(cmd) = SendFile::new(&line) {
+            if let Err(error) = cmd
+                .and_then(|cmd| self.files.offer(cmd))
+                .and_then(|offer| self.session.send_command(&offer))
+            {
+                self.push(vec![Span::new(error.to_string(), Color::Red)]);
+            }
+            return true;
+        }
+
+        match self.session.send(&line) {
+            Ok(Command::Login(cmd)) => self.login = Some(cmd),
+            Ok(cmd) => self.files.sent(&cmd),
+            Err(error) => self.push(vec![Span::new(error.to_string(), Color::Red)]),
+        }
+
+        true
+    }
+
+    //
+    // Переподключаемся к серверу и, если пользователь уже логинился, логинимся заново под тем же именем.
+    //
+
+    fn reconnect(&mut self) -> Result<(), Error> {
+        self.session.reconnect()?;
+        self.generation += 1;
+        spawn_reader(
+            self.session.reader()?,
+            self.generation,
+            self.events_tx.clone(),
+        );
+
+        self.disconnected = false;
+        self.push(vec![Span::new("reconnected", Color::DarkGrey)]);
+
+        if let Some(login) = self.login.clone() {
+            self.session.send_command(&Command::Login(login))?;
+        }
+
+        self.users.clear();
+        self.refresh_users();
+        Ok(())
+    }
+
+    fn draw(&mut self) -> io::Result<()> {
+        let (width, height) = terminal::size()?;
+        let sidebar = if width >= SIDEBAR_WIDTH * 3 {
+            SIDEBAR_WIDTH
+        } else {
+            0
+        };
+        let pane_width = (width - sidebar).saturating_sub(if sidebar > 0 { 1 } else { 0 });
+        let pane_height = height.saturating_sub(2) as usize;
+
+        let mut out = stdout();
+        queue!(out, Clear(ClearType::All))?;
+
+        //
+        // Окно сообщений: разбиваем все строки по ширине окна и показываем последние pane_height,
+        // с учетом прокрутки.
+        //
+
+        let rows: Vec<Vec<Span>> = self
+            .messages
+            .iter()
+            .flat_map(|line| wrap(line, pane_width as usize))
+            .collect();
+        self.scroll = self.scroll.min(rows.len().saturating_sub(pane_height));
+        let end = rows.len() - self.scroll;
+        let start = end.saturating_sub(pane_height);
+
+        for (y, row) in rows[start..end].iter().enumerate() {
+            queue!(out, MoveTo(0, y as u16))?;
+            for span in row {
+                print_span(&mut out, span)?;
+            }
+        }
+
+        //
+        // Список пользователей в сети.
+        //
+
+        if sidebar > 0 {
+            for y in 0..pane_height as u16 {
+                queue!(out, MoveTo(pane_width, y), Print("│"))?;
+            }
+
+            let title = format!(" online ({})", self.users.len());
+            queue!(
+                out,
+                MoveTo(pane_width + 1, 0),
+                SetAttribute(Attribute::Bold),
+                Print(title),
+                SetAttribute(Attribute::Reset)
+            )?;
+
+            for (y, user) in self
+                .users
+                .iter()
+                .take(pane_height.saturating_sub(1))
+                .enumerate()
+            {
+                let name: String = user.chars().take(sidebar as usize - 2).collect();
+                queue!(
+                    out,
+                    MoveTo(pane_width + 2, y as u16 + 1),
+                    SetForegroundColor(nick_color(user)),
+                    Print(name),
+                    ResetColor
+                )?;
+            }
+        }
+
+        //
+        // Разделитель и строка ввода. Если ввод не помещается, показываем его часть вокруг курсора.
+        //
+
+        let input_width = (width as usize).saturating_sub(3).max(1);
+        let offset = self.cursor.saturating_sub(input_width);
+        let visible: String = self
+            .input
+            .iter()
+            .skip(offset)
+            .take(input_width)
+            .map(|c| if *c == '\n' { '↵' } else { *c })
+            .collect();
+
+        queue!(
+            out,
+            MoveTo(0, height.saturating_sub(2)),
+            SetForegroundColor(Color::DarkGrey),
+            Print("─".repeat(width as usize)),
+            ResetColor,
+            MoveTo(0, height.saturating_sub(1)),
+            Print("> "),
+            Print(visible),
+            MoveTo((self.cursor - offset + 2) as u16, height.saturating_sub(1))
+        )?;
+
+        out.flush()?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+//
+// Сообщение общего чата, полученное от сервера.
+//
+
+struct Post<'a> {
+    id: u64,
+    time: &'a str,
+    parent: Option<u64>,
+    from: &'a str,
+    text: &'a str,
+}
+
+//
+// Что клиент помнит о сообщении общего чата, показанном в окне сообщений.
+//
+
+struct Shown {
+    index: usize,
+    time: String,
+    parent: Option<u64>,
+    from: String,
+    text: String,
+    edited: bool,
+    reactions: BTreeMap<String, usize>,
+}
+
+//
+// Разбирает сообщение общего чата "#<id> <время> <имя>: <текст>" или ответ "#<id> <время> ^<parent> <имя>: <текст>".
+//
+
+fn parse_posted(line: &str) -> Option<Post<'_>> {
+    let (id, rest) = line.strip_prefix('#')?.split_once(' ')?;
+    let (time, rest) = rest.split_once(' ')?;
+    let (parent, rest) = match rest.strip_prefix('^') {
+        Some(rest) => {
+            let (parent, rest) = rest.split_once(' ')?;
+            (Some(parent.parse().ok()?), rest)
+        }
+        None => (None, rest),
+    };
+    let (from, text) = rest.split_once(": ")?;
+
+    if from.contains(' ') {
+        return None;
+    }
+
+    Some(Post {
+        id: id.parse().ok()?,
+        time,
+        parent,
+        from,
+        text,
+    })
+}
+
+fn nick_color(name: &str) -> Color {
+    let hash = name
+        .bytes()
+        .fold(0usize, |hash, byte| hash.wrapping_mul(31) + byte as usize);
+    NICK_COLORS[hash % NICK_COLORS.len()]
+}
+
+fn print_span(out: &mut impl Write, span: &Span) -> io::Result<()> {
+    if span.bold {
+        queue!(out, SetAttribute(Attribute::Bold))?;
+    }
+    if let Some(color) = span.color {
+        queue!(out, SetForegroundColor(color))?;
+    }
+    queue!(
+        out,
+        Print(&span.text),
+        ResetColor,
+        SetAttribute(Attribute::Reset)
+    )
+}
+
+//
+// Разбивает раскрашенную строку на экранные строки шириной не больше width символов.
+//
+
+fn wrap(line: &[Span], width: usize) -> Vec<Vec<Span>> {
+    let width = width.max(1);
+    let mut rows = vec![Vec::new()];
+    let mut row_width = 0;
+
+    for span in line {
+        //
+        // Строки многострочного сообщения начинаются с новой экранной строки.
+        //
+
+        for (i, part) in span.text.split('\n').enumerate() {
+            if i > 0 {
+                rows.push(Vec::new());
+                row_width = 0;
+            }
+
+            let mut chars = part.chars().peekable();
+
+            while chars.peek().is_some() {
+                if row_width == width {
+                    rows.push(Vec::new());
+                    row_width = 0;
+                }
+
+                let text: String = chars.by_ref().take(width - row_width).collect();
+                row_width += text.chars().count();
+                rows.last_mut().unwrap().push(Span {
+                    text,
+                    ..span.clone()
+                });
+            }
+        }
+    }
+
+    rows
+}
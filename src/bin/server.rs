@@ -0,0 +1,294 @@
+use simple_chat::audit::FileAuditLog;
+use simple_chat::bots::EchoBot;
+use simple_chat::bots::TimeBot;
+use simple_chat::commands::UserKind;
+use simple_chat::direct_messages::DirectMessageStorage;
+use simple_chat::direct_messages::FileDirectMessageStorage;
+use simple_chat::direct_messages::MemoryDirectMessageStorage;
+use simple_chat::history::FileHistoryStorage;
+use simple_chat::history::HistoryStorage;
+use simple_chat::history::MemoryHistoryStorage;
+use simple_chat::moderation::FileModerationStorage;
+use simple_chat::moderation::MemoryModerationStorage;
+use simple_chat::moderation::ModerationStorage;
+use simple_chat::server::ChatServer;
+use simple_chat::server::DEFAULT_ADDRESS;
+use simple_chat::storage::FileUserStorage;
+use simple_chat::storage::MemoryUserStorage;
+use simple_chat::storage::UserStorage;
+use simple_chat::webhooks::Webhook;
+use std::env;
+use std::process::ExitCode;
+use std::sync::Arc;
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+const USAGE: &str =
+    "usage: server [--address <host:port>] [--users <file>] [--moderation <file>] [--direct-messages <file>] [--history <file>] [--audit <file>] [--metrics <host:port>] [--websocket <host:port>] [--api <host:port>] [--api-token <user>:<token>]... [--webhook <url>[,room=<room>][,mention=<user>][,keyword=<word>][,presence|,presence_only]]... [--bot echo|time]... [--admin <uuid>] [--admin-token <token>]";
+
+fn main() -> ExitCode {
+    //
+    // Логи пишутся в stderr. Уровень задается переменной окружения RUST_LOG, по умолчанию - info.
+    //
+
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()))
+        .with_writer(std::io::stderr)
+        .init();
+
+    //
+    // Разбираем аргументы командной строки.
+    // По умолчанию слушаем порт 8889 на IP-адресе localhost (локальный адрес)
+    // и храним пользователей, баны, мьюты, историю и личную переписку только в памяти.
+    //
+
+    let mut address = DEFAULT_ADDRESS.to_string();
+    let mut users_path = None;
+    let mut moderation_path = None;
+    let mut direct_messages_path = None;
+    let mut history_path = None;
+    let mut audit_path = None;
+    let mut metrics_address = None;
+    let mut websocket_address = None;
+    let mut api_address = None;
+    let mut api_tokens = Vec::new();
+    let mut webhooks = Vec::new();
+    let mut bots = Vec::new();
+    let mut admin = None;
+    let mut admin_token = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args.next();
+        match (arg.as_str(), value) {
+            ("--address", Some(value)) => address = value,
+            ("--users", Some(value)) => users_path = Some(value),
+            ("--moderation", Some(value)) => moderation_path = Some(value),
+            ("--direct-messages", Some(value)) => direct_messages_path = Some(value),
+            ("--history", Some(value)) => history_path = Some(value),
+            ("--audit", Some(value)) => audit_path = Some(value),
+            ("--metrics", Some(value)) => metrics_address = Some(value),
+            ("--websocket", Some(value)) => websocket_address = Some(value),
+            ("--api", Some(value)) => api_address = Some(value),
+            ("--webhook", Some(value)) => match parse_webhook(&value) {
+                Some(webhook) => webhooks.push(webhook),
+                None => {
+                    eprintln!("invalid webhook: {value}");
+                    return ExitCode::from(2);
+                }
+            },
+            ("--bot", Some(value)) if value == "echo" || value == "time" => bots.push(value),
+            ("--api-token", Some(value)) => match value.split_once(':') {
+                Some((user, token)) if !user.is_empty() && !token.is_empty() => {
+                    api_tokens.push((user.to_string(), token.to_string()))
+                }
+                _ => {
+                    eprintln!("invalid api token, expected <user>:<token>");
+                    return ExitCode::from(2);
+                }
+            },
+            ("--admin-token", Some(value)) if !value.trim().is_empty() => admin_token = Some(value),
+            ("--admin", Some(value)) => match Uuid::parse_str(&value) {
+                Ok(id) => admin = Some(id),
+                Err(_) => {
+                    eprintln!("invalid admin uuid: {value}");
+                    return ExitCode::from(2);
+                }
+            },
+            _ => {
+                eprintln!("{USAGE}");
+                return ExitCode::from(2);
+            }
+        }
+    }
+
+    //
+    // Без токена администратора войти администратором нельзя.
+    //
+
+    if admin.is_some() && admin_token.is_none() {
+        eprintln!("--admin requires --admin-token");
+        return ExitCode::from(2);
+    }
+
+    let users: Box<dyn UserStorage> = match users_path {
+        Some(path) => match FileUserStorage::open(&path) {
+            Ok(value) => Box::new(value),
+            Err(error) => {
+                eprintln!("cannot open {path}: {error}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => Box::new(MemoryUserStorage::new()),
+    };
+
+    let moderation: Box<dyn ModerationStorage> = match moderation_path {
+        Some(path) => match FileModerationStorage::open(&path) {
+            Ok(value) => Box::new(value),
+            Err(error) => {
+                eprintln!("cannot open {path}: {error}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => Box::new(MemoryModerationStorage::new()),
+    };
+
+    let direct_messages: Box<dyn DirectMessageStorage> = match direct_messages_path {
+        Some(path) => match FileDirectMessageStorage::open(&path) {
+            Ok(value) => Box::new(value),
+            Err(error) => {
+                eprintln!("cannot open {path}: {error}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => Box::new(MemoryDirectMessageStorage::new()),
+    };
+
+    let history: Box<dyn HistoryStorage> = match history_path {
+        Some(path) => match FileHistoryStorage::open(&path) {
+            Ok(value) => Box::new(value),
+            Err(error) => {
+                eprintln!("cannot open {path}: {error}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => Box::new(MemoryHistoryStorage::new()),
+    };
+
+    //
+    // Администратор, переданный в аргументах, может регистрировать остальных пользователей.
+    //
+
+    if let Some(id) = admin {
+        if let Err(error) = users.add_user(id, UserKind::Admin) {
+            eprintln!("cannot register admin: {error}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let mut builder = ChatServer::builder()
+        .address(address)
+        .user_storage(users)
+        .moderation_storage(moderation)
+        .direct_message_storage(direct_messages)
+        .history_storage(history);
+
+    if let Some(address) = metrics_address {
+        builder = builder.metrics_address(address);
+    }
+
+    if let Some(address) = websocket_address {
+        builder = builder.websocket_address(address);
+    }
+
+    //
+    // HTTP API без токенов бесполезно: все запросы к нему были бы отклонены.
+    //
+
+    if let Some(address) = api_address {
+        if api_tokens.is_empty() {
+            eprintln!("--api requires at least one --api-token");
+            return ExitCode::from(2);
+        }
+        builder = builder.api_address(address);
+    }
+    for (user, token) in api_tokens {
+        builder = builder.api_token(token, user);
+    }
+    for webhook in webhooks {
+        builder = builder.webhook(webhook);
+    }
+    if let Some(token) = admin_token {
+        builder = builder.admin_token(token);
+    }
+
+    //
+    // Встроенные боты: echo повторяет сообщения, в которых его упомянули, time отвечает на !time.
+    //
+
+    for bot in bots {
+        builder = match bot.as_str() {
+            "echo" => builder.bot(EchoBot::new("echo")),
+            _ => builder.bot(TimeBot),
+        };
+    }
+
+    //
+    // Журнал аудита ведется, только если для него указан файл.
+    //
+
+    if let Some(path) = audit_path {
+        match FileAuditLog::open(&path) {
+            Ok(audit) => builder = builder.audit_log(audit),
+            Err(error) => {
+                eprintln!("cannot open {path}: {error}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let server = match builder.build() {
+        Ok(value) => Arc::new(value),
+        Err(error) => {
+            eprintln!("cannot start server: {error}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    info!(address = %server.local_addr(), "listening");
+    if let Some(address) = server.metrics_addr() {
+        info!(%address, "serving metrics");
+    }
+    if let Some(address) = server.websocket_addr() {
+        info!(%address, "accepting websocket connections");
+    }
+    if let Some(address) = server.api_addr() {
+        info!(%address, "serving http api");
+    }
+
+    //
+    // По SIGINT/SIGTERM просим сервер остановиться. Сама остановка
+    // (оповещение клиентов и сохранение состояния) выполнится в run.
+    //
+
+    {
+        let server = server.clone();
+        ctrlc::set_handler(move || server.shutdown()).unwrap();
+    }
+
+    //
+    // Принимаем новые соединения от клиентов до остановки сервера.
+    // Вся логика обработки соединений находится в библиотеке (simple_chat::server).
+    //
+
+    match server.run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("server stopped with error: {error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+//
+// Вебхук из аргумента --webhook: адрес и через запятую фильтры сообщений и события входа и выхода.
+//
+
+fn parse_webhook(spec: &str) -> Option<Webhook> {
+    let mut parts = spec.split(',');
+    let mut webhook = Webhook::new(parts.next()?).ok()?;
+
+    for part in parts {
+        webhook = match part.split_once('=') {
+            Some(("room", room)) => webhook.room(room),
+            Some(("mention", user)) => webhook.mention(user),
+            Some(("keyword", keyword)) => webhook.keyword(keyword),
+            None if part == "presence" => webhook.presence(true),
+            None if part == "presence_only" => webhook.presence(true).messages(false),
+            _ => return None,
+        };
+    }
+
+    Some(webhook)
+}
@@ -0,0 +1,76 @@
+use crate::error::Error;
+
+/// Строка, с которой начинается многострочный блок.
+pub const BEGIN: &str = "%begin";
+/// Строка, которой заканчивается многострочный блок.
+pub const END: &str = "%end";
+
+/// Многострочный текст в виде блока: `%begin`, строки текста, `%end` (без завершающего перевода строки).
+/// Строки текста, начинающиеся с `%`, получают еще один `%` в начале, чтобы строку `%end`
+/// внутри текста нельзя было принять за конец блока.
+pub fn encode(text: &str) -> String {
+    let mut block = String::from(BEGIN);
+
+    for line in text.split('\n') {
+        block.push('\n');
+        if line.starts_with('%') {
+            block.push('%');
+        }
+        block.push_str(line);
+    }
+
+    block.push('\n');
+    block.push_str(END);
+    block
+}
+
+/// Текст блока, который целиком лежит в `input`. Возвращает `None`, если `input` - не блок.
+pub fn decode(input: &str) -> Option<Result<String, Error>> {
+    let mut lines = input
+        .split('\n')
+        .map(|line| line.strip_suffix('\r').unwrap_or(line));
+    if lines.next()? != BEGIN {
+        return None;
+    }
+
+    let mut assembler = Assembler {
+        block: Some(Vec::new()),
+    };
+    let text = lines.find_map(|line| assembler.push(line));
+
+    Some(match (text, lines.next()) {
+        (Some(text), None) if !text.is_empty() => Ok(text),
+        (Some(_), None) => Err(Error::MissingArgument),
+        _ => Err(Error::InvalidInput),
+    })
+}
+
+/// Собирает блоки из строк, которые читаются из соединения по одной. Обычные строки
+/// возвращает сразу, а строки блока - одной строкой с переводами строк, когда дочитан `%end`.
+#[derive(Default)]
+pub struct Assembler {
+    block: Option<Vec<String>>,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, line: &str) -> Option<String> {
+        let Some(block) = self.block.as_mut() else {
+            if line == BEGIN {
+                self.block = Some(Vec::new());
+                return None;
+            }
+            return Some(line.to_string());
+        };
+
+        if line == END {
+            return self.block.take().map(|lines| lines.join("\n"));
+        }
+
+        block.push(line.strip_prefix('%').unwrap_or(line).to_string());
+        None
+    }
+}
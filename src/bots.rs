@@ -0,0 +1,141 @@
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
+use crate::commands::format_time;
+use crate::commands::mentions;
+use crate::commands::Command;
+
+/// Бот, который работает внутри сервера. Сервер передает ему каждую успешно выполненную команду
+/// вместе с тем, кто ее прислал, кроме личных сообщений и передач файлов, и выполняет ответы бота
+/// от его имени. Бот виден в `%show_users`
+/// как пользователь, но соединения у него нет, и залогиниться под его именем нельзя.
+pub trait Bot: Send + Sync {
+    /// Имя, под которым бот пишет в чат.
+    fn name(&self) -> &str;
+
+    /// Ответы на команду. Сообщения ботов, в том числе этого, ботам не передаются.
+    fn on_command(&self, sender: &Sender, command: &Command) -> Vec<BotReply>;
+}
+
+/// Кто прислал команду.
+#[derive(Debug, Clone, Copy)]
+pub struct Sender<'a> {
+    /// Пользователь, если он уже залогинился.
+    pub user: Option<&'a str>,
+    pub address: SocketAddr,
+}
+
+/// Ответ бота.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BotReply {
+    /// Сообщение в общий чат.
+    Message(String),
+    /// Сообщение в общий чат с упоминанием того, кто прислал команду: "@<user> <text>".
+    Mention(String),
+    /// Уведомление "%notice <text>" во все сеансы того, кто прислал команду.
+    Notice(String),
+}
+
+/// Повторяет сообщения, в которых его упомянули, упоминая в ответ их автора.
+pub struct EchoBot {
+    name: String,
+}
+
+impl EchoBot {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self { name: name.into() }
+    }
+}
+
+impl Bot for EchoBot {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn on_command(&self, _sender: &Sender, command: &Command) -> Vec<BotReply> {
+        let Command::MessageWithMentions(cmd) = command else {
+            return Vec::new();
+        };
+        if !cmd.user_names.contains(&self.name) {
+            return Vec::new();
+        }
+
+        //
+        // Свои упоминания из текста убираем целыми словами: в ответе должен быть упомянут только
+        // автор сообщения, а упоминания пользователей, чьи имена начинаются с имени бота, остаются.
+        //
+
+        let text = cmd
+            .message
+            .split_whitespace()
+            .filter(|word| mentions(word) != [self.name.as_str()])
+            .collect::<Vec<_>>()
+            .join(" ");
+        match text.as_str() {
+            "" => Vec::new(),
+            text => vec![BotReply::Mention(text.to_string())],
+        }
+    }
+}
+
+/// Отвечает на сообщение `!time` текущим временем сервера (UTC, RFC 3339).
+pub struct TimeBot;
+
+impl Bot for TimeBot {
+    fn name(&self) -> &str {
+        "time"
+    }
+
+    fn on_command(&self, _sender: &Sender, command: &Command) -> Vec<BotReply> {
+        match command {
+            Command::Message(cmd) if cmd.message.trim() == "!time" => {
+                vec![BotReply::Mention(format_time(SystemTime::now()))]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn example_bots() {
+        let sender = Sender {
+            user: Some("alex"),
+            address: SocketAddr::from((Ipv4Addr::LOCALHOST, 8889)),
+        };
+        let echo = EchoBot::new("echo");
+
+        assert_eq!(
+            echo.on_command(&sender, &Command::text("@echo привет".to_string())),
+            [BotReply::Mention("привет".to_string())]
+        );
+        assert!(echo
+            .on_command(&sender, &Command::text("@roma привет".to_string()))
+            .is_empty());
+        assert!(echo
+            .on_command(&sender, &Command::text("@echo".to_string()))
+            .is_empty());
+        assert_eq!(
+            echo.on_command(
+                &sender,
+                &Command::text("@echo, передай @echoes привет".to_string())
+            ),
+            [BotReply::Mention("передай @echoes привет".to_string())]
+        );
+
+        assert_eq!(
+            TimeBot
+                .on_command(&sender, &Command::text(" !time ".to_string()))
+                .len(),
+            1
+        );
+        assert!(TimeBot
+            .on_command(&sender, &Command::text("!time please".to_string()))
+            .is_empty());
+    }
+}
@@ -0,0 +1,407 @@
+use std::fmt::Display;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+
+use crate::block;
+use crate::block::Assembler;
+use crate::commands::Command;
+use crate::commands::FileChunk;
+use crate::commands::MAX_FILE_CHUNK_SIZE;
+use crate::error::Error;
+use crate::websocket;
+use crate::websocket::Message;
+
+/// Первая строка, которой клиент просит перейти на кадры. Сервер отвечает такой же строкой,
+/// еще построчно, и после этого обе стороны обмениваются только кадрами.
+pub const FRAMED: &str = "%framed";
+
+/// Возможность в `%hello`: если ее называют и клиент, и сервер, сразу после приветствия
+/// обе стороны переходят на кадры.
+pub const FRAMED_CAPABILITY: &str = "framed";
+
+const LINE: u8 = 1;
+const TEXT: u8 = 2;
+const FILE_CHUNK: u8 = 3;
+
+/// Единица обмена между клиентом и сервером, независимо от того, как она передается.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// Одна строка без перевода строки: команда, сообщение или событие сервера.
+    Line(String),
+    /// Текст с переводами строк. От клиента - всегда сообщение в общий чат, даже если текст
+    /// начинается с `%`; от сервера - событие, в тексте которого есть переводы строк.
+    Text(String),
+    /// Кусок файла. Кадром передается как есть, без base64.
+    FileChunk(FileChunk),
+}
+
+impl Frame {
+    /// Строка от сервера: с переводами строк - `Text`, иначе `Line`.
+    pub fn text(line: impl Into<String>) -> Self {
+        let line = line.into();
+        if line.contains('\n') {
+            Self::Text(line)
+        } else {
+            Self::Line(line)
+        }
+    }
+
+    /// Команда клиента в виде кадра.
+    pub fn command(cmd: &Command) -> Self {
+        let message = match cmd {
+            Command::FileChunk(chunk) => return Self::FileChunk(chunk.clone()),
+            Command::Message(cmd) => &cmd.message,
+            Command::MessageWithMentions(cmd) => &cmd.message,
+            _ => return Self::Line(cmd.to_string()),
+        };
+
+        if message.contains('\n') || message.starts_with('%') {
+            Self::Text(message.clone())
+        } else {
+            Self::Line(message.clone())
+        }
+    }
+
+    /// Команда, которую прислал клиент.
+    pub fn to_command(&self) -> Result<Command, Error> {
+        match self {
+            Self::Line(line) => Command::new(line),
+            Self::Text(text) => Ok(Command::text(text.clone())),
+            Self::FileChunk(chunk) => Ok(Command::FileChunk(chunk.clone())),
+        }
+    }
+}
+
+impl Display for Frame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Line(text) | Self::Text(text) => write!(f, "{text}"),
+            Self::FileChunk(chunk) => Display::fmt(chunk, f),
+        }
+    }
+}
+
+/// Как кадры передаются по соединению.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Строками, которые заканчиваются переводом строки. `Text` передается блоком `%begin`/`%end`,
+    /// `FileChunk` - командой `%file_chunk` с данными в base64.
+    #[default]
+    Lines,
+    /// Кадрами: длина (u32, big-endian) байта типа и содержимого, байт типа, содержимое.
+    /// Содержимое `Line` и `Text` - текст в UTF-8, `FileChunk` - идентификатор передачи
+    /// (u64, big-endian) и данные.
+    Frames,
+    /// Сообщениями WebSocket: `Line` и `Text` - текстовыми, `FileChunk` - двоичным с тем же
+    /// содержимым, что и в кадре. Текстовое сообщение от клиента с переводами строк - `Text`.
+    WebSocket,
+}
+
+impl Framing {
+    pub fn encode(self, frame: &Frame) -> Vec<u8> {
+        match self {
+            Self::Lines => match frame {
+                Frame::Line(line) => format!("{line}\n").into_bytes(),
+                Frame::Text(text) => format!("{}\n", block::encode(text)).into_bytes(),
+                Frame::FileChunk(chunk) => format!("{chunk}\n").into_bytes(),
+            },
+            Self::Frames => {
+                let (kind, payload) = match frame {
+                    Frame::Line(line) => (LINE, line.as_bytes().to_vec()),
+                    Frame::Text(text) => (TEXT, text.as_bytes().to_vec()),
+                    Frame::FileChunk(chunk) => {
+                        let mut payload = chunk.id.to_be_bytes().to_vec();
+                        payload.extend_from_slice(&chunk.data);
+                        (FILE_CHUNK, payload)
+                    }
+                };
+
+                let mut bytes = Vec::with_capacity(payload.len() + 5);
+                bytes.extend_from_slice(&(payload.len() as u32 + 1).to_be_bytes());
+                bytes.push(kind);
+                bytes.extend_from_slice(&payload);
+                bytes
+            }
+            Self::WebSocket => websocket::encode(&message(frame), None),
+        }
+    }
+
+    /// Чем закончить соединение после ошибки чтения: для WebSocket - кадром close с кодом ошибки,
+    /// в остальных случаях ничем.
+    pub fn close(self, error: &Error) -> Vec<u8> {
+        match self {
+            Self::Lines | Self::Frames => Vec::new(),
+            Self::WebSocket => websocket::close(websocket::close_code(error)),
+        }
+    }
+
+    /// Читает следующий кадр. Возвращает `None`, если соединение закрылось.
+    /// Строка длиннее `max_line_length` байт - ошибка `LineTooLong`, блок `%begin`/`%end` или кадр
+    /// длиннее `max_length` байт - `MessageTooLong`. Ограничение на строку действует и внутри кадров.
+    /// После любой ошибки читать соединение дальше нельзя.
+    pub fn read<S: Read + Write>(
+        self,
+        reader: &mut BufReader<S>,
+        max_line_length: usize,
+        max_length: usize,
+    ) -> Result<Option<Frame>, Error> {
+        let frame = match self {
+            Self::Lines => return read_lines(reader, max_line_length, max_length),
+            Self::Frames => read_frame(reader, max_length)?,
+            Self::WebSocket => match websocket::read(reader, max_length)? {
+                Some(Message::Text(text)) => Some(Frame::text(text)),
+                Some(Message::Binary(payload)) => Some(file_chunk(payload)?),
+                None => None,
+            },
+        };
+
+        //
+        // Построчно строка вместе с переводом строки должна уместиться в max_line_length.
+        //
+
+        match &frame {
+            Some(Frame::Line(text) | Frame::Text(text))
+                if text.split('\n').any(|line| line.len() >= max_line_length) =>
+            {
+                Err(Error::LineTooLong)
+            }
+            _ => Ok(frame),
+        }
+    }
+}
+
+fn message(frame: &Frame) -> Message {
+    match frame {
+        Frame::Line(text) | Frame::Text(text) => Message::Text(text.clone()),
+        Frame::FileChunk(chunk) => {
+            let mut payload = chunk.id.to_be_bytes().to_vec();
+            payload.extend_from_slice(&chunk.data);
+            Message::Binary(payload)
+        }
+    }
+}
+
+fn read_lines(
+    reader: &mut impl BufRead,
+    max_line_length: usize,
+    max_length: usize,
+) -> Result<Option<Frame>, Error> {
+    let Some(line) = read_line(reader, max_line_length)? else {
+        return Ok(None);
+    };
+    if line != block::BEGIN {
+        return Ok(Some(Frame::Line(line)));
+    }
+
+    //
+    // Многострочное сообщение: дочитываем строки до %end, следя за длиной всего блока.
+    //
+
+    let mut assembler = Assembler::new();
+    assembler.push(&line);
+    let mut length = line.len() + 1;
+
+    loop {
+        let remaining = max_length.saturating_sub(length);
+        if remaining == 0 {
+            return Err(Error::MessageTooLong);
+        }
+
+        let Some(line) =
+            read_line(reader, remaining.min(max_line_length)).map_err(|error| match error {
+                Error::LineTooLong if remaining < max_line_length => Error::MessageTooLong,
+                error => error,
+            })?
+        else {
+            return Ok(None);
+        };
+
+        length += line.len() + 1;
+        if let Some(text) = assembler.push(&line) {
+            return Ok(Some(Frame::Text(text)));
+        }
+    }
+}
+
+//
+// Строка без перевода строки в конце. Соединение, закрытое посреди строки, - то же, что закрытое между строками.
+//
+
+fn read_line(reader: &mut impl BufRead, max_length: usize) -> Result<Option<String>, Error> {
+    let mut line = String::new();
+    let read = reader
+        .take(max_length as u64)
+        .read_line(&mut line)
+        .map_err(Error::IO)?;
+
+    if !line.ends_with('\n') {
+        return match read {
+            _ if read == max_length => Err(Error::LineTooLong),
+            _ => Ok(None),
+        };
+    }
+
+    line.pop();
+    if line.ends_with('\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn read_frame(reader: &mut impl BufRead, max_length: usize) -> Result<Option<Frame>, Error> {
+    let mut length = [0; 4];
+    match reader.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(Error::IO(e)),
+    }
+
+    let length = u32::from_be_bytes(length) as usize;
+    if length == 0 {
+        return Err(Error::InvalidInput);
+    }
+    if length - 1 > max_length {
+        return Err(Error::MessageTooLong);
+    }
+
+    let mut bytes = vec![0; length];
+    match reader.read_exact(&mut bytes) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(Error::IO(e)),
+    }
+
+    let payload = bytes.split_off(1);
+    let text = |payload: Vec<u8>| String::from_utf8(payload).map_err(|_| Error::InvalidInput);
+
+    match bytes[0] {
+        LINE => {
+            let line = text(payload)?;
+            if line.contains('\n') {
+                return Err(Error::InvalidInput);
+            }
+            Ok(Some(Frame::Line(line)))
+        }
+        TEXT => Ok(Some(Frame::Text(text(payload)?))),
+        FILE_CHUNK => file_chunk(payload).map(Some),
+        _ => Err(Error::InvalidInput),
+    }
+}
+
+//
+// Кусок файла: идентификатор передачи (u64, big-endian) и непустые данные.
+//
+
+fn file_chunk(payload: Vec<u8>) -> Result<Frame, Error> {
+    if payload.len() <= 8 || payload.len() - 8 > MAX_FILE_CHUNK_SIZE {
+        return Err(Error::InvalidInput);
+    }
+
+    let (id, data) = payload.split_at(8);
+    Ok(Frame::FileChunk(FileChunk {
+        id: u64::from_be_bytes(id.try_into().unwrap()),
+        data: data.to_vec(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::websocket::tests::Duplex;
+
+    fn frame() -> impl Strategy<Value = Frame> {
+        prop_oneof![
+            "[^\r\n]{0,64}"
+                .prop_filter("block start", |line| line != block::BEGIN)
+                .prop_map(Frame::Line),
+            prop::collection::vec("[^\r\n]{0,16}", 2..5)
+                .prop_map(|lines| Frame::Text(lines.join("\n"))),
+            (
+                any::<u64>(),
+                prop::collection::vec(any::<u8>(), 1..=MAX_FILE_CHUNK_SIZE)
+            )
+                .prop_map(|(id, data)| Frame::FileChunk(FileChunk { id, data })),
+        ]
+    }
+
+    fn framing() -> impl Strategy<Value = Framing> {
+        prop_oneof![
+            Just(Framing::Lines),
+            Just(Framing::Frames),
+            Just(Framing::WebSocket)
+        ]
+    }
+
+    /// Кадр так, как его отправляет клиент: по WebSocket клиент маскирует кадры.
+    fn client_encode(framing: Framing, frame: &Frame) -> Vec<u8> {
+        match framing {
+            Framing::WebSocket => websocket::encode(&message(frame), Some([1, 2, 3, 4])),
+            _ => framing.encode(frame),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(frames in prop::collection::vec(frame(), 1..4), framing in framing()) {
+            let bytes: Vec<u8> = frames.iter().flat_map(|frame| client_encode(framing, frame)).collect();
+            let mut reader = Duplex::reader(bytes);
+
+            //
+            // Построчно кусок файла передается командой %file_chunk и читается как обычная строка.
+            //
+
+            for frame in frames {
+                let expected = match (framing, frame) {
+                    (Framing::Lines, Frame::FileChunk(chunk)) => Frame::Line(chunk.to_string()),
+                    (_, frame) => frame,
+                };
+                prop_assert_eq!(framing.read(&mut reader, 4096, 16384).unwrap(), Some(expected));
+            }
+            prop_assert_eq!(framing.read(&mut reader, 4096, 16384).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn limits() {
+        let read = |framing: Framing, frame: &Frame| {
+            let bytes = client_encode(framing, frame);
+            framing.read(&mut Duplex::reader(bytes), 16, 32)
+        };
+
+        let long_line = Frame::Line("x".repeat(16));
+        let long_text = Frame::Text(["x".repeat(10), "y".repeat(10), "z".repeat(10)].join("\n"));
+
+        assert!(matches!(
+            read(Framing::Lines, &long_line),
+            Err(Error::LineTooLong)
+        ));
+        assert!(matches!(
+            read(Framing::Lines, &long_text),
+            Err(Error::MessageTooLong)
+        ));
+        assert!(matches!(
+            read(Framing::Frames, &Frame::Line("x".repeat(15))),
+            Ok(Some(_))
+        ));
+        for framing in [Framing::Frames, Framing::WebSocket] {
+            assert!(matches!(read(framing, &long_line), Err(Error::LineTooLong)));
+            assert!(matches!(
+                read(framing, &Frame::Text(format!("{}\ny", "x".repeat(16)))),
+                Err(Error::LineTooLong)
+            ));
+        }
+        assert!(matches!(
+            read(Framing::Frames, &Frame::Line("x".repeat(33))),
+            Err(Error::MessageTooLong)
+        ));
+        assert!(matches!(
+            Framing::Frames.read(&mut Duplex::reader(vec![0, 0, 0, 1, 9]), 16, 32),
+            Err(Error::InvalidInput)
+        ));
+    }
+}
@@ -1,14 +1,33 @@
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::time::Duration;
+use std::time::SystemTime;
 use std::{char, str};
 
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use chrono::DateTime;
+use chrono::SecondsFormat;
+use chrono::Utc;
+
 // подключена внешняя библиотека https://crates.io/crates/uuid для генерации уникального id
 
 use uuid::Uuid;
 
+use crate::block;
 use crate::error::Error;
 
 pub const CMD_WHOAMI: &str = "whoami";
 pub const CMD_BYE: &str = "bye";
 
+/// Максимальная длина реакции в символах.
+pub const MAX_REACTION_LENGTH: usize = 16;
+
+/// Максимальный размер одного куска файла в байтах. В base64 вместе с командой
+/// он укладывается в ограничение длины строки, которое сервер ставит по умолчанию.
+pub const MAX_FILE_CHUNK_SIZE: usize = 2048;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Command {
     Login(Login),
     Message(Message),
@@ -16,10 +35,80 @@ pub enum Command {
     AddUser(AddUser),
     RemoveUser(RemoveUser),
     ShowUsers(ShowUsers),
+    Bye(Bye),
+    Kick(Kick),
+    Ban(Ban),
+    Unban(Unban),
+    Mute(Mute),
+    Unmute(Unmute),
+    Stats(Stats),
+    DirectMessage(DirectMessage),
+    ShowDirectMessages(ShowDirectMessages),
+    ShowMentions(ShowMentions),
+    Edit(Edit),
+    Delete(Delete),
+    Reply(Reply),
+    Thread(Thread),
+    React(React),
+    Unreact(Unreact),
+    History(History),
+    FileOffer(FileOffer),
+    FileAccept(FileAccept),
+    FileDecline(FileDecline),
+    FileCancel(FileCancel),
+    FileChunk(FileChunk),
+    FileEnd(FileEnd),
+    Hello(Hello),
 }
 
 impl Command {
+    /// Имена всех специальных команд (тех, что начинаются с `%`).
+    pub const NAMES: &'static [&'static str] = &[
+        Login::COMMAND_NAME,
+        AddUser::COMMAND_NAME,
+        RemoveUser::COMMAND_NAME,
+        ShowUsers::COMMAND_NAME,
+        Bye::COMMAND_NAME,
+        Kick::COMMAND_NAME,
+        Ban::COMMAND_NAME,
+        Unban::COMMAND_NAME,
+        Mute::COMMAND_NAME,
+        Unmute::COMMAND_NAME,
+        Stats::COMMAND_NAME,
+        DirectMessage::COMMAND_NAME,
+        ShowDirectMessages::COMMAND_NAME,
+        ShowMentions::COMMAND_NAME,
+        Edit::COMMAND_NAME,
+        Delete::COMMAND_NAME,
+        Reply::COMMAND_NAME,
+        Thread::COMMAND_NAME,
+        React::COMMAND_NAME,
+        Unreact::COMMAND_NAME,
+        History::COMMAND_NAME,
+        FileOffer::COMMAND_NAME,
+        FileAccept::COMMAND_NAME,
+        FileDecline::COMMAND_NAME,
+        FileCancel::COMMAND_NAME,
+        FileChunk::COMMAND_NAME,
+        FileEnd::COMMAND_NAME,
+        Hello::COMMAND_NAME,
+    ];
+
     pub fn new(input: &str) -> Result<Self, Error> {
+        // каждая команда заканчивается переводом строки, в саму команду он не входит
+        let input = input
+            .strip_suffix('\n')
+            .map(|line| line.strip_suffix('\r').unwrap_or(line))
+            .unwrap_or(input);
+
+        //
+        // Многострочное сообщение приходит блоком %begin ... %end и целиком становится одним сообщением.
+        //
+
+        if let Some(text) = block::decode(input) {
+            return Ok(Self::text(text?));
+        }
+
         let mut chars = input.chars().peekable();
 
         let command = match chars.peek().ok_or(Error::MissingCommandName)? {
@@ -39,69 +128,171 @@ impl Command {
                     AddUser::COMMAND_NAME => Self::AddUser(AddUser::new(chars)?),
                     RemoveUser::COMMAND_NAME => Self::RemoveUser(RemoveUser::new(chars)?),
                     ShowUsers::COMMAND_NAME => Self::ShowUsers(ShowUsers::new()),
+                    Bye::COMMAND_NAME => Self::Bye(Bye),
+                    Kick::COMMAND_NAME => Self::Kick(Kick::new(chars)?),
+                    Ban::COMMAND_NAME => Self::Ban(Ban::new(chars)?),
+                    Unban::COMMAND_NAME => Self::Unban(Unban::new(chars)?),
+                    Mute::COMMAND_NAME => Self::Mute(Mute::new(chars)?),
+                    Unmute::COMMAND_NAME => Self::Unmute(Unmute::new(chars)?),
+                    Stats::COMMAND_NAME => Self::Stats(Stats),
+                    DirectMessage::COMMAND_NAME => Self::DirectMessage(DirectMessage::new(chars)?),
+                    ShowDirectMessages::COMMAND_NAME => {
+                        Self::ShowDirectMessages(ShowDirectMessages::new(chars))
+                    }
+                    ShowMentions::COMMAND_NAME => Self::ShowMentions(ShowMentions),
+                    Edit::COMMAND_NAME => Self::Edit(Edit::new(chars)?),
+                    Delete::COMMAND_NAME => Self::Delete(Delete::new(chars)?),
+                    Reply::COMMAND_NAME => Self::Reply(Reply::new(chars)?),
+                    Thread::COMMAND_NAME => Self::Thread(Thread::new(chars)?),
+                    React::COMMAND_NAME => Self::React(React::new(chars)?),
+                    Unreact::COMMAND_NAME => Self::Unreact(Unreact::new(chars)?),
+                    History::COMMAND_NAME => Self::History(History::new(chars)?),
+                    FileOffer::COMMAND_NAME => Self::FileOffer(FileOffer::new(chars)?),
+                    FileAccept::COMMAND_NAME => Self::FileAccept(FileAccept::new(chars)?),
+                    FileDecline::COMMAND_NAME => Self::FileDecline(FileDecline::new(chars)?),
+                    FileCancel::COMMAND_NAME => Self::FileCancel(FileCancel::new(chars)?),
+                    FileChunk::COMMAND_NAME => Self::FileChunk(FileChunk::new(chars)?),
+                    FileEnd::COMMAND_NAME => Self::FileEnd(FileEnd::new(chars)?),
+                    Hello::COMMAND_NAME => Self::Hello(Hello::new(chars)?),
                     _ => return Err(Error::UnknownCommand),
                 }
             }
-            '@' => Self::MessageWithMentions(MessageWithMentions::new(chars)?),
-            _ => Self::Message(Message::new(chars.collect())),
+            //
+            // Все, что не команда, - сообщение в общий чат.
+            //
+            _ => Self::text(chars.collect()),
         };
 
         Ok(command)
     }
+
+    /// Сообщение в общий чат: обычное или с упоминаниями, смотря что в тексте.
+    pub fn text(message: String) -> Self {
+        let cmd = MessageWithMentions::new(message);
+
+        if cmd.user_names.is_empty() {
+            Self::Message(Message::new(cmd.message))
+        } else {
+            Self::MessageWithMentions(cmd)
+        }
+    }
+
+    /// Команды, которые могут выполнять только администраторы.
+    pub fn is_admin_only(&self) -> bool {
+        matches!(
+            self,
+            Self::AddUser(_)
+                | Self::RemoveUser(_)
+                | Self::Kick(_)
+                | Self::Ban(_)
+                | Self::Unban(_)
+                | Self::Mute(_)
+                | Self::Unmute(_)
+                | Self::Stats(_)
+        )
+    }
+
+    /// Текст команды в том виде, в котором она передается по сети (вместе с переводом строки).
+    pub fn to_wire(&self) -> String {
+        format!("{self}\n")
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Login(cmd) => Display::fmt(cmd, f),
+            Self::Message(cmd) => Display::fmt(cmd, f),
+            Self::MessageWithMentions(cmd) => Display::fmt(cmd, f),
+            Self::AddUser(cmd) => Display::fmt(cmd, f),
+            Self::RemoveUser(cmd) => Display::fmt(cmd, f),
+            Self::ShowUsers(cmd) => Display::fmt(cmd, f),
+            Self::Bye(cmd) => Display::fmt(cmd, f),
+            Self::Kick(cmd) => Display::fmt(cmd, f),
+            Self::Ban(cmd) => Display::fmt(cmd, f),
+            Self::Unban(cmd) => Display::fmt(cmd, f),
+            Self::Mute(cmd) => Display::fmt(cmd, f),
+            Self::Unmute(cmd) => Display::fmt(cmd, f),
+            Self::Stats(cmd) => Display::fmt(cmd, f),
+            Self::DirectMessage(cmd) => Display::fmt(cmd, f),
+            Self::ShowDirectMessages(cmd) => Display::fmt(cmd, f),
+            Self::ShowMentions(cmd) => Display::fmt(cmd, f),
+            Self::Edit(cmd) => Display::fmt(cmd, f),
+            Self::Delete(cmd) => Display::fmt(cmd, f),
+            Self::Reply(cmd) => Display::fmt(cmd, f),
+            Self::Thread(cmd) => Display::fmt(cmd, f),
+            Self::React(cmd) => Display::fmt(cmd, f),
+            Self::Unreact(cmd) => Display::fmt(cmd, f),
+            Self::History(cmd) => Display::fmt(cmd, f),
+            Self::FileOffer(cmd) => Display::fmt(cmd, f),
+            Self::FileAccept(cmd) => Display::fmt(cmd, f),
+            Self::FileDecline(cmd) => Display::fmt(cmd, f),
+            Self::FileCancel(cmd) => Display::fmt(cmd, f),
+            Self::FileChunk(cmd) => Display::fmt(cmd, f),
+            Self::FileEnd(cmd) => Display::fmt(cmd, f),
+            Self::Hello(cmd) => Display::fmt(cmd, f),
+        }
+    }
 }
 
+/// Вход в чат: `%login <id> [token]`. Администратор подтверждает вход токеном администратора.
+/// Id - одно слово без пробелов: пробел отделяет от него токен.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Login {
     pub id: String,
+    pub token: Option<String>,
 }
 
 impl Login {
     pub const COMMAND_NAME: &'static str = "login";
 
     pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
-        Ok(Self {
-            id: input.collect::<String>().trim().to_string(),
-        })
+        let input: String = input.collect();
+        let mut parts = input.split_whitespace();
+        let id = parts.next().unwrap_or_default().to_string();
+        let token = parts.next().map(str::to_string);
+        if parts.next().is_some() {
+            return Err(Error::InvalidInput);
+        }
+
+        Ok(Self { id, token })
     }
 }
 
+impl Display for Login {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{} {}", Self::COMMAND_NAME, self.id)?;
+        if let Some(token) = &self.token {
+            write!(f, " {token}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Сообщение в общий чат, в котором упомянуты пользователи: `@имя` в любом месте текста.
+/// Текст хранится целиком, вместе с упоминаниями.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MessageWithMentions {
-    user_names: Vec<String>,
-    message: String,
+    pub user_names: Vec<String>,
+    pub message: String,
 }
 
 impl MessageWithMentions {
-    pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
-        let mut chars = input.peekable();
-        let mut user_names = Vec::new();
-
-        loop {
-            if *chars.peek().ok_or(Error::MissingUserName)? != '@' {
-                break;
-            }
-
-            chars.next().ok_or(Error::MissingUserName)?;
-            let mut name = String::new();
-
-            loop {
-                let current_char = chars.next().ok_or(Error::MissingUserName)?;
-
-                if current_char == ' ' {
-                    break;
-                }
-
-                name.push(current_char);
-            }
-
-            user_names.push(name);
+    pub fn new(message: String) -> Self {
+        Self {
+            user_names: mentions(&message),
+            message,
         }
+    }
+}
 
-        Ok(Self {
-            user_names,
-            message: chars.collect(), // собирает остаток строки
-        })
+impl Display for MessageWithMentions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write_text(f, &self.message)
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Message {
     pub message: String,
 }
@@ -112,9 +303,29 @@ impl Message {
     }
 }
 
+impl Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write_text(f, &self.message)
+    }
+}
+
+//
+// Текст с переводами строк, а также текст, начинающийся с '%' (иначе его примут за команду),
+// передается блоком %begin/%end.
+//
+
+fn write_text(f: &mut std::fmt::Formatter<'_>, text: &str) -> std::fmt::Result {
+    if text.contains('\n') || text.starts_with('%') {
+        write!(f, "{}", block::encode(text))
+    } else {
+        write!(f, "{text}")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct AddUser {
-    id: Uuid,
-    kind: UserKind,
+    pub id: Uuid,
+    pub kind: UserKind,
 }
 
 impl AddUser {
@@ -136,22 +347,22 @@ impl AddUser {
         let kind: String = input.collect();
         let user_id = Uuid::parse_str(&user_id).map_err(|_| Error::InvalidUuid)?;
 
-        Ok(match kind.as_str() {
-            UserKind::NORMAL_KIND => Self {
-                id: user_id,
-                kind: UserKind::Normal,
-            },
-            UserKind::ADMIN_KIND => Self {
-                id: user_id,
-                kind: UserKind::Admin,
-            },
-            _ => return Err(Error::InvalidUserKind),
+        Ok(Self {
+            id: user_id,
+            kind: kind.parse()?,
         })
     }
 }
 
+impl Display for AddUser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{} {} {}", Self::COMMAND_NAME, self.id, self.kind)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RemoveUser {
-    id: Uuid,
+    pub id: Uuid,
 }
 
 impl RemoveUser {
@@ -164,6 +375,13 @@ impl RemoveUser {
     }
 }
 
+impl Display for RemoveUser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{} {}", Self::COMMAND_NAME, self.id)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct ShowUsers;
 
 impl ShowUsers {
@@ -174,6 +392,819 @@ impl ShowUsers {
     }
 }
 
+impl Display for ShowUsers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{}", Self::COMMAND_NAME)
+    }
+}
+
+/// Клиент завершает сеанс, сервер закрывает соединение.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bye;
+
+impl Bye {
+    pub const COMMAND_NAME: &'static str = CMD_BYE;
+}
+
+impl Display for Bye {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{}", Self::COMMAND_NAME)
+    }
+}
+
+/// Администратор отключает пользователя от сервера: `%kick <user> [reason]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Kick {
+    pub user: String,
+    pub reason: Option<String>,
+}
+
+impl Kick {
+    pub const COMMAND_NAME: &'static str = "kick";
+
+    pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
+        let (user, reason) = split_argument(input)?;
+
+        Ok(Self {
+            user,
+            reason: Some(reason).filter(|reason| !reason.is_empty()),
+        })
+    }
+}
+
+impl Display for Kick {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{} {}", Self::COMMAND_NAME, self.user)?;
+        if let Some(reason) = &self.reason {
+            write!(f, " {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Запрет входа пользователю или подключений с IP-адреса: `%ban <user|ip> [duration]`.
+/// Без длительности бан бессрочный.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub target: BanTarget,
+    pub duration: Option<Duration>,
+}
+
+impl Ban {
+    pub const COMMAND_NAME: &'static str = "ban";
+
+    pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
+        let (target, duration) = split_argument(input)?;
+
+        Ok(Self {
+            target: target.parse()?,
+            duration: parse_optional_duration(&duration)?,
+        })
+    }
+}
+
+impl Display for Ban {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{} {}", Self::COMMAND_NAME, self.target)?;
+        if let Some(duration) = self.duration {
+            write!(f, " {}", format_duration(duration))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unban {
+    pub target: BanTarget,
+}
+
+impl Unban {
+    pub const COMMAND_NAME: &'static str = "unban";
+
+    pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
+        Ok(Self {
+            target: input.collect::<String>().trim().parse()?,
+        })
+    }
+}
+
+impl Display for Unban {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{} {}", Self::COMMAND_NAME, self.target)
+    }
+}
+
+/// Пользователь может читать чат, но его сообщения никому не рассылаются: `%mute <user> [duration]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mute {
+    pub user: String,
+    pub duration: Option<Duration>,
+}
+
+impl Mute {
+    pub const COMMAND_NAME: &'static str = "mute";
+
+    pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
+        let (user, duration) = split_argument(input)?;
+
+        Ok(Self {
+            user,
+            duration: parse_optional_duration(&duration)?,
+        })
+    }
+}
+
+impl Display for Mute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{} {}", Self::COMMAND_NAME, self.user)?;
+        if let Some(duration) = self.duration {
+            write!(f, " {}", format_duration(duration))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unmute {
+    pub user: String,
+}
+
+impl Unmute {
+    pub const COMMAND_NAME: &'static str = "unmute";
+
+    pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
+        let user = input.collect::<String>().trim().to_string();
+        if user.is_empty() {
+            return Err(Error::MissingArgument);
+        }
+
+        Ok(Self { user })
+    }
+}
+
+impl Display for Unmute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{} {}", Self::COMMAND_NAME, self.user)
+    }
+}
+
+/// Кого банят: пользователя по имени или IP-адрес.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BanTarget {
+    User(String),
+    Ip(IpAddr),
+}
+
+impl str::FromStr for BanTarget {
+    type Err = Error;
+
+    fn from_str(target: &str) -> Result<Self, Self::Err> {
+        if target.is_empty() {
+            return Err(Error::MissingArgument);
+        }
+
+        Ok(match target.parse() {
+            Ok(ip) => Self::Ip(ip),
+            Err(_) => Self::User(target.to_string()),
+        })
+    }
+}
+
+impl Display for BanTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::User(user) => write!(f, "{user}"),
+            Self::Ip(ip) => write!(f, "{ip}"),
+        }
+    }
+}
+
+/// Имена, упомянутые в тексте, в порядке появления и без повторов.
+/// Упоминание - слово, которое начинается с `@`; знаки препинания в конце слова в имя не входят,
+/// а `@` в середине слова (например, в адресе почты) упоминанием не считается.
+pub fn mentions(text: &str) -> Vec<String> {
+    let mut user_names: Vec<String> = Vec::new();
+
+    for word in text.split_whitespace() {
+        let Some(name) = word.strip_prefix('@') else {
+            continue;
+        };
+        let name = name.trim_end_matches([',', '.', '!', '?', ':', ';', ')']);
+
+        if !name.is_empty() && !user_names.iter().any(|known| known == name) {
+            user_names.push(name.to_string());
+        }
+    }
+
+    user_names
+}
+
+//
+// Делит аргументы команды на первое слово (обязательное) и остаток строки.
+//
+
+fn split_argument(input: impl Iterator<Item = char>) -> Result<(String, String), Error> {
+    let input: String = input.collect();
+    let (first, rest) = input.split_once(' ').unwrap_or((&input, ""));
+
+    if first.is_empty() {
+        return Err(Error::MissingArgument);
+    }
+
+    Ok((first.to_string(), rest.to_string()))
+}
+
+/// Разбирает длительность вида `30s`, `10m`, `2h` или `7d`.
+pub fn parse_duration(input: &str) -> Result<Duration, Error> {
+    let unit = input.chars().last().ok_or(Error::InvalidDuration)?;
+    let number: u64 = input[..input.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| Error::InvalidDuration)?;
+
+    let seconds = match unit {
+        's' => Some(number),
+        'm' => number.checked_mul(60),
+        'h' => number.checked_mul(60 * 60),
+        'd' => number.checked_mul(24 * 60 * 60),
+        _ => None,
+    };
+
+    match seconds {
+        Some(seconds) if seconds > 0 => Ok(Duration::from_secs(seconds)),
+        _ => Err(Error::InvalidDuration),
+    }
+}
+
+/// Записывает длительность в самых крупных единицах, в которых она выражается целым числом.
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+
+    match seconds {
+        _ if seconds.is_multiple_of(24 * 60 * 60) => format!("{}d", seconds / (24 * 60 * 60)),
+        _ if seconds.is_multiple_of(60 * 60) => format!("{}h", seconds / (60 * 60)),
+        _ if seconds.is_multiple_of(60) => format!("{}m", seconds / 60),
+        _ => format!("{seconds}s"),
+    }
+}
+
+/// Разбирает время в формате RFC 3339, например `2024-05-01T12:30:00Z` или `2024-05-01T15:30:00+03:00`.
+pub fn parse_time(input: &str) -> Result<SystemTime, Error> {
+    DateTime::parse_from_rfc3339(input)
+        .map(SystemTime::from)
+        .map_err(|_| Error::InvalidTime)
+}
+
+/// Записывает время в UTC в формате RFC 3339 с точностью до миллисекунд.
+pub fn format_time(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn parse_optional_duration(input: &str) -> Result<Option<Duration>, Error> {
+    match input.trim() {
+        "" => Ok(None),
+        duration => parse_duration(duration).map(Some),
+    }
+}
+
+/// Администратор запрашивает статистику сервера.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats;
+
+impl Stats {
+    pub const COMMAND_NAME: &'static str = "stats";
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{}", Self::COMMAND_NAME)
+    }
+}
+
+/// Личное сообщение: `%dm <user> <text>`. Доставляется только в сеансы получателя
+/// и хранится в отдельной переписке этой пары пользователей.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectMessage {
+    pub user: String,
+    pub message: String,
+}
+
+impl DirectMessage {
+    pub const COMMAND_NAME: &'static str = "dm";
+
+    pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
+        let (user, message) = split_argument(input)?;
+
+        if message.is_empty() {
+            return Err(Error::MissingArgument);
+        }
+
+        Ok(Self { user, message })
+    }
+}
+
+impl Display for DirectMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{} {} {}", Self::COMMAND_NAME, self.user, self.message)
+    }
+}
+
+/// Список собеседников (`%dms`) или переписка с одним из них (`%dms <user>`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShowDirectMessages {
+    pub user: Option<String>,
+}
+
+impl ShowDirectMessages {
+    pub const COMMAND_NAME: &'static str = "dms";
+
+    pub fn new(input: impl Iterator<Item = char>) -> Self {
+        let user = input.collect::<String>().trim().to_string();
+
+        Self {
+            user: Some(user).filter(|user| !user.is_empty()),
+        }
+    }
+}
+
+impl Display for ShowDirectMessages {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{}", Self::COMMAND_NAME)?;
+        if let Some(user) = &self.user {
+            write!(f, " {user}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Пользователь запрашивает непрочитанные упоминания.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShowMentions;
+
+impl ShowMentions {
+    pub const COMMAND_NAME: &'static str = "mentions";
+}
+
+impl Display for ShowMentions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{}", Self::COMMAND_NAME)
+    }
+}
+
+/// Автор исправляет текст своего сообщения: `%edit <id> <text>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    pub id: u64,
+    pub message: String,
+}
+
+impl Edit {
+    pub const COMMAND_NAME: &'static str = "edit";
+
+    pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
+        let (id, message) = split_argument(input)?;
+
+        if message.is_empty() {
+            return Err(Error::MissingArgument);
+        }
+
+        Ok(Self {
+            id: id.parse().map_err(|_| Error::InvalidMessageId)?,
+            message,
+        })
+    }
+}
+
+impl Display for Edit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{} {} {}", Self::COMMAND_NAME, self.id, self.message)
+    }
+}
+
+/// Удаление сообщения: `%delete <id>`. Автор может удалить свое сообщение, администратор - любое.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delete {
+    pub id: u64,
+}
+
+impl Delete {
+    pub const COMMAND_NAME: &'static str = "delete";
+
+    pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
+        let (id, _) = split_argument(input)?;
+
+        Ok(Self {
+            id: id.parse().map_err(|_| Error::InvalidMessageId)?,
+        })
+    }
+}
+
+impl Display for Delete {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{} {}", Self::COMMAND_NAME, self.id)
+    }
+}
+
+/// Ответ на сообщение общего чата: `%reply <id> <text>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub id: u64,
+    pub message: String,
+}
+
+impl Reply {
+    pub const COMMAND_NAME: &'static str = "reply";
+
+    pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
+        let (id, message) = split_argument(input)?;
+
+        if message.is_empty() {
+            return Err(Error::MissingArgument);
+        }
+
+        Ok(Self {
+            id: id.parse().map_err(|_| Error::InvalidMessageId)?,
+            message,
+        })
+    }
+}
+
+impl Display for Reply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{} {} {}", Self::COMMAND_NAME, self.id, self.message)
+    }
+}
+
+/// Вся ветка обсуждения, в которую входит сообщение: `%thread <id>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Thread {
+    pub id: u64,
+}
+
+impl Thread {
+    pub const COMMAND_NAME: &'static str = "thread";
+
+    pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
+        let (id, _) = split_argument(input)?;
+
+        Ok(Self {
+            id: id.parse().map_err(|_| Error::InvalidMessageId)?,
+        })
+    }
+}
+
+impl Display for Thread {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{} {}", Self::COMMAND_NAME, self.id)
+    }
+}
+
+/// Пользователь отмечает сообщение реакцией: `%react <id> <reaction>`, например `%react 42 👍`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct React {
+    pub id: u64,
+    pub reaction: String,
+}
+
+impl React {
+    pub const COMMAND_NAME: &'static str = "react";
+
+    pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
+        let (id, reaction) = split_reaction(input)?;
+        Ok(Self { id, reaction })
+    }
+}
+
+impl Display for React {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{} {} {}", Self::COMMAND_NAME, self.id, self.reaction)
+    }
+}
+
+/// Пользователь снимает свою реакцию: `%unreact <id> <reaction>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unreact {
+    pub id: u64,
+    pub reaction: String,
+}
+
+impl Unreact {
+    pub const COMMAND_NAME: &'static str = "unreact";
+
+    pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
+        let (id, reaction) = split_reaction(input)?;
+        Ok(Self { id, reaction })
+    }
+}
+
+impl Display for Unreact {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{} {} {}", Self::COMMAND_NAME, self.id, self.reaction)
+    }
+}
+
+//
+// Аргументы %react и %unreact: идентификатор сообщения и реакция - одно короткое слово, обычно эмодзи.
+//
+
+fn split_reaction(input: impl Iterator<Item = char>) -> Result<(u64, String), Error> {
+    let (id, reaction) = split_argument(input)?;
+
+    if reaction.is_empty() {
+        return Err(Error::MissingArgument);
+    }
+    if reaction.chars().any(char::is_whitespace) || reaction.chars().count() > MAX_REACTION_LENGTH {
+        return Err(Error::InvalidReaction);
+    }
+
+    Ok((id.parse().map_err(|_| Error::InvalidMessageId)?, reaction))
+}
+
+/// Сообщения общего чата за промежуток времени: `%history [since|-] [until]`.
+/// Время - в формате RFC 3339, `since` входит в промежуток, `until` - нет.
+/// Без аргументов - последние сообщения.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct History {
+    pub since: Option<SystemTime>,
+    pub until: Option<SystemTime>,
+}
+
+impl History {
+    pub const COMMAND_NAME: &'static str = "history";
+
+    pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
+        let input: String = input.collect();
+        let mut times = input.split_whitespace().map(|time| match time {
+            "-" => Ok(None),
+            time => parse_time(time).map(Some),
+        });
+
+        let since = times.next().transpose()?.flatten();
+        let until = times.next().transpose()?.flatten();
+        if times.next().is_some() {
+            return Err(Error::InvalidInput);
+        }
+
+        Ok(Self { since, until })
+    }
+}
+
+impl Display for History {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{}", Self::COMMAND_NAME)?;
+        match (self.since, self.until) {
+            (None, None) => Ok(()),
+            (Some(since), None) => write!(f, " {}", format_time(since)),
+            (since, Some(until)) => write!(
+                f,
+                " {} {}",
+                since.map_or("-".to_string(), format_time),
+                format_time(until)
+            ),
+        }
+    }
+}
+
+/// Предложение передать файл пользователю: `%file_offer <user> <size> <sha256> <name>`.
+/// Сам файл передается кусками (`%file_chunk`) только после того, как получатель согласится.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileOffer {
+    pub user: String,
+    pub size: u64,
+    /// SHA-256 содержимого файла в шестнадцатеричном виде.
+    pub checksum: String,
+    pub name: String,
+}
+
+impl FileOffer {
+    pub const COMMAND_NAME: &'static str = "file_offer";
+
+    pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
+        let (user, rest) = split_argument(input)?;
+        let (size, rest) = split_argument(rest.chars())?;
+        let (checksum, name) = split_argument(rest.chars())?;
+
+        if name.is_empty() {
+            return Err(Error::MissingArgument);
+        }
+        if checksum.len() != 64 || !checksum.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::InvalidInput);
+        }
+
+        //
+        // Имя файла без пути: получатель сохраняет файл в свой каталог и никуда больше.
+        //
+
+        if name.contains(['/', '\\']) || name == "." || name == ".." {
+            return Err(Error::InvalidInput);
+        }
+
+        Ok(Self {
+            user,
+            size: size.parse().map_err(|_| Error::InvalidInput)?,
+            checksum: checksum.to_ascii_lowercase(),
+            name,
+        })
+    }
+}
+
+impl Display for FileOffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "%{} {} {} {} {}",
+            Self::COMMAND_NAME,
+            self.user,
+            self.size,
+            self.checksum,
+            self.name
+        )
+    }
+}
+
+/// Получатель соглашается принять файл: `%file_accept <transfer>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileAccept {
+    pub id: u64,
+}
+
+impl FileAccept {
+    pub const COMMAND_NAME: &'static str = "file_accept";
+
+    pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
+        Ok(Self {
+            id: parse_transfer_id(input)?,
+        })
+    }
+}
+
+impl Display for FileAccept {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{} {}", Self::COMMAND_NAME, self.id)
+    }
+}
+
+/// Получатель отказывается от файла: `%file_decline <transfer>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileDecline {
+    pub id: u64,
+}
+
+impl FileDecline {
+    pub const COMMAND_NAME: &'static str = "file_decline";
+
+    pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
+        Ok(Self {
+            id: parse_transfer_id(input)?,
+        })
+    }
+}
+
+impl Display for FileDecline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{} {}", Self::COMMAND_NAME, self.id)
+    }
+}
+
+/// Отправитель или получатель прерывает передачу: `%file_cancel <transfer>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileCancel {
+    pub id: u64,
+}
+
+impl FileCancel {
+    pub const COMMAND_NAME: &'static str = "file_cancel";
+
+    pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
+        Ok(Self {
+            id: parse_transfer_id(input)?,
+        })
+    }
+}
+
+impl Display for FileCancel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{} {}", Self::COMMAND_NAME, self.id)
+    }
+}
+
+/// Очередной кусок файла: `%file_chunk <transfer> <data in base64>`.
+/// Кусок не больше `MAX_FILE_CHUNK_SIZE` байт, чтобы строка укладывалась в ограничение длины.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileChunk {
+    pub id: u64,
+    pub data: Vec<u8>,
+}
+
+impl FileChunk {
+    pub const COMMAND_NAME: &'static str = "file_chunk";
+
+    pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
+        let (id, data) = split_argument(input)?;
+        let data = BASE64_STANDARD
+            .decode(data.trim())
+            .map_err(|_| Error::InvalidInput)?;
+
+        if data.is_empty() {
+            return Err(Error::MissingArgument);
+        }
+        if data.len() > MAX_FILE_CHUNK_SIZE {
+            return Err(Error::InvalidInput);
+        }
+
+        Ok(Self {
+            id: id.parse().map_err(|_| Error::InvalidTransferId)?,
+            data,
+        })
+    }
+}
+
+impl Display for FileChunk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "%{} {} {}",
+            Self::COMMAND_NAME,
+            self.id,
+            BASE64_STANDARD.encode(&self.data)
+        )
+    }
+}
+
+/// Отправитель передал все куски файла: `%file_end <transfer>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEnd {
+    pub id: u64,
+}
+
+impl FileEnd {
+    pub const COMMAND_NAME: &'static str = "file_end";
+
+    pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
+        Ok(Self {
+            id: parse_transfer_id(input)?,
+        })
+    }
+}
+
+impl Display for FileEnd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{} {}", Self::COMMAND_NAME, self.id)
+    }
+}
+
+fn parse_transfer_id(input: impl Iterator<Item = char>) -> Result<u64, Error> {
+    let (id, _) = split_argument(input)?;
+    id.parse().map_err(|_| Error::InvalidTransferId)
+}
+
+/// Приветствие в начале соединения: `%hello <version> [capability ...]`. Клиент называет версию
+/// протокола и возможности, которые понимает. Сервер отвечает тем же: своей версией, своими
+/// возможностями и ограничениями в виде `<name>=<value>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u32,
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    pub const COMMAND_NAME: &'static str = "hello";
+
+    /// Версия протокола, которую понимают этот сервер и этот клиент.
+    pub const VERSION: u32 = 1;
+
+    pub fn new(input: impl Iterator<Item = char>) -> Result<Self, Error> {
+        let (version, rest) = split_argument(input)?;
+
+        Ok(Self {
+            version: version.parse().map_err(|_| Error::InvalidInput)?,
+            capabilities: rest.split_whitespace().map(str::to_string).collect(),
+        })
+    }
+
+    pub fn has(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// Значение ограничения `<name>=<value>` из ответа сервера.
+    pub fn limit(&self, name: &str) -> Option<u64> {
+        self.capabilities.iter().find_map(|c| {
+            let (key, value) = c.split_once('=')?;
+            (key == name).then(|| value.parse().ok()).flatten()
+        })
+    }
+}
+
+impl Display for Hello {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "%{} {}", Self::COMMAND_NAME, self.version)?;
+        for capability in &self.capabilities {
+            write!(f, " {capability}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum UserKind {
     Admin,
     Normal,
@@ -184,9 +1215,31 @@ impl UserKind {
     pub const ADMIN_KIND: &'static str = "admin";
 }
 
+impl str::FromStr for UserKind {
+    type Err = Error;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        match kind {
+            Self::NORMAL_KIND => Ok(Self::Normal),
+            Self::ADMIN_KIND => Ok(Self::Admin),
+            _ => Err(Error::InvalidUserKind),
+        }
+    }
+}
+
+impl Display for UserKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Admin => write!(f, "{}", Self::ADMIN_KIND),
+            Self::Normal => write!(f, "{}", Self::NORMAL_KIND),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::Command;
+    use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn test() {
@@ -194,12 +1247,246 @@ mod test {
             "%add_user e634488a-a14e-4166-903c-56ac9f37f8e9 normal",
             "%remove_user e634488a-a14e-4166-903c-56ac9f37f8e9",
             "%login e634488a-a14e-4166-903c-56ac9f37f8e9",
+            "%login e634488a-a14e-4166-903c-56ac9f37f8e9 s3cr3t",
             "%show_users",
+            "%bye",
+            "%kick Roma спам",
+            "%ban 192.168.0.1 2h",
+            "%mute Alex 30m",
+            "%unmute Alex",
+            "%stats",
+            "%dm Roma привет, это лично тебе",
+            "%dms",
+            "%dms Roma",
+            "%mentions",
+            "%edit 42 исправленный текст",
+            "%delete 42",
+            "%reply 42 согласен",
+            "%thread 42",
+            "%react 42 👍",
+            "%unreact 42 👍",
+            "%history",
+            "%history 2024-05-01T12:30:00Z",
+            "%history - 2024-05-01T15:30:00.250+03:00",
+            "%file_offer Roma 11 b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9 hello world.txt",
+            "%file_accept 1",
+            "%file_decline 1",
+            "%file_cancel 1",
+            "%file_chunk 1 aGVsbG8gd29ybGQ=",
+            "%file_end 1",
+            "Пишите @Roma, он знает",
             "@Roma @Alex Пацаны, помогите распарсить",
             "Good bye, world!",
+            "%begin\nthread 'main' panicked:\n%%end\n\n@Roma глянь\n%end",
+            "%begin\n%%show_users\n%end",
+            "%hello 1 framed multi_line",
         ];
         for sample in samples {
             assert!(Command::new(sample).is_ok(), "{sample}");
         }
+
+        // id - одно слово: имя с пробелом испортило бы файлы истории и личных сообщений
+        assert!(Command::new("%login ci bot s3cr3t").is_err());
+    }
+
+    fn user_kind() -> impl Strategy<Value = UserKind> {
+        prop_oneof![Just(UserKind::Admin), Just(UserKind::Normal)]
+    }
+
+    fn uuid() -> impl Strategy<Value = Uuid> {
+        any::<u128>().prop_map(Uuid::from_u128)
+    }
+
+    fn user_name() -> impl Strategy<Value = String> {
+        "[a-zA-Z_][a-zA-Z0-9_]{0,15}"
+    }
+
+    fn duration() -> impl Strategy<Value = Option<Duration>> {
+        prop::option::of((1u64..10_000_000).prop_map(Duration::from_secs))
+    }
+
+    fn time() -> impl Strategy<Value = SystemTime> {
+        (0u64..10_000_000_000_000)
+            .prop_map(|millis| SystemTime::UNIX_EPOCH + Duration::from_millis(millis))
+    }
+
+    fn ban_target() -> impl Strategy<Value = BanTarget> {
+        prop_oneof![
+            user_name().prop_map(BanTarget::User),
+            any::<[u8; 4]>().prop_map(|ip| BanTarget::Ip(IpAddr::from(ip))),
+            any::<[u16; 8]>().prop_map(|ip| BanTarget::Ip(IpAddr::from(ip))),
+        ]
+    }
+
+    fn moderation_command() -> impl Strategy<Value = Command> {
+        prop_oneof![
+            (user_name(), prop::option::of("[^\r\n]{1,32}"))
+                .prop_map(|(user, reason)| Command::Kick(Kick { user, reason })),
+            (ban_target(), duration())
+                .prop_map(|(target, duration)| Command::Ban(Ban { target, duration })),
+            ban_target().prop_map(|target| Command::Unban(Unban { target })),
+            (user_name(), duration())
+                .prop_map(|(user, duration)| Command::Mute(Mute { user, duration })),
+            user_name().prop_map(|user| Command::Unmute(Unmute { user })),
+        ]
+    }
+
+    fn reaction_command() -> impl Strategy<Value = Command> {
+        prop_oneof![
+            (any::<u64>(), "[^\\s]{1,16}")
+                .prop_map(|(id, reaction)| Command::React(React { id, reaction })),
+            (any::<u64>(), "[^\\s]{1,16}")
+                .prop_map(|(id, reaction)| Command::Unreact(Unreact { id, reaction })),
+        ]
+    }
+
+    fn file_command() -> impl Strategy<Value = Command> {
+        prop_oneof![
+            (
+                user_name(),
+                any::<u64>(),
+                "[0-9a-f]{64}",
+                "[^/\\\\\r\n.][^/\\\\\r\n]{0,31}"
+            )
+                .prop_map(|(user, size, checksum, name)| Command::FileOffer(
+                    FileOffer {
+                        user,
+                        size,
+                        checksum,
+                        name
+                    }
+                )),
+            any::<u64>().prop_map(|id| Command::FileAccept(FileAccept { id })),
+            any::<u64>().prop_map(|id| Command::FileDecline(FileDecline { id })),
+            any::<u64>().prop_map(|id| Command::FileCancel(FileCancel { id })),
+            (
+                any::<u64>(),
+                prop::collection::vec(any::<u8>(), 1..=MAX_FILE_CHUNK_SIZE)
+            )
+                .prop_map(|(id, data)| Command::FileChunk(FileChunk { id, data })),
+            any::<u64>().prop_map(|id| Command::FileEnd(FileEnd { id })),
+        ]
+    }
+
+    fn message_with_mentions() -> impl Strategy<Value = Command> {
+        (
+            "[^%@\r\n]{0,16}",
+            prop::collection::vec(("[^@\r\n]{0,16}", user_name()), 1..4),
+            "[^@\r\n]{0,16}",
+        )
+            .prop_map(|(head, mentioned, tail)| {
+                let mut message = head;
+                let mut user_names: Vec<String> = Vec::new();
+
+                for (text, name) in mentioned {
+                    message.push_str(&format!(" @{name} {text}"));
+                    if !user_names.contains(&name) {
+                        user_names.push(name);
+                    }
+                }
+                message.push_str(&format!(" {tail}"));
+
+                Command::MessageWithMentions(MessageWithMentions {
+                    user_names,
+                    message,
+                })
+            })
+    }
+
+    fn command() -> impl Strategy<Value = Command> {
+        prop_oneof![
+            (
+                "[a-zA-Zа-яА-Я0-9_-]{1,36}",
+                prop::option::of("[a-zA-Z0-9_-]{1,32}")
+            )
+                .prop_map(|(id, token)| Command::Login(Login { id, token })),
+            "[^%@\r\n][^@\r\n]{0,64}".prop_map(|message| Command::Message(Message { message })),
+            message_with_mentions(),
+            prop::collection::vec("[^\r\n]{0,16}", 2..5)
+                .prop_map(|lines| Command::text(lines.join("\n"))),
+            (uuid(), user_kind()).prop_map(|(id, kind)| Command::AddUser(AddUser { id, kind })),
+            uuid().prop_map(|id| Command::RemoveUser(RemoveUser { id })),
+            Just(Command::ShowUsers(ShowUsers)),
+            Just(Command::Bye(Bye)),
+            Just(Command::Stats(Stats)),
+            Just(Command::ShowMentions(ShowMentions)),
+            (any::<u64>(), "[^\r\n]{1,64}")
+                .prop_map(|(id, message)| Command::Edit(Edit { id, message })),
+            any::<u64>().prop_map(|id| Command::Delete(Delete { id })),
+            (any::<u64>(), "[^\r\n]{1,64}")
+                .prop_map(|(id, message)| Command::Reply(Reply { id, message })),
+            any::<u64>().prop_map(|id| Command::Thread(Thread { id })),
+            reaction_command(),
+            file_command(),
+            (prop::option::of(time()), prop::option::of(time()))
+                .prop_map(|(since, until)| Command::History(History { since, until })),
+            (user_name(), "[^\r\n]{1,64}").prop_map(|(user, message)| Command::DirectMessage(
+                DirectMessage { user, message }
+            )),
+            prop::option::of(user_name())
+                .prop_map(|user| Command::ShowDirectMessages(ShowDirectMessages { user })),
+            moderation_command(),
+            (
+                any::<u32>(),
+                prop::collection::vec("[a-z_]{1,16}(=[0-9]{1,8})?", 0..4)
+            )
+                .prop_map(|(version, capabilities)| Command::Hello(Hello {
+                    version,
+                    capabilities
+                })),
+        ]
+    }
+
+    #[test]
+    fn invalid_reactions() {
+        assert!(matches!(
+            Command::new("%react 42"),
+            Err(Error::MissingArgument)
+        ));
+        assert!(matches!(
+            Command::new("%react 42 два слова"),
+            Err(Error::InvalidReaction)
+        ));
+        assert!(matches!(
+            Command::new("%unreact 42 очень-длинная-реакция"),
+            Err(Error::InvalidReaction)
+        ));
+    }
+
+    #[test]
+    fn mentions_anywhere() {
+        assert_eq!(
+            mentions("@Roma, привет! Пиши на roma@mail.ru, и позови @Alex. И еще раз @Roma"),
+            vec!["Roma".to_string(), "Alex".to_string()]
+        );
+        assert!(mentions("@ без имени").is_empty());
+    }
+
+    proptest! {
+        #[test]
+        fn wire_round_trip(cmd in command()) {
+            prop_assert_eq!(Command::new(&cmd.to_wire()).unwrap(), cmd);
+        }
+    }
+
+    #[test]
+    fn to_wire() {
+        let samples = vec![
+            "%add_user e634488a-a14e-4166-903c-56ac9f37f8e9 admin",
+            "%remove_user e634488a-a14e-4166-903c-56ac9f37f8e9",
+            "%login e634488a-a14e-4166-903c-56ac9f37f8e9",
+            "%login e634488a-a14e-4166-903c-56ac9f37f8e9 s3cr3t",
+            "%show_users",
+            "%bye",
+            "@Roma @Alex Пацаны, помогите распарсить",
+            "Good bye, world!",
+            "%begin\nthread 'main' panicked:\n%%end\n\n@Roma глянь\n%end",
+            "%begin\n%%show_users\n%end",
+            "%hello 1 framed multi_line",
+        ];
+        for sample in samples {
+            let cmd = Command::new(&format!("{sample}\n")).unwrap();
+            assert_eq!(cmd.to_wire(), format!("{sample}\n"));
+        }
     }
 }
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::error::Error;
+use crate::files;
+
+/// Сколько последних сообщений хранится в переписке одной пары пользователей.
+pub const MAX_CONVERSATION_LENGTH: usize = 1000;
+
+/// Одно личное сообщение.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectMessageEntry {
+    pub from: String,
+    pub to: String,
+    pub text: String,
+}
+
+/// Хранилище личной переписки. Сообщения каждой пары пользователей хранятся отдельно
+/// и никогда не попадают в общий чат.
+pub trait DirectMessageStorage: Send + Sync {
+    fn add(&self, entry: DirectMessageEntry) -> Result<(), Error>;
+
+    /// Собеседники пользователя в алфавитном порядке.
+    fn conversations(&self, user: &str) -> Result<Vec<String>, Error>;
+
+    /// Переписка двух пользователей от старых сообщений к новым.
+    fn conversation(&self, user: &str, other: &str) -> Result<Vec<DirectMessageEntry>, Error>;
+
+    /// Сохраняет накопленные изменения. Вызывается при остановке сервера.
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<T: DirectMessageStorage + ?Sized> DirectMessageStorage for Box<T> {
+    fn add(&self, entry: DirectMessageEntry) -> Result<(), Error> {
+        (**self).add(entry)
+    }
+
+    fn conversations(&self, user: &str) -> Result<Vec<String>, Error> {
+        (**self).conversations(user)
+    }
+
+    fn conversation(&self, user: &str, other: &str) -> Result<Vec<DirectMessageEntry>, Error> {
+        (**self).conversation(user, other)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        (**self).flush()
+    }
+}
+
+//
+// Переписка пары хранится под ключом из двух имен в алфавитном порядке,
+// чтобы сообщения в обе стороны попадали в одну переписку.
+//
+
+fn pair(user: &str, other: &str) -> (String, String) {
+    if user <= other {
+        (user.to_string(), other.to_string())
+    } else {
+        (other.to_string(), user.to_string())
+    }
+}
+
+/// Личная переписка в памяти, теряется при перезапуске сервера.
+#[derive(Default)]
+pub struct MemoryDirectMessageStorage {
+    conversations: Mutex<HashMap<(String, String), Vec<DirectMessageEntry>>>,
+}
+
+impl MemoryDirectMessageStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn entries(&self) -> Vec<DirectMessageEntry> {
+        self.conversations
+            .lock()
+            .unwrap()
+            .values()
+            .flatten()
+            .cloned()
+            .collect()
+    }
+}
+
+impl DirectMessageStorage for MemoryDirectMessageStorage {
+    fn add(&self, entry: DirectMessageEntry) -> Result<(), Error> {
+        let mut conversations = self.conversations.lock().unwrap();
+        let conversation = conversations
+            .entry(pair(&entry.from, &entry.to))
+            .or_default();
+
+        conversation.push(entry);
+        if conversation.len() > MAX_CONVERSATION_LENGTH {
+            conversation.remove(0);
+        }
+
+        Ok(())
+    }
+
+    fn conversations(&self, user: &str) -> Result<Vec<String>, Error> {
+        let mut users: Vec<String> = self
+            .conversations
+            .lock()
+            .unwrap()
+            .keys()
+            .filter_map(|(first, second)| {
+                if first == user {
+                    Some(second.clone())
+                } else if second == user {
+                    Some(first.clone())
+                } else {
+                    None
+                }
+            })
+            .collect();
+        users.sort();
+
+        Ok(users)
+    }
+
+    fn conversation(&self, user: &str, other: &str) -> Result<Vec<DirectMessageEntry>, Error> {
+        Ok(self
+            .conversations
+            .lock()
+            .unwrap()
+            .get(&pair(user, other))
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+/// Личная переписка в текстовом файле, по сообщению на строку: `<from> <to> <text>`.
+/// Каждое сообщение сразу дописывается в конец файла, а `flush` переписывает файл заново,
+/// убирая из него сообщения, вытесненные из переписки.
+pub struct FileDirectMessageStorage {
+    path: PathBuf,
+    storage: MemoryDirectMessageStorage,
+    /// Дописывались ли в файл сообщения после того, как он был записан целиком.
+    dirty: Mutex<bool>,
+}
+
+impl FileDirectMessageStorage {
+    /// Открывает файл с перепиской. Если файла нет, хранилище будет пустым.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let path = path.into();
+        let storage = MemoryDirectMessageStorage::new();
+
+        let content = files::load(&path)?;
+
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            let mut parts = line.splitn(3, ' ');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(from), Some(to), Some(text)) => storage.add(DirectMessageEntry {
+                    from: from.to_string(),
+                    to: to.to_string(),
+                    text: text.to_string(),
+                })?,
+                _ => return Err(Error::InvalidInput),
+            }
+        }
+
+        Ok(Self {
+            path,
+            storage,
+            dirty: Mutex::new(false),
+        })
+    }
+}
+
+impl DirectMessageStorage for FileDirectMessageStorage {
+    fn add(&self, entry: DirectMessageEntry) -> Result<(), Error> {
+        files::check_user(&entry.from)?;
+        files::check_user(&entry.to)?;
+        let mut dirty = self.dirty.lock().unwrap();
+        files::append(&self.path, &entry_line(&entry))?;
+        self.storage.add(entry)?;
+        *dirty = true;
+        Ok(())
+    }
+
+    fn conversations(&self, user: &str) -> Result<Vec<String>, Error> {
+        self.storage.conversations(user)
+    }
+
+    fn conversation(&self, user: &str, other: &str) -> Result<Vec<DirectMessageEntry>, Error> {
+        self.storage.conversation(user, other)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        let mut dirty = self.dirty.lock().unwrap();
+        if !*dirty {
+            return Ok(());
+        }
+
+        let mut content = String::new();
+        for entry in self.storage.entries() {
+            content.push_str(&entry_line(&entry));
+            content.push('\n');
+        }
+
+        files::save(&self.path, &content)?;
+
+        *dirty = false;
+        Ok(())
+    }
+}
+
+fn entry_line(entry: &DirectMessageEntry) -> String {
+    format!("{} {} {}", entry.from, entry.to, entry.text)
+}
@@ -1,4 +1,5 @@
 use std::fmt::Display;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum Error {
@@ -9,9 +10,73 @@ pub enum Error {
     InvalidUserKind,
     MissingUserName,
     MissingCommandName,
+    PermissionDenied,
+    InvalidCredentials,
+    LineTooLong,
+    ShutdownTimeout,
+    InvalidDuration,
+    UnknownUser,
+    Banned,
+    Muted,
+    RateLimited(Duration),
+    TooManyConnections,
+    LoginTimeout,
+    NotLoggedIn,
+    InvalidMessageId,
+    UnknownMessage,
+    InvalidReaction,
+    InvalidTime,
+    InvalidTransferId,
+    UnknownTransfer,
+    FileTooLarge,
+    ChecksumMismatch,
+    TooManyTransfers,
+    MessageTooLong,
+    UnsupportedVersion,
+    Rejected(String),
     IO(std::io::Error),
 }
 
+impl Error {
+    /// Короткое имя варианта, например для меток в метриках.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::InvalidInput => "invalid_input",
+            Self::UnknownCommand => "unknown_command",
+            Self::InvalidUuid => "invalid_uuid",
+            Self::MissingArgument => "missing_argument",
+            Self::InvalidUserKind => "invalid_user_kind",
+            Self::MissingUserName => "missing_user_name",
+            Self::MissingCommandName => "missing_command_name",
+            Self::PermissionDenied => "permission_denied",
+            Self::InvalidCredentials => "invalid_credentials",
+            Self::LineTooLong => "line_too_long",
+            Self::ShutdownTimeout => "shutdown_timeout",
+            Self::InvalidDuration => "invalid_duration",
+            Self::UnknownUser => "unknown_user",
+            Self::Banned => "banned",
+            Self::Muted => "muted",
+            Self::RateLimited(_) => "rate_limited",
+            Self::TooManyConnections => "too_many_connections",
+            Self::LoginTimeout => "login_timeout",
+            Self::NotLoggedIn => "not_logged_in",
+            Self::InvalidMessageId => "invalid_message_id",
+            Self::UnknownMessage => "unknown_message",
+            Self::InvalidReaction => "invalid_reaction",
+            Self::InvalidTime => "invalid_time",
+            Self::InvalidTransferId => "invalid_transfer_id",
+            Self::UnknownTransfer => "unknown_transfer",
+            Self::FileTooLarge => "file_too_large",
+            Self::ChecksumMismatch => "checksum_mismatch",
+            Self::TooManyTransfers => "too_many_transfers",
+            Self::MessageTooLong => "message_too_long",
+            Self::UnsupportedVersion => "unsupported_version",
+            Self::Rejected(_) => "rejected",
+            Self::IO(_) => "io",
+        }
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -22,6 +87,34 @@ impl Display for Error {
             Self::InvalidUserKind => write!(f, "invalid user kind"),
             Self::MissingUserName => write!(f, "missing user name"),
             Self::MissingCommandName => write!(f, "missing command name"),
+            Self::PermissionDenied => write!(f, "permission denied"),
+            Self::InvalidCredentials => write!(f, "invalid credentials"),
+            Self::LineTooLong => write!(f, "line too long"),
+            Self::ShutdownTimeout => write!(f, "shutdown timed out"),
+            Self::InvalidDuration => write!(f, "invalid duration"),
+            Self::UnknownUser => write!(f, "unknown user"),
+            Self::Banned => write!(f, "banned"),
+            Self::Muted => write!(f, "muted"),
+            Self::RateLimited(retry_after) => write!(
+                f,
+                "rate limited, retry after {}s",
+                retry_after.as_secs_f64().ceil().max(1.0)
+            ),
+            Self::TooManyConnections => write!(f, "too many connections"),
+            Self::LoginTimeout => write!(f, "login timed out"),
+            Self::NotLoggedIn => write!(f, "not logged in"),
+            Self::InvalidMessageId => write!(f, "invalid message id"),
+            Self::UnknownMessage => write!(f, "unknown message"),
+            Self::InvalidReaction => write!(f, "invalid reaction"),
+            Self::InvalidTime => write!(f, "invalid time"),
+            Self::InvalidTransferId => write!(f, "invalid transfer id"),
+            Self::UnknownTransfer => write!(f, "unknown transfer"),
+            Self::FileTooLarge => write!(f, "file too large"),
+            Self::ChecksumMismatch => write!(f, "checksum mismatch"),
+            Self::TooManyTransfers => write!(f, "too many transfers"),
+            Self::MessageTooLong => write!(f, "message too long"),
+            Self::UnsupportedVersion => write!(f, "unsupported protocol version"),
+            Self::Rejected(reason) => write!(f, "rejected: {reason}"),
             Self::IO(e) => write!(f, "input/output error: {e}"),
         }
     }
@@ -0,0 +1,47 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use crate::error::Error;
+
+//
+// Общий код файловых хранилищ: каждое из них держит данные в памяти и хранит их
+// в текстовом файле, по записи на строку.
+//
+
+/// Содержимое файла хранилища. Если файла еще нет, хранилище считается пустым.
+pub(crate) fn load(path: &Path) -> Result<String, Error> {
+    match fs::read_to_string(path) {
+        Ok(value) => Ok(value),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(Error::IO(e)),
+    }
+}
+
+/// Заменяет содержимое файла хранилища. Пишем во временный файл и переименовываем его,
+/// чтобы при сбое не остаться с наполовину записанным файлом.
+pub(crate) fn save(path: &Path, content: &str) -> Result<(), Error> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, content).map_err(Error::IO)?;
+    fs::rename(&tmp_path, path).map_err(Error::IO)
+}
+
+/// Поля записей в файлах разделяются пробелами, поэтому имя пользователя в записи
+/// не может содержать пробельных символов: такая запись испортила бы файл.
+pub(crate) fn check_user(user: &str) -> Result<(), Error> {
+    if user.contains(char::is_whitespace) {
+        return Err(Error::InvalidInput);
+    }
+    Ok(())
+}
+
+/// Дописывает строку в конец файла хранилища, создавая файл, если его еще нет.
+pub(crate) fn append(path: &Path, line: &str) -> Result<(), Error> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(format!("{line}\n").as_bytes()))
+        .map_err(Error::IO)
+}
@@ -0,0 +1,542 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use crate::commands::format_time;
+use crate::commands::parse_time;
+use crate::error::Error;
+use crate::files;
+
+/// Сколько последних сообщений общего чата хранится. Старые вытесняются новыми.
+pub const MAX_HISTORY_LENGTH: usize = 10_000;
+
+/// Сообщение общего чата. Идентификатор и время присваивает хранилище. Идентификатор - порядковый
+/// номер сообщения: идентификаторы растут монотонно и не переиспользуются, даже если сообщение удалено.
+/// Время (UTC, с точностью до миллисекунд) у более нового сообщения никогда не меньше, чем у старого.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub time: SystemTime,
+    /// Сообщение, на которое это сообщение отвечает.
+    pub parent: Option<u64>,
+    pub from: String,
+    pub text: String,
+    pub edited: bool,
+    /// Кто какими реакциями отметил сообщение: реакция -> имена пользователей.
+    pub reactions: BTreeMap<String, BTreeSet<String>>,
+}
+
+/// История общего чата.
+pub trait HistoryStorage: Send + Sync {
+    /// Сохраняет сообщение (или ответ на сообщение `parent`) и возвращает его вместе
+    /// с присвоенными идентификатором и временем.
+    fn add(&self, from: &str, text: &str, parent: Option<u64>) -> Result<HistoryEntry, Error>;
+
+    fn get(&self, id: u64) -> Result<Option<HistoryEntry>, Error>;
+
+    /// Последние `limit` сообщений, отправленных начиная с `since` и раньше `until`,
+    /// от старых к новым. Без границы промежуток с этой стороны не ограничен.
+    fn range(
+        &self,
+        since: Option<SystemTime>,
+        until: Option<SystemTime>,
+        limit: usize,
+    ) -> Result<Vec<HistoryEntry>, Error>;
+
+    /// Вся ветка обсуждения, в которую входит сообщение: начальное сообщение и все ответы
+    /// на него (в том числе ответы на ответы) в порядке отправки. Пустая, если сообщения нет.
+    fn thread(&self, id: u64) -> Result<Vec<HistoryEntry>, Error>;
+
+    /// Возвращает `false`, если сообщения нет.
+    fn edit(&self, id: u64, text: &str) -> Result<bool, Error>;
+
+    /// Возвращает `false`, если сообщения нет.
+    fn delete(&self, id: u64) -> Result<bool, Error>;
+
+    /// Пользователь отмечает сообщение реакцией. Каждой реакцией пользователь может отметить
+    /// сообщение только один раз. Возвращает новое количество таких реакций или `None`,
+    /// если сообщения нет или пользователь уже отметил его этой реакцией.
+    fn react(&self, id: u64, user: &str, reaction: &str) -> Result<Option<usize>, Error>;
+
+    /// Пользователь снимает свою реакцию. Возвращает новое количество таких реакций или `None`,
+    /// если сообщения нет или пользователь не отмечал его этой реакцией.
+    fn unreact(&self, id: u64, user: &str, reaction: &str) -> Result<Option<usize>, Error>;
+
+    /// Сохраняет накопленные изменения. Вызывается при остановке сервера.
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<T: HistoryStorage + ?Sized> HistoryStorage for Box<T> {
+    fn add(&self, from: &str, text: &str, parent: Option<u64>) -> Result<HistoryEntry, Error> {
+        (**self).add(from, text, parent)
+    }
+
+    fn get(&self, id: u64) -> Result<Option<HistoryEntry>, Error> {
+        (**self).get(id)
+    }
+
+    fn range(
+        &self,
+        since: Option<SystemTime>,
+        until: Option<SystemTime>,
+        limit: usize,
+    ) -> Result<Vec<HistoryEntry>, Error> {
+        (**self).range(since, until, limit)
+    }
+
+    fn thread(&self, id: u64) -> Result<Vec<HistoryEntry>, Error> {
+        (**self).thread(id)
+    }
+
+    fn edit(&self, id: u64, text: &str) -> Result<bool, Error> {
+        (**self).edit(id, text)
+    }
+
+    fn delete(&self, id: u64) -> Result<bool, Error> {
+        (**self).delete(id)
+    }
+
+    fn react(&self, id: u64, user: &str, reaction: &str) -> Result<Option<usize>, Error> {
+        (**self).react(id, user, reaction)
+    }
+
+    fn unreact(&self, id: u64, user: &str, reaction: &str) -> Result<Option<usize>, Error> {
+        (**self).unreact(id, user, reaction)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        (**self).flush()
+    }
+}
+
+struct History {
+    next_id: u64,
+    last_time: SystemTime,
+    entries: BTreeMap<u64, HistoryEntry>,
+}
+
+impl History {
+    fn insert(&mut self, entry: HistoryEntry) {
+        self.next_id = self.next_id.max(entry.id + 1);
+        self.last_time = self.last_time.max(entry.time);
+        self.entries.insert(entry.id, entry);
+
+        if self.entries.len() > MAX_HISTORY_LENGTH {
+            self.entries.pop_first();
+        }
+    }
+}
+
+/// История в памяти, теряется при перезапуске сервера.
+pub struct MemoryHistoryStorage {
+    history: Mutex<History>,
+}
+
+impl Default for MemoryHistoryStorage {
+    fn default() -> Self {
+        Self {
+            history: Mutex::new(History {
+                next_id: 1,
+                last_time: SystemTime::UNIX_EPOCH,
+                entries: BTreeMap::new(),
+            }),
+        }
+    }
+}
+
+impl MemoryHistoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn insert(&self, entry: HistoryEntry) {
+        self.history.lock().unwrap().insert(entry);
+    }
+
+    fn entries(&self) -> Vec<HistoryEntry> {
+        self.history
+            .lock()
+            .unwrap()
+            .entries
+            .values()
+            .cloned()
+            .collect()
+    }
+}
+
+impl HistoryStorage for MemoryHistoryStorage {
+    fn add(&self, from: &str, text: &str, parent: Option<u64>) -> Result<HistoryEntry, Error> {
+        let mut history = self.history.lock().unwrap();
+
+        //
+        // Если системные часы перевели назад, новое сообщение получает время предыдущего,
+        // чтобы порядок по времени совпадал с порядком по идентификаторам.
+        //
+
+        let entry = HistoryEntry {
+            id: history.next_id,
+            time: now().max(history.last_time),
+            parent,
+            from: from.to_string(),
+            text: text.to_string(),
+            edited: false,
+            reactions: BTreeMap::new(),
+        };
+        history.insert(entry.clone());
+
+        Ok(entry)
+    }
+
+    fn get(&self, id: u64) -> Result<Option<HistoryEntry>, Error> {
+        Ok(self.history.lock().unwrap().entries.get(&id).cloned())
+    }
+
+    fn range(
+        &self,
+        since: Option<SystemTime>,
+        until: Option<SystemTime>,
+        limit: usize,
+    ) -> Result<Vec<HistoryEntry>, Error> {
+        let history = self.history.lock().unwrap();
+
+        //
+        // Время не убывает вместе с идентификаторами, поэтому идем от новых сообщений к старым
+        // и останавливаемся на первом сообщении, отправленном раньше начала промежутка.
+        //
+
+        let mut entries: Vec<HistoryEntry> = history
+            .entries
+            .values()
+            .rev()
+            .filter(|entry| until.is_none_or(|until| entry.time < until))
+            .take_while(|entry| since.is_none_or(|since| entry.time >= since))
+            .take(limit)
+            .cloned()
+            .collect();
+        entries.reverse();
+
+        Ok(entries)
+    }
+
+    fn thread(&self, id: u64) -> Result<Vec<HistoryEntry>, Error> {
+        let history = self.history.lock().unwrap();
+
+        //
+        // Поднимаемся от сообщения к началу ветки. Если какое-то из сообщений выше удалено,
+        // веткой считается то, что от нее осталось.
+        //
+
+        let Some(mut root) = history.entries.get(&id) else {
+            return Ok(Vec::new());
+        };
+        while let Some(parent) = root.parent.and_then(|parent| history.entries.get(&parent)) {
+            root = parent;
+        }
+
+        //
+        // Ответ всегда новее сообщения, на которое он отвечает, поэтому за один проход
+        // по истории в порядке идентификаторов родитель попадает в ветку раньше ответа.
+        //
+
+        let mut thread: Vec<HistoryEntry> = Vec::new();
+        for entry in history.entries.range(root.id..).map(|(_, entry)| entry) {
+            let in_thread = entry.id == root.id
+                || entry
+                    .parent
+                    .is_some_and(|parent| thread.iter().any(|known| known.id == parent));
+
+            if in_thread {
+                thread.push(entry.clone());
+            }
+        }
+
+        Ok(thread)
+    }
+
+    fn edit(&self, id: u64, text: &str) -> Result<bool, Error> {
+        let mut history = self.history.lock().unwrap();
+
+        Ok(match history.entries.get_mut(&id) {
+            Some(entry) => {
+                entry.text = text.to_string();
+                entry.edited = true;
+                true
+            }
+            None => false,
+        })
+    }
+
+    fn delete(&self, id: u64) -> Result<bool, Error> {
+        Ok(self.history.lock().unwrap().entries.remove(&id).is_some())
+    }
+
+    fn react(&self, id: u64, user: &str, reaction: &str) -> Result<Option<usize>, Error> {
+        let mut history = self.history.lock().unwrap();
+        let Some(entry) = history.entries.get_mut(&id) else {
+            return Ok(None);
+        };
+
+        let users = entry.reactions.entry(reaction.to_string()).or_default();
+        Ok(users.insert(user.to_string()).then_some(users.len()))
+    }
+
+    fn unreact(&self, id: u64, user: &str, reaction: &str) -> Result<Option<usize>, Error> {
+        let mut history = self.history.lock().unwrap();
+        let Some(entry) = history.entries.get_mut(&id) else {
+            return Ok(None);
+        };
+        let Some(users) = entry.reactions.get_mut(reaction) else {
+            return Ok(None);
+        };
+
+        if !users.remove(user) {
+            return Ok(None);
+        }
+
+        let count = users.len();
+        if count == 0 {
+            entry.reactions.remove(reaction);
+        }
+        Ok(Some(count))
+    }
+}
+
+/// История в текстовом файле, по сообщению на строку: `<id> <time> <edited|-> <parent|-> <from> <text>`,
+/// где `time` - время в формате RFC 3339. Следующие строки многострочного сообщения идут
+/// за ним отдельными строками файла, каждая - с `+` в начале.
+/// Первая строка `next <id>` хранит следующий идентификатор, чтобы после перезапуска
+/// не выдать заново идентификатор удаленного сообщения. Реакции хранятся после сообщений,
+/// по строке на реакцию каждого пользователя: `react <id> <reaction> <user>`.
+/// Каждое изменение сразу дописывается в конец файла: новое или исправленное сообщение -
+/// строкой сообщения, удаление - строкой `delete <id>`, реакции - строками `react`
+/// и `unreact <id> <reaction> <user>`. При открытии файла они применяются по порядку,
+/// а `flush` переписывает файл заново, оставляя только текущее состояние.
+pub struct FileHistoryStorage {
+    path: PathBuf,
+    storage: MemoryHistoryStorage,
+    /// Дописывались ли в файл изменения после того, как он был записан целиком.
+    dirty: Mutex<bool>,
+}
+
+impl FileHistoryStorage {
+    /// Открывает файл с историей. Если файла нет, хранилище будет пустым.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let path = path.into();
+        let storage = MemoryHistoryStorage::new();
+
+        let content = files::load(&path)?;
+
+        let mut lines = content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .peekable();
+        while let Some(line) = lines.next() {
+            if let Some(next_id) = line.strip_prefix("next ") {
+                let next_id: u64 = next_id.parse().map_err(|_| Error::InvalidInput)?;
+                let mut history = storage.history.lock().unwrap();
+                history.next_id = history.next_id.max(next_id);
+                continue;
+            }
+
+            if let Some(id) = line.strip_prefix("delete ") {
+                storage.delete(id.parse().map_err(|_| Error::InvalidInput)?)?;
+                continue;
+            }
+
+            if let Some((kind, reaction)) = line
+                .split_once(' ')
+                .filter(|(kind, _)| *kind == "react" || *kind == "unreact")
+            {
+                let parts: Vec<&str> = reaction.splitn(3, ' ').collect();
+                let [id, reaction, user] = parts[..] else {
+                    return Err(Error::InvalidInput);
+                };
+
+                let id = id.parse().map_err(|_| Error::InvalidInput)?;
+                if kind == "react" {
+                    storage.react(id, user, reaction)?;
+                } else {
+                    storage.unreact(id, user, reaction)?;
+                }
+                continue;
+            }
+
+            let parts: Vec<&str> = line.splitn(6, ' ').collect();
+            let [id, time, edited, parent, from, text] = parts[..] else {
+                return Err(Error::InvalidInput);
+            };
+
+            let mut text = text.to_string();
+            while let Some(more) = lines.next_if(|line| line.starts_with('+')) {
+                text.push('\n');
+                text.push_str(&more[1..]);
+            }
+
+            //
+            // Исправленное сообщение записано еще раз целиком, но без реакций: их берем у прежней версии.
+            //
+
+            let id = id.parse().map_err(|_| Error::InvalidInput)?;
+            let reactions = storage
+                .get(id)?
+                .map(|entry| entry.reactions)
+                .unwrap_or_default();
+
+            storage.insert(HistoryEntry {
+                id,
+                time: parse_time(time).map_err(|_| Error::InvalidInput)?,
+                parent: match parent {
+                    "-" => None,
+                    parent => Some(parent.parse().map_err(|_| Error::InvalidInput)?),
+                },
+                from: from.to_string(),
+                text,
+                edited: edited == "edited",
+                reactions,
+            });
+        }
+
+        Ok(Self {
+            path,
+            storage,
+            dirty: Mutex::new(false),
+        })
+    }
+
+    //
+    // Дописывает изменение в файл. Если дописать не удалось, изменение уже есть в памяти
+    // и попадет в файл при следующем flush, но вызывающий узнает об ошибке.
+    //
+
+    fn append(&self, dirty: &mut bool, line: &str) -> Result<(), Error> {
+        files::append(&self.path, line)?;
+        *dirty = true;
+        Ok(())
+    }
+}
+
+impl HistoryStorage for FileHistoryStorage {
+    fn add(&self, from: &str, text: &str, parent: Option<u64>) -> Result<HistoryEntry, Error> {
+        files::check_user(from)?;
+        let mut dirty = self.dirty.lock().unwrap();
+        let entry = self.storage.add(from, text, parent)?;
+        self.append(&mut dirty, &entry_line(&entry))?;
+        Ok(entry)
+    }
+
+    fn get(&self, id: u64) -> Result<Option<HistoryEntry>, Error> {
+        self.storage.get(id)
+    }
+
+    fn range(
+        &self,
+        since: Option<SystemTime>,
+        until: Option<SystemTime>,
+        limit: usize,
+    ) -> Result<Vec<HistoryEntry>, Error> {
+        self.storage.range(since, until, limit)
+    }
+
+    fn thread(&self, id: u64) -> Result<Vec<HistoryEntry>, Error> {
+        self.storage.thread(id)
+    }
+
+    fn edit(&self, id: u64, text: &str) -> Result<bool, Error> {
+        let mut dirty = self.dirty.lock().unwrap();
+        let edited = self.storage.edit(id, text)?;
+        if let Some(entry) = self.storage.get(id)?.filter(|_| edited) {
+            self.append(&mut dirty, &entry_line(&entry))?;
+        }
+        Ok(edited)
+    }
+
+    fn delete(&self, id: u64) -> Result<bool, Error> {
+        let mut dirty = self.dirty.lock().unwrap();
+        let deleted = self.storage.delete(id)?;
+        if deleted {
+            self.append(&mut dirty, &format!("delete {id}"))?;
+        }
+        Ok(deleted)
+    }
+
+    fn react(&self, id: u64, user: &str, reaction: &str) -> Result<Option<usize>, Error> {
+        let mut dirty = self.dirty.lock().unwrap();
+        let count = self.storage.react(id, user, reaction)?;
+        if count.is_some() {
+            self.append(&mut dirty, &format!("react {id} {reaction} {user}"))?;
+        }
+        Ok(count)
+    }
+
+    fn unreact(&self, id: u64, user: &str, reaction: &str) -> Result<Option<usize>, Error> {
+        let mut dirty = self.dirty.lock().unwrap();
+        let count = self.storage.unreact(id, user, reaction)?;
+        if count.is_some() {
+            self.append(&mut dirty, &format!("unreact {id} {reaction} {user}"))?;
+        }
+        Ok(count)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        let mut dirty = self.dirty.lock().unwrap();
+        if !*dirty {
+            return Ok(());
+        }
+
+        let mut content = format!("next {}\n", self.storage.history.lock().unwrap().next_id);
+        let entries = self.storage.entries();
+        for entry in &entries {
+            content.push_str(&entry_line(entry));
+            content.push('\n');
+        }
+        for entry in &entries {
+            for (reaction, users) in &entry.reactions {
+                for user in users {
+                    content.push_str(&format!("react {} {reaction} {user}\n", entry.id));
+                }
+            }
+        }
+
+        files::save(&self.path, &content)?;
+
+        *dirty = false;
+        Ok(())
+    }
+}
+
+//
+// Сообщение в файле истории. Следующие строки многострочного сообщения начинаются с '+'.
+//
+
+fn entry_line(entry: &HistoryEntry) -> String {
+    let edited = if entry.edited { "edited" } else { "-" };
+    let parent = entry
+        .parent
+        .map_or("-".to_string(), |parent| parent.to_string());
+
+    format!(
+        "{} {} {edited} {parent} {} {}",
+        entry.id,
+        format_time(entry.time),
+        entry.from,
+        entry.text.replace('\n', "\n+")
+    )
+}
+
+//
+// Текущее время с точностью до миллисекунд: с такой точностью время передается клиентам и хранится в файле.
+//
+
+fn now() -> SystemTime {
+    let millis = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+
+    SystemTime::UNIX_EPOCH + Duration::from_millis(millis as u64)
+}
@@ -0,0 +1,39 @@
+use std::net::SocketAddr;
+
+use uuid::Uuid;
+
+use crate::bots::Sender;
+use crate::commands::Command;
+use crate::history::HistoryEntry;
+
+/// Обработчик событий сервера. Все методы по умолчанию ничего не делают,
+/// поэтому реализовывать нужно только интересующие события.
+/// Обработчики вызываются в том порядке, в котором их добавили. Если обработчик паникует,
+/// сервер логирует это и продолжает работу, как будто этого обработчика нет.
+pub trait Hook: Send + Sync {
+    fn on_connect(&self, _connection_id: Uuid, _address: SocketAddr) {}
+
+    /// Вызывается перед выполнением каждой команды клиента и каждого сообщения из HTTP API.
+    /// Следующий обработчик получает команду, которую вернул предыдущий.
+    fn before_command(&self, _sender: &Sender, _command: &Command) -> Action {
+        Action::Continue
+    }
+
+    /// Вызывается после того, как сообщение общего чата разослано.
+    fn after_broadcast(&self, _entry: &HistoryEntry, _mentioned: &[String]) {}
+
+    fn on_login(&self, _connection_id: Uuid, _user: &str) {}
+
+    fn on_disconnect(&self, _connection_id: Uuid) {}
+}
+
+/// Что сделать с командой, которую получил `before_command`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Выполнить команду как есть.
+    Continue,
+    /// Выполнить вместо нее эту команду.
+    Rewrite(Command),
+    /// Не выполнять команду. Клиент получит ошибку "rejected: <причина>".
+    Reject(String),
+}
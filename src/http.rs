@@ -0,0 +1,237 @@
+use std::fmt::Display;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+
+use crate::error::Error;
+
+//
+// Простейший HTTP/1.1 для служебных эндпоинтов и вебхуков: один запрос на соединение, тело запроса -
+// только с Content-Length. Размер заголовков ограничен, чтобы клиент не мог занять всю память.
+//
+
+const MAX_HEAD_LENGTH: u64 = 8192;
+
+pub(crate) struct Request {
+    pub method: String,
+    /// Путь без параметров запроса.
+    pub path: String,
+    /// Параметры запроса (`?name=value&...`), уже раскодированные.
+    pub query: Vec<(String, String)>,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Значение заголовка. Имена заголовков сравниваются без учета регистра.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Читает строку запроса и заголовки.
+pub(crate) fn read_request(connection: &TcpStream) -> Result<Request, Error> {
+    read_head(&mut BufReader::new(connection))
+}
+
+/// Читает строку запроса и заголовки из соединения, которое уже читается через буфер
+/// (например, перед тем как перейти на WebSocket). Все, что идет после заголовков, остается в буфере.
+pub(crate) fn read_head(reader: &mut impl BufRead) -> Result<Request, Error> {
+    let mut reader = reader.by_ref().take(MAX_HEAD_LENGTH);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).map_err(Error::IO)?;
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target),
+        _ => return Err(Error::InvalidInput),
+    };
+
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((percent_decode(name)?, percent_decode(value)?))
+        })
+        .collect::<Result<_, Error>>()?;
+
+    let mut headers = Vec::new();
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).map_err(Error::IO)? == 0 {
+            return Err(Error::InvalidInput);
+        }
+        if header.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    Ok(Request {
+        method,
+        path: path.to_string(),
+        query,
+        headers,
+    })
+}
+
+/// Читает тело запроса длиной из заголовка `Content-Length` как текст в UTF-8.
+/// Тело длиннее `max_length` байт не читается - ошибка `MessageTooLong`.
+pub(crate) fn read_body(
+    reader: &mut impl BufRead,
+    request: &Request,
+    max_length: usize,
+) -> Result<String, Error> {
+    let length: usize = match request.header("Content-Length") {
+        Some(length) => length.parse().map_err(|_| Error::InvalidInput)?,
+        None => 0,
+    };
+    if length > max_length {
+        return Err(Error::MessageTooLong);
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(Error::IO)?;
+    String::from_utf8(body).map_err(|_| Error::InvalidInput)
+}
+
+//
+// Раскодирует %XX и '+' (пробел) в параметрах запроса.
+//
+
+fn percent_decode(input: &str) -> Result<String, Error> {
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut chars = input.bytes();
+
+    while let Some(byte) = chars.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = [chars.next(), chars.next()];
+                let hex = match hex {
+                    [Some(high), Some(low)] => [high, low],
+                    _ => return Err(Error::InvalidInput),
+                };
+                let hex = std::str::from_utf8(&hex).map_err(|_| Error::InvalidInput)?;
+                bytes.push(u8::from_str_radix(hex, 16).map_err(|_| Error::InvalidInput)?);
+            }
+            byte => bytes.push(byte),
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| Error::InvalidInput)
+}
+
+pub(crate) fn write_response(
+    mut connection: &TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<(), Error> {
+    write!(
+        connection,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+    .map_err(Error::IO)
+}
+
+/// Адрес вида `http://<host>[:<port>][/<path>]`. HTTPS не поддерживается.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Url {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Self, Error> {
+        let rest = url.strip_prefix("http://").ok_or(Error::InvalidInput)?;
+        let (authority, path) = match rest.find('/') {
+            Some(start) => rest.split_at(start),
+            None => (rest, "/"),
+        };
+
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| Error::InvalidInput)?),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(Error::InvalidInput);
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl Display for Url {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+/// Отправляет POST-запрос и возвращает код ответа. `timeout` ограничивает и подключение,
+/// и каждую операцию чтения и записи.
+pub(crate) fn post(
+    url: &Url,
+    content_type: &str,
+    body: &str,
+    timeout: Duration,
+) -> Result<u16, Error> {
+    let address = (url.host.as_str(), url.port)
+        .to_socket_addrs()
+        .map_err(Error::IO)?
+        .next()
+        .ok_or(Error::InvalidInput)?;
+
+    let mut connection = TcpStream::connect_timeout(&address, timeout).map_err(Error::IO)?;
+    connection
+        .set_read_timeout(Some(timeout))
+        .map_err(Error::IO)?;
+    connection
+        .set_write_timeout(Some(timeout))
+        .map_err(Error::IO)?;
+
+    write!(
+        connection,
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        url.path,
+        url.host,
+        url.port,
+        body.len()
+    )
+    .map_err(Error::IO)?;
+
+    let mut status_line = String::new();
+    BufReader::new(connection.take(MAX_HEAD_LENGTH))
+        .read_line(&mut status_line)
+        .map_err(Error::IO)?;
+
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or(Error::InvalidInput)
+}
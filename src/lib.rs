@@ -1,2 +1,20 @@
+pub mod audit;
+pub mod block;
+pub mod bots;
+pub mod codec;
 pub mod commands;
+pub mod direct_messages;
 pub mod error;
+mod files;
+pub mod history;
+pub mod hooks;
+mod http;
+pub mod mentions;
+pub mod metrics;
+pub mod moderation;
+pub mod rate_limit;
+pub mod server;
+pub mod storage;
+pub mod transfers;
+pub mod webhooks;
+pub mod websocket;
@@ -0,0 +1,41 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::history::HistoryEntry;
+
+/// Сколько непрочитанных упоминаний хранится для одного пользователя. Старые вытесняются новыми.
+pub const MAX_UNREAD_MENTIONS: usize = 100;
+
+/// Непрочитанные упоминания каждого пользователя: сообщения общего чата, в которых его упомянули.
+/// Хранятся в памяти, пока пользователь не запросит их командой `%mentions`.
+#[derive(Default)]
+pub struct MentionInbox {
+    unread: Mutex<HashMap<String, VecDeque<HistoryEntry>>>,
+}
+
+impl MentionInbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&self, user: &str, message: HistoryEntry) {
+        let mut unread = self.unread.lock().unwrap();
+        let inbox = unread.entry(user.to_string()).or_default();
+
+        inbox.push_back(message);
+        if inbox.len() > MAX_UNREAD_MENTIONS {
+            inbox.pop_front();
+        }
+    }
+
+    /// Возвращает непрочитанные упоминания пользователя от старых к новым и помечает их прочитанными.
+    pub fn take(&self, user: &str) -> Vec<HistoryEntry> {
+        self.unread
+            .lock()
+            .unwrap()
+            .remove(user)
+            .map(Vec::from)
+            .unwrap_or_default()
+    }
+}
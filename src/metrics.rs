@@ -0,0 +1,343 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fmt::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::error::Error;
+
+//
+// Границы корзин гистограммы времени рассылки сообщения, в секундах.
+//
+
+const BROADCAST_BUCKETS: [f64; 9] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+//
+// Количество сообщений в секунду считается за последние RATE_WINDOW секунд.
+//
+
+const RATE_WINDOW: usize = 60;
+
+/// Мгновенные значения, которые сервер берет из списка соединений в момент вывода метрик.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Gauges {
+    pub connections: usize,
+    pub users: usize,
+}
+
+/// Счетчики сервера, общие для всех потоков.
+pub struct Metrics {
+    started: Instant,
+    messages: AtomicU64,
+    recent_messages: Mutex<RecentCounter>,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    parse_errors: Mutex<BTreeMap<&'static str, u64>>,
+    broadcast: Mutex<Histogram>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            messages: AtomicU64::new(0),
+            recent_messages: Mutex::new(RecentCounter::default()),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            parse_errors: Mutex::new(BTreeMap::new()),
+            broadcast: Mutex::new(Histogram::default()),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn second(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    /// Разослано сообщение в общий чат.
+    pub fn message(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.recent_messages.lock().unwrap().add(self.second());
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Строку от клиента не удалось разобрать в команду.
+    pub fn parse_error(&self, error: &Error) {
+        *self
+            .parse_errors
+            .lock()
+            .unwrap()
+            .entry(error.kind())
+            .or_default() += 1;
+    }
+
+    /// Сколько заняла рассылка одного сообщения всем получателям.
+    pub fn broadcast(&self, duration: Duration) {
+        self.broadcast.lock().unwrap().observe(duration);
+    }
+
+    fn messages_per_second(&self) -> f64 {
+        self.recent_messages
+            .lock()
+            .unwrap()
+            .per_second(self.second())
+    }
+
+    /// Метрики в текстовом формате Prometheus.
+    pub fn render(&self, gauges: Gauges) -> String {
+        let mut out = String::new();
+
+        metric(
+            &mut out,
+            "chat_connections",
+            "gauge",
+            "Active connections.",
+            gauges.connections,
+        );
+        metric(
+            &mut out,
+            "chat_users",
+            "gauge",
+            "Logged-in users.",
+            gauges.users,
+        );
+        metric(
+            &mut out,
+            "chat_messages_total",
+            "counter",
+            "Chat messages broadcast.",
+            self.messages.load(Ordering::Relaxed),
+        );
+        metric(
+            &mut out,
+            "chat_messages_per_second",
+            "gauge",
+            "Chat messages per second over the last minute.",
+            self.messages_per_second(),
+        );
+        metric(
+            &mut out,
+            "chat_received_bytes_total",
+            "counter",
+            "Bytes received from clients.",
+            self.bytes_received.load(Ordering::Relaxed),
+        );
+        metric(
+            &mut out,
+            "chat_sent_bytes_total",
+            "counter",
+            "Bytes sent to clients.",
+            self.bytes_sent.load(Ordering::Relaxed),
+        );
+
+        header(
+            &mut out,
+            "chat_parse_errors_total",
+            "counter",
+            "Lines that failed to parse, by error.",
+        );
+        for (kind, count) in self.parse_errors.lock().unwrap().iter() {
+            writeln!(out, "chat_parse_errors_total{{error=\"{kind}\"}} {count}").unwrap();
+        }
+
+        header(
+            &mut out,
+            "chat_broadcast_duration_seconds",
+            "histogram",
+            "Time to deliver a chat message to all recipients.",
+        );
+        let broadcast = self.broadcast.lock().unwrap();
+        let mut cumulative = 0;
+        for (le, count) in BROADCAST_BUCKETS.iter().zip(broadcast.buckets) {
+            cumulative += count;
+            writeln!(
+                out,
+                "chat_broadcast_duration_seconds_bucket{{le=\"{le}\"}} {cumulative}"
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "chat_broadcast_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            broadcast.count
+        )
+        .unwrap();
+        writeln!(out, "chat_broadcast_duration_seconds_sum {}", broadcast.sum).unwrap();
+        writeln!(
+            out,
+            "chat_broadcast_duration_seconds_count {}",
+            broadcast.count
+        )
+        .unwrap();
+
+        out
+    }
+
+    /// Те же метрики одной строкой вида `ключ=значение ...` для команды `%stats`.
+    pub fn summary(&self, gauges: Gauges) -> String {
+        let mut out = format!(
+            "connections={} users={} messages={} messages_per_second={:.2} received_bytes={} sent_bytes={}",
+            gauges.connections,
+            gauges.users,
+            self.messages.load(Ordering::Relaxed),
+            self.messages_per_second(),
+            self.bytes_received.load(Ordering::Relaxed),
+            self.bytes_sent.load(Ordering::Relaxed),
+        );
+
+        for (kind, count) in self.parse_errors.lock().unwrap().iter() {
+            write!(out, " parse_errors_{kind}={count}").unwrap();
+        }
+
+        let broadcast = self.broadcast.lock().unwrap();
+        if broadcast.count > 0 {
+            write!(
+                out,
+                " broadcast_avg_ms={:.3}",
+                broadcast.sum * 1000.0 / broadcast.count as f64
+            )
+            .unwrap();
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: impl Display) {
+    header(out, name, kind, help);
+    writeln!(out, "{name} {value}").unwrap();
+}
+
+//
+// Количество событий по секундам за последние RATE_WINDOW секунд.
+// Ячейки переиспользуются по кругу, устаревшие обнуляются при сдвиге.
+//
+
+struct RecentCounter {
+    slots: [u64; RATE_WINDOW],
+    second: u64,
+}
+
+impl Default for RecentCounter {
+    fn default() -> Self {
+        Self {
+            slots: [0; RATE_WINDOW],
+            second: 0,
+        }
+    }
+}
+
+impl RecentCounter {
+    fn advance(&mut self, second: u64) {
+        if second <= self.second {
+            return;
+        }
+
+        if second - self.second >= RATE_WINDOW as u64 {
+            self.slots = [0; RATE_WINDOW];
+        } else {
+            for passed in self.second + 1..=second {
+                self.slots[passed as usize % RATE_WINDOW] = 0;
+            }
+        }
+
+        self.second = second;
+    }
+
+    fn add(&mut self, second: u64) {
+        self.advance(second);
+        self.slots[self.second as usize % RATE_WINDOW] += 1;
+    }
+
+    fn per_second(&mut self, second: u64) -> f64 {
+        self.advance(second);
+        self.slots.iter().sum::<u64>() as f64 / RATE_WINDOW as f64
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BROADCAST_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+
+        if let Some(i) = BROADCAST_BUCKETS.iter().position(|le| seconds <= *le) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::new();
+        metrics.message();
+        metrics.received(10);
+        metrics.sent(20);
+        metrics.parse_error(&Error::UnknownCommand);
+        metrics.parse_error(&Error::UnknownCommand);
+        metrics.broadcast(Duration::from_millis(2));
+
+        let text = metrics.render(Gauges {
+            connections: 3,
+            users: 2,
+        });
+
+        for line in [
+            "chat_connections 3",
+            "chat_users 2",
+            "chat_messages_total 1",
+            "chat_received_bytes_total 10",
+            "chat_sent_bytes_total 20",
+            "chat_parse_errors_total{error=\"unknown_command\"} 2",
+            "chat_broadcast_duration_seconds_bucket{le=\"0.001\"} 0",
+            "chat_broadcast_duration_seconds_bucket{le=\"0.005\"} 1",
+            "chat_broadcast_duration_seconds_bucket{le=\"+Inf\"} 1",
+            "chat_broadcast_duration_seconds_count 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{line}");
+        }
+    }
+
+    #[test]
+    fn recent_counter() {
+        let mut counter = RecentCounter::default();
+        counter.add(0);
+        counter.add(0);
+        counter.add(59);
+        assert_eq!(counter.per_second(59), 3.0 / 60.0);
+        assert_eq!(counter.per_second(60), 1.0 / 60.0);
+        assert_eq!(counter.per_second(200), 0.0);
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::commands::BanTarget;
+use crate::error::Error;
+use crate::files;
+
+/// Хранилище банов и мьютов. `until` - момент, когда ограничение снимается сам;
+/// `None` означает бессрочное ограничение. Истекшие ограничения считаются отсутствующими.
+pub trait ModerationStorage: Send + Sync {
+    fn ban(&self, target: BanTarget, until: Option<SystemTime>) -> Result<(), Error>;
+
+    /// Возвращает `false`, если бана не было.
+    fn unban(&self, target: &BanTarget) -> Result<bool, Error>;
+
+    fn is_banned(&self, target: &BanTarget) -> Result<bool, Error>;
+
+    fn mute(&self, user: String, until: Option<SystemTime>) -> Result<(), Error>;
+
+    /// Возвращает `false`, если мьюта не было.
+    fn unmute(&self, user: &str) -> Result<bool, Error>;
+
+    fn is_muted(&self, user: &str) -> Result<bool, Error>;
+
+    /// Сохраняет накопленные изменения. Вызывается при остановке сервера.
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<T: ModerationStorage + ?Sized> ModerationStorage for Box<T> {
+    fn ban(&self, target: BanTarget, until: Option<SystemTime>) -> Result<(), Error> {
+        (**self).ban(target, until)
+    }
+
+    fn unban(&self, target: &BanTarget) -> Result<bool, Error> {
+        (**self).unban(target)
+    }
+
+    fn is_banned(&self, target: &BanTarget) -> Result<bool, Error> {
+        (**self).is_banned(target)
+    }
+
+    fn mute(&self, user: String, until: Option<SystemTime>) -> Result<(), Error> {
+        (**self).mute(user, until)
+    }
+
+    fn unmute(&self, user: &str) -> Result<bool, Error> {
+        (**self).unmute(user)
+    }
+
+    fn is_muted(&self, user: &str) -> Result<bool, Error> {
+        (**self).is_muted(user)
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        (**self).flush()
+    }
+}
+
+/// Баны и мьюты в памяти, теряются при перезапуске сервера.
+#[derive(Default)]
+pub struct MemoryModerationStorage {
+    bans: Mutex<HashMap<BanTarget, Option<SystemTime>>>,
+    mutes: Mutex<HashMap<String, Option<SystemTime>>>,
+}
+
+impl MemoryModerationStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn bans(&self) -> Vec<(BanTarget, Option<SystemTime>)> {
+        let mut bans = self.bans.lock().unwrap();
+        bans.retain(|_, until| active(*until));
+        bans.iter()
+            .map(|(target, until)| (target.clone(), *until))
+            .collect()
+    }
+
+    fn mutes(&self) -> Vec<(String, Option<SystemTime>)> {
+        let mut mutes = self.mutes.lock().unwrap();
+        mutes.retain(|_, until| active(*until));
+        mutes
+            .iter()
+            .map(|(user, until)| (user.clone(), *until))
+            .collect()
+    }
+}
+
+impl ModerationStorage for MemoryModerationStorage {
+    fn ban(&self, target: BanTarget, until: Option<SystemTime>) -> Result<(), Error> {
+        self.bans.lock().unwrap().insert(target, until);
+        Ok(())
+    }
+
+    fn unban(&self, target: &BanTarget) -> Result<bool, Error> {
+        Ok(self.bans.lock().unwrap().remove(target).is_some())
+    }
+
+    fn is_banned(&self, target: &BanTarget) -> Result<bool, Error> {
+        Ok(self
+            .bans
+            .lock()
+            .unwrap()
+            .get(target)
+            .is_some_and(|until| active(*until)))
+    }
+
+    fn mute(&self, user: String, until: Option<SystemTime>) -> Result<(), Error> {
+        self.mutes.lock().unwrap().insert(user, until);
+        Ok(())
+    }
+
+    fn unmute(&self, user: &str) -> Result<bool, Error> {
+        Ok(self.mutes.lock().unwrap().remove(user).is_some())
+    }
+
+    fn is_muted(&self, user: &str) -> Result<bool, Error> {
+        Ok(self
+            .mutes
+            .lock()
+            .unwrap()
+            .get(user)
+            .is_some_and(|until| active(*until)))
+    }
+}
+
+/// Баны и мьюты в текстовом файле, по одному ограничению на строку:
+/// `ban <until|-> <user|ip>` или `mute <until|-> <user>`, где `until` - время снятия в секундах от UNIX epoch.
+/// Каждое изменение сразу записывается на диск, поэтому баны и мьюты не теряются,
+/// даже если сервер остановился аварийно.
+pub struct FileModerationStorage {
+    path: PathBuf,
+    storage: MemoryModerationStorage,
+    writing: Mutex<()>,
+}
+
+impl FileModerationStorage {
+    /// Открывает файл с ограничениями. Если файла нет, хранилище будет пустым.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let path = path.into();
+        let storage = MemoryModerationStorage::new();
+
+        let content = files::load(&path)?;
+
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            let mut parts = line.splitn(3, ' ');
+            let (kind, until, target) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(kind), Some(until), Some(target)) => (kind, until, target),
+                _ => return Err(Error::InvalidInput),
+            };
+
+            let until = match until {
+                "-" => None,
+                seconds => Some(
+                    UNIX_EPOCH
+                        + Duration::from_secs(seconds.parse().map_err(|_| Error::InvalidInput)?),
+                ),
+            };
+
+            match kind {
+                "ban" => storage.ban(target.parse()?, until)?,
+                "mute" => storage.mute(target.to_string(), until)?,
+                _ => return Err(Error::InvalidInput),
+            }
+        }
+
+        Ok(Self {
+            path,
+            storage,
+            writing: Mutex::new(()),
+        })
+    }
+
+    //
+    // Меняет ограничение в памяти (`None` - снимает его) и переписывает файл целиком. Пока файл
+    // пишется, другие потоки ждут, поэтому последним на диск попадает самое свежее состояние.
+    // Истекшие ограничения в файл не попадают. Если записать файл не удалось, изменение в памяти
+    // откатывается, чтобы память и файл не расходились. Возвращает, было ли ограничение до изменения.
+    //
+
+    fn change<K: Eq + Hash + Clone>(
+        &self,
+        restrictions: &Mutex<HashMap<K, Option<SystemTime>>>,
+        key: K,
+        until: Option<Option<SystemTime>>,
+    ) -> Result<bool, Error> {
+        let _writing = self.writing.lock().unwrap();
+        let set = |until: Option<Option<SystemTime>>| {
+            let mut restrictions = restrictions.lock().unwrap();
+            match until {
+                Some(until) => restrictions.insert(key.clone(), until),
+                None => restrictions.remove(&key),
+            }
+        };
+
+        let previous = set(until);
+        if previous.is_none() && until.is_none() {
+            return Ok(false);
+        }
+
+        let mut content = String::new();
+        for (target, until) in self.storage.bans() {
+            content.push_str(&format!("ban {} {target}\n", format_until(until)));
+        }
+        for (user, until) in self.storage.mutes() {
+            content.push_str(&format!("mute {} {user}\n", format_until(until)));
+        }
+
+        if let Err(error) = files::save(&self.path, &content) {
+            set(previous);
+            return Err(error);
+        }
+        Ok(previous.is_some())
+    }
+}
+
+impl ModerationStorage for FileModerationStorage {
+    fn ban(&self, target: BanTarget, until: Option<SystemTime>) -> Result<(), Error> {
+        self.change(&self.storage.bans, target, Some(until))
+            .map(drop)
+    }
+
+    fn unban(&self, target: &BanTarget) -> Result<bool, Error> {
+        self.change(&self.storage.bans, target.clone(), None)
+    }
+
+    fn is_banned(&self, target: &BanTarget) -> Result<bool, Error> {
+        self.storage.is_banned(target)
+    }
+
+    fn mute(&self, user: String, until: Option<SystemTime>) -> Result<(), Error> {
+        self.change(&self.storage.mutes, user, Some(until))
+            .map(drop)
+    }
+
+    fn unmute(&self, user: &str) -> Result<bool, Error> {
+        self.change(&self.storage.mutes, user.to_string(), None)
+    }
+
+    fn is_muted(&self, user: &str) -> Result<bool, Error> {
+        self.storage.is_muted(user)
+    }
+}
+
+fn active(until: Option<SystemTime>) -> bool {
+    until.is_none_or(|until| SystemTime::now() < until)
+}
+
+fn format_until(until: Option<SystemTime>) -> String {
+    match until {
+        Some(until) => until
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .to_string(),
+        None => "-".to_string(),
+    }
+}
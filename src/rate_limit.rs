@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::commands::mentions;
+use crate::commands::Command;
+
+/// Скорость пополнения "ведра с токенами": не больше `burst` действий подряд,
+/// дальше - не чаще `per_second` действий в секунду.
+#[derive(Debug, Clone, Copy)]
+pub struct Rate {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+/// Ограничения частоты для каждого из бюджетов.
+#[derive(Debug, Clone)]
+pub struct Rates {
+    pub messages: Rate,
+    pub mentions: Rate,
+    pub commands: Rate,
+    /// Куски передаваемых файлов. Их много, но общий объем ограничен размером файла.
+    pub files: Rate,
+}
+
+impl Rates {
+    fn get(&self, budget: Budget) -> Rate {
+        match budget {
+            Budget::Messages => self.messages,
+            Budget::Mentions => self.mentions,
+            Budget::Commands => self.commands,
+            Budget::Files => self.files,
+        }
+    }
+}
+
+/// Ограничения частоты для каждого пользователя и каждого IP-адреса.
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    pub user: Rates,
+    /// За одним адресом (NAT, прокси) могут быть несколько пользователей,
+    /// поэтому адресу разрешено больше, чем одному пользователю.
+    pub ip: Rates,
+    /// Сколько раз соединение может упереться в ограничения, прежде чем сервер его закроет.
+    pub violations: Rate,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            user: Rates {
+                messages: Rate {
+                    burst: 10,
+                    per_second: 1.0,
+                },
+                mentions: Rate {
+                    burst: 5,
+                    per_second: 0.5,
+                },
+                commands: Rate {
+                    burst: 20,
+                    per_second: 2.0,
+                },
+                files: Rate {
+                    burst: 1000,
+                    per_second: 500.0,
+                },
+            },
+            ip: Rates {
+                messages: Rate {
+                    burst: 50,
+                    per_second: 5.0,
+                },
+                mentions: Rate {
+                    burst: 25,
+                    per_second: 2.5,
+                },
+                commands: Rate {
+                    burst: 100,
+                    per_second: 10.0,
+                },
+                files: Rate {
+                    burst: 5000,
+                    per_second: 2500.0,
+                },
+            },
+            violations: Rate {
+                burst: 10,
+                per_second: 0.1,
+            },
+        }
+    }
+}
+
+/// Отдельные бюджеты для обычных сообщений, сообщений с упоминаниями, специальных команд и кусков файлов.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Budget {
+    Messages,
+    Mentions,
+    Commands,
+    Files,
+}
+
+impl Budget {
+    /// Ответ с упоминаниями тратит тот же бюджет, что и сообщение с упоминаниями:
+    /// упомянутые в нем тоже получают `%mention`.
+    pub fn of(cmd: &Command) -> Self {
+        match cmd {
+            Command::Reply(cmd) if !mentions(&cmd.message).is_empty() => Self::Mentions,
+            Command::Message(_)
+            | Command::DirectMessage(_)
+            | Command::Edit(_)
+            | Command::Reply(_) => Self::Messages,
+            Command::MessageWithMentions(_) => Self::Mentions,
+            Command::FileChunk(_) => Self::Files,
+            _ => Self::Commands,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Rate, now: Instant) -> Self {
+        Self {
+            tokens: rate.burst as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.per_second).min(rate.burst as f64);
+        self.updated = now;
+    }
+
+    /// Через сколько появится следующий токен (ноль, если токен уже есть).
+    fn wait_time(&mut self, rate: Rate, now: Instant) -> Duration {
+        self.refill(rate, now);
+
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else if rate.per_second > 0.0 {
+            Duration::from_secs_f64((1.0 - self.tokens) / rate.per_second)
+        } else {
+            Duration::MAX
+        }
+    }
+
+    /// Забирает токен. Если токенов нет, возвращает время, через которое стоит повторить попытку.
+    pub fn take(&mut self, rate: Rate, now: Instant) -> Result<(), Duration> {
+        match self.wait_time(rate, now) {
+            Duration::ZERO => {
+                self.tokens -= 1.0;
+                Ok(())
+            }
+            wait => Err(wait),
+        }
+    }
+
+    fn is_full(&self, rate: Rate) -> bool {
+        self.tokens >= rate.burst as f64
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    User(String),
+    Ip(IpAddr),
+}
+
+//
+// Когда ведер становится слишком много, выбрасываем полные: их состояние
+// ничем не отличается от только что созданного ведра.
+//
+
+const MAX_BUCKETS: usize = 10_000;
+
+/// Ограничитель частоты, общий для всех соединений.
+pub struct RateLimiter {
+    limits: RateLimits,
+    buckets: Mutex<HashMap<(Key, Budget), TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    fn rate(&self, key: &Key, budget: Budget) -> Rate {
+        match key {
+            Key::User(_) => self.limits.user.get(budget),
+            Key::Ip(_) => self.limits.ip.get(budget),
+        }
+    }
+
+    /// Списывает действие с бюджета IP-адреса и (если пользователь залогинен) с бюджета пользователя.
+    /// Токен забирается, только если он есть в обоих ведрах.
+    pub fn check(&self, user: Option<&str>, ip: IpAddr, budget: Budget) -> Result<(), Duration> {
+        self.limit(user, ip, budget, true)
+    }
+
+    /// Проверяет, как `check`, но ничего не списывает: так можно отказать клиенту, бюджет которого
+    /// уже исчерпан, еще до того, как станет известно, какой бюджет на самом деле потратит действие.
+    pub fn peek(&self, user: Option<&str>, ip: IpAddr, budget: Budget) -> Result<(), Duration> {
+        self.limit(user, ip, budget, false)
+    }
+
+    fn limit(
+        &self,
+        user: Option<&str>,
+        ip: IpAddr,
+        budget: Budget,
+        take: bool,
+    ) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > MAX_BUCKETS {
+            buckets.retain(|(key, budget), bucket| !bucket.is_full(self.rate(key, *budget)));
+        }
+
+        let mut keys = vec![Key::Ip(ip)];
+        if let Some(user) = user {
+            keys.push(Key::User(user.to_string()));
+        }
+
+        let wait = keys
+            .iter()
+            .map(|key| {
+                let rate = self.rate(key, budget);
+                buckets
+                    .entry((key.clone(), budget))
+                    .or_insert_with(|| TokenBucket::new(rate, now))
+                    .wait_time(rate, now)
+            })
+            .max()
+            .unwrap_or_default();
+
+        if wait > Duration::ZERO {
+            return Err(wait);
+        }
+        if !take {
+            return Ok(());
+        }
+
+        for key in keys {
+            let rate = self.rate(&key, budget);
+            buckets
+                .get_mut(&(key, budget))
+                .unwrap()
+                .take(rate, now)
+                .ok();
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let rate = Rate {
+            burst: 2,
+            per_second: 0.5,
+        };
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(rate, start);
+
+        assert!(bucket.take(rate, start).is_ok());
+        assert!(bucket.take(rate, start).is_ok());
+        assert_eq!(bucket.take(rate, start), Err(Duration::from_secs(2)));
+        assert!(bucket.take(rate, start + Duration::from_secs(2)).is_ok());
+    }
+
+    #[test]
+    fn budgets() {
+        let budget = |line: &str| Budget::of(&Command::new(line).unwrap());
+
+        assert_eq!(budget("привет"), Budget::Messages);
+        assert_eq!(budget("привет, @roma"), Budget::Mentions);
+        assert_eq!(budget("%reply 1 согласен"), Budget::Messages);
+        assert_eq!(budget("%reply 1 согласен, @roma"), Budget::Mentions);
+        assert_eq!(budget("%show_users"), Budget::Commands);
+    }
+
+    #[test]
+    fn user_and_ip_budgets() {
+        let defaults = RateLimits::default();
+        let limiter = RateLimiter::new(RateLimits {
+            user: Rates {
+                messages: Rate {
+                    burst: 1,
+                    per_second: 0.0,
+                },
+                ..defaults.user
+            },
+            ip: Rates {
+                messages: Rate {
+                    burst: 2,
+                    per_second: 0.0,
+                },
+                ..defaults.ip
+            },
+            ..defaults
+        });
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let other_ip = IpAddr::from([127, 0, 0, 2]);
+
+        assert!(limiter.peek(Some("alex"), ip, Budget::Messages).is_ok());
+        assert!(limiter.check(Some("alex"), ip, Budget::Messages).is_ok());
+        assert!(limiter.peek(Some("alex"), ip, Budget::Messages).is_err());
+        assert!(limiter.check(Some("alex"), ip, Budget::Messages).is_err());
+        assert!(limiter.check(Some("alex"), ip, Budget::Commands).is_ok());
+
+        // тот же пользователь с другого адреса упирается в свой бюджет
+        assert!(limiter
+            .check(Some("alex"), other_ip, Budget::Messages)
+            .is_err());
+
+        // другой пользователь с того же адреса тратит свой бюджет, пока не кончится бюджет адреса
+        assert!(limiter.check(Some("roma"), ip, Budget::Messages).is_ok());
+        assert!(limiter.check(Some("vova"), ip, Budget::Messages).is_err());
+        assert!(limiter
+            .check(Some("vova"), other_ip, Budget::Messages)
+            .is_ok());
+    }
+}
@@ -1,99 +1,765 @@
-use simple_chat::commands::Command;
-use simple_chat::error::Error;
 use std::collections::HashMap;
-use std::io::BufRead;
 use std::io::BufReader;
+use std::io::Read;
 use std::io::Write;
-use std::net;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Shutdown;
+use std::net::SocketAddr;
+use std::net::TcpListener;
 use std::net::TcpStream;
+use std::panic::catch_unwind;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::sync::Condvar;
 use std::sync::Mutex;
-use std::thread::spawn;
+use std::thread;
+use std::thread::sleep;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use tracing::error;
+use tracing::field;
+use tracing::info;
+use tracing::info_span;
+use tracing::warn;
+use tracing::Span;
 use uuid::Uuid;
 
+use crate::audit::AuditEntry;
+use crate::audit::AuditLog;
+use crate::bots::Bot;
+use crate::bots::BotReply;
+use crate::bots::Sender;
+use crate::codec::Frame;
+use crate::codec::Framing;
+use crate::codec::FRAMED;
+use crate::codec::FRAMED_CAPABILITY;
+use crate::commands::format_time;
+use crate::commands::mentions;
+use crate::commands::parse_time;
+use crate::commands::BanTarget;
+use crate::commands::Command;
+use crate::commands::Hello;
+use crate::commands::Login;
+use crate::commands::UserKind;
+use crate::direct_messages::DirectMessageEntry;
+use crate::direct_messages::DirectMessageStorage;
+use crate::direct_messages::MemoryDirectMessageStorage;
+use crate::error::Error;
+use crate::files;
+use crate::history::HistoryEntry;
+use crate::history::HistoryStorage;
+use crate::history::MemoryHistoryStorage;
+use crate::hooks::Action;
+use crate::hooks::Hook;
+use crate::http;
+use crate::mentions::MentionInbox;
+use crate::metrics::Gauges;
+use crate::metrics::Metrics;
+use crate::moderation::MemoryModerationStorage;
+use crate::moderation::ModerationStorage;
+use crate::rate_limit::Budget;
+use crate::rate_limit::Rate;
+use crate::rate_limit::RateLimiter;
+use crate::rate_limit::RateLimits;
+use crate::rate_limit::TokenBucket;
+use crate::storage::MemoryUserStorage;
+use crate::storage::UserStorage;
+use crate::transfers::Transfer;
+use crate::transfers::Transfers;
+use crate::webhooks::Webhook;
+use crate::webhooks::Webhooks;
+use crate::webhooks::ROOM;
+use crate::websocket;
+
+pub const DEFAULT_ADDRESS: &str = "localhost:8889";
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+//
+// Пауза после ошибки accept() (например, EMFILE, когда закончились файловые дескрипторы).
+// С каждой следующей ошибкой подряд пауза удваивается, но не превышает максимума.
+//
+
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+//
+// Сколько сообщений общего чата не больше отправляется в ответ на одну команду %history.
+//
+
+const MAX_HISTORY_REPLY: usize = 100;
+
+/// Возможности сервера, о которых он сообщает в ответ на `%hello`: кадры вместо строк
+/// и группы команд сверх входа и обычных сообщений.
+const CAPABILITIES: &[&str] = &[
+    FRAMED_CAPABILITY,
+    "multi_line",
+    "history",
+    "mentions",
+    "replies",
+    "reactions",
+    "direct_messages",
+    "files",
+];
+
 type AcceptedConnections = Arc<Mutex<HashMap<Uuid, AcceptedConnection>>>;
 
 struct AcceptedConnection {
-    connection: TcpStream,
+    connection: MeteredStream,
+    address: SocketAddr,
     user_id: Option<String>,
 }
 
-fn main() {
-    //
-    // Слушаем порт 8889 на IP-адресе localhost (локальный адрес).
-    //
+/// Ограничения, которые сервер накладывает на клиентов.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Максимальная длина одной команды в байтах (вместе с переводом строки).
+    pub max_line_length: usize,
+    /// Максимальная длина многострочного сообщения (блока `%begin`/`%end`) в байтах.
+    pub max_block_length: usize,
+    /// Частота сообщений и команд для каждого пользователя и IP-адреса.
+    pub rate: RateLimits,
+    /// Максимальное количество одновременных соединений.
+    pub max_connections: usize,
+    /// Максимальное количество одновременных соединений с одного IP-адреса.
+    pub max_connections_per_ip: usize,
+    /// Сколько времени после подключения у клиента есть на то, чтобы выполнить `%login`.
+    pub login_timeout: Duration,
+    /// Максимальный размер файла, который можно передать через сервер, в байтах.
+    pub max_file_size: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_line_length: 4096,
+            max_block_length: 16 * 1024,
+            rate: RateLimits::default(),
+            max_connections: 1024,
+            max_connections_per_ip: 16,
+            login_timeout: Duration::from_secs(30),
+            max_file_size: 1024 * 1024,
+        }
+    }
+}
 
-    let server = net::TcpListener::bind("localhost:8889").unwrap();
+/// Состояние сервера, общее для всех потоков, обрабатывающих соединения.
+struct Shared {
+    connections: AcceptedConnections,
+    limits: Limits,
+    rate_limiter: RateLimiter,
+    users: Box<dyn UserStorage>,
+    moderation: Box<dyn ModerationStorage>,
+    direct_messages: Box<dyn DirectMessageStorage>,
+    history: Box<dyn HistoryStorage>,
+    mentions: MentionInbox,
+    transfers: Transfers,
+    audit: Option<Box<dyn AuditLog>>,
+    hooks: Vec<Box<dyn Hook>>,
+    bots: Vec<Box<dyn Bot>>,
+    /// Токены HTTP API и пользователи, от имени которых пишут их владельцы.
+    api_tokens: HashMap<String, String>,
+    /// Токен, которым администраторы подтверждают вход. Без него войти администратором нельзя.
+    admin_token: Option<String>,
+    webhooks: Webhooks,
+    metrics: Arc<Metrics>,
+    shutdown: AtomicBool,
 
     //
-    // Создаем контейнер для размещения всех принятых соединений.
+    // Количество работающих потоков, обрабатывающих соединения.
+    // При остановке сервера мы ждем, пока оно не станет равным нулю.
     //
+    active_threads: Mutex<usize>,
+    threads_finished: Condvar,
+}
 
-    let accepted_connections = Arc::new(Mutex::new(HashMap::new()));
+pub struct ChatServerBuilder {
+    address: String,
+    metrics_address: Option<String>,
+    websocket_address: Option<String>,
+    api_address: Option<String>,
+    api_tokens: HashMap<String, String>,
+    admin_token: Option<String>,
+    webhooks: Vec<Webhook>,
+    shutdown_timeout: Duration,
+    limits: Limits,
+    users: Box<dyn UserStorage>,
+    moderation: Box<dyn ModerationStorage>,
+    direct_messages: Box<dyn DirectMessageStorage>,
+    history: Box<dyn HistoryStorage>,
+    audit: Option<Box<dyn AuditLog>>,
+    hooks: Vec<Box<dyn Hook>>,
+    bots: Vec<Box<dyn Bot>>,
+}
 
-    //
-    // Бесконечно принимаем новые соединения от клиентов.
-    //
+impl ChatServerBuilder {
+    /// Адрес, на котором сервер будет принимать соединения. Порт 0 означает любой свободный порт,
+    /// узнать его можно через `ChatServer::local_addr`.
+    pub fn address(mut self, address: impl Into<String>) -> Self {
+        self.address = address.into();
+        self
+    }
 
-    loop {
+    /// Адрес, на котором по HTTP отдаются метрики в формате Prometheus (`GET /metrics`).
+    /// По умолчанию метрики доступны только администраторам через команду `%stats`.
+    pub fn metrics_address(mut self, address: impl Into<String>) -> Self {
+        self.metrics_address = Some(address.into());
+        self
+    }
+
+    /// Адрес, на котором сервер принимает соединения по WebSocket, например от браузеров.
+    /// Клиенты WebSocket - такие же участники чата, как и клиенты, подключившиеся по TCP.
+    pub fn websocket_address(mut self, address: impl Into<String>) -> Self {
+        self.websocket_address = Some(address.into());
+        self
+    }
+
+    /// Адрес HTTP API, через которое скрипты могут писать в чат и смотреть пользователей и историю,
+    /// не держа соединение: `POST /rooms/general/messages`, `GET /users`, `GET /history`.
+    /// Запросы принимаются только с токеном из `api_token`.
+    pub fn api_address(mut self, address: impl Into<String>) -> Self {
+        self.api_address = Some(address.into());
+        self
+    }
+
+    /// Токен HTTP API (`Authorization: Bearer <token>`). Сообщения с этим токеном
+    /// приходят в чат от пользователя `user`.
+    pub fn api_token(mut self, token: impl Into<String>, user: impl Into<String>) -> Self {
+        self.api_tokens.insert(token.into(), user.into());
+        self
+    }
+
+    /// Токен администратора. Пользователь, зарегистрированный как admin, входит командой
+    /// `%login <id> <token>`; без токена войти под его id нельзя.
+    pub fn admin_token(mut self, token: impl Into<String>) -> Self {
+        self.admin_token = Some(token.into());
+        self
+    }
+
+    /// Сколько времени при остановке ждать завершения потоков, обрабатывающих соединения.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn user_storage(mut self, users: impl UserStorage + 'static) -> Self {
+        self.users = Box::new(users);
+        self
+    }
+
+    pub fn moderation_storage(mut self, moderation: impl ModerationStorage + 'static) -> Self {
+        self.moderation = Box::new(moderation);
+        self
+    }
+
+    pub fn direct_message_storage(
+        mut self,
+        direct_messages: impl DirectMessageStorage + 'static,
+    ) -> Self {
+        self.direct_messages = Box::new(direct_messages);
+        self
+    }
+
+    pub fn history_storage(mut self, history: impl HistoryStorage + 'static) -> Self {
+        self.history = Box::new(history);
+        self
+    }
+
+    /// Журнал, куда записываются действия администраторов. По умолчанию они никуда не записываются.
+    pub fn audit_log(mut self, audit: impl AuditLog + 'static) -> Self {
+        self.audit = Some(Box::new(audit));
+        self
+    }
+
+    pub fn hook(mut self, hook: impl Hook + 'static) -> Self {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Бот, который получает все выполненные команды и отвечает на них от своего имени.
+    /// Боты получают команды в том порядке, в котором их добавили.
+    pub fn bot(mut self, bot: impl Bot + 'static) -> Self {
+        self.bots.push(Box::new(bot));
+        self
+    }
+
+    /// Адрес, на который сервер будет отправлять события чата. Вебхуков может быть несколько.
+    pub fn webhook(mut self, webhook: Webhook) -> Self {
+        self.webhooks.push(webhook);
+        self
+    }
+
+    pub fn build(self) -> Result<ChatServer, Error> {
         //
-        // Блокируем программу на вызове server.accept() до тех пор, пока какой-то из клиентов
-        // не попытается установить с нами TCP-соединение.
+        // Пользователи API и боты пишут в общий чат под своими именами, а в файлах хранилищ
+        // поля записей разделяются пробелами. Имена с пробелами испортили бы эти файлы.
         //
 
-        let connection = match server.accept() {
-            Err(_) => continue,
-            Ok((conn, _)) => conn,
+        let names = (self.api_tokens.values().map(String::as_str))
+            .chain(self.bots.iter().map(|bot| bot.name()));
+        for name in names {
+            files::check_user(name)?;
+        }
+
+        let listener = TcpListener::bind(&self.address).map_err(Error::IO)?;
+        let local_addr = listener.local_addr().map_err(Error::IO)?;
+
+        let metrics_listener = match &self.metrics_address {
+            Some(address) => Some(TcpListener::bind(address).map_err(Error::IO)?),
+            None => None,
+        };
+        let metrics_addr = match &metrics_listener {
+            Some(listener) => Some(listener.local_addr().map_err(Error::IO)?),
+            None => None,
+        };
+
+        let websocket_listener = match &self.websocket_address {
+            Some(address) => Some(TcpListener::bind(address).map_err(Error::IO)?),
+            None => None,
+        };
+        let websocket_addr = match &websocket_listener {
+            Some(listener) => Some(listener.local_addr().map_err(Error::IO)?),
+            None => None,
+        };
+
+        let api_listener = match &self.api_address {
+            Some(address) => Some(TcpListener::bind(address).map_err(Error::IO)?),
+            None => None,
         };
+        let api_addr = match &api_listener {
+            Some(listener) => Some(listener.local_addr().map_err(Error::IO)?),
+            None => None,
+        };
+
+        let shared = Arc::new(Shared {
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            rate_limiter: RateLimiter::new(self.limits.rate.clone()),
+            limits: self.limits,
+            users: self.users,
+            moderation: self.moderation,
+            direct_messages: self.direct_messages,
+            history: self.history,
+            mentions: MentionInbox::new(),
+            transfers: Transfers::new(),
+            audit: self.audit,
+            hooks: self.hooks,
+            bots: self.bots,
+            api_tokens: self.api_tokens,
+            admin_token: self.admin_token,
+            webhooks: Webhooks::start(self.webhooks)?,
+            metrics: Arc::new(Metrics::new()),
+            shutdown: AtomicBool::new(false),
+            active_threads: Mutex::new(0),
+            threads_finished: Condvar::new(),
+        });
 
         //
-        // Генерируем уникальный идентификатор подключения.
+        // Метрики отдаются в отдельном потоке, независимо от того, запущен ли уже run.
         //
 
-        let connection_id = Uuid::new_v4();
+        if let Some(metrics_listener) = metrics_listener {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .spawn(move || serve_metrics(metrics_listener, &shared))
+                .map_err(Error::IO)?;
+        }
 
         //
-        // Клонируем принятое TCP-соединение.
+        // Соединения WebSocket принимаются в своем потоке, а обслуживаются так же, как соединения TCP.
         //
 
-        let connection_clone = match connection.try_clone() {
-            Err(_) => continue,
-            Ok(value) => value,
-        };
+        if let Some(websocket_listener) = websocket_listener {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .spawn(move || serve_websocket(websocket_listener, &shared))
+                .map_err(Error::IO)?;
+        }
+
+        if let Some(api_listener) = api_listener {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .spawn(move || serve_api(api_listener, &shared))
+                .map_err(Error::IO)?;
+        }
+
+        Ok(ChatServer {
+            listener,
+            local_addr,
+            metrics_addr,
+            websocket_addr,
+            api_addr,
+            shutdown_timeout: self.shutdown_timeout,
+            shared,
+        })
+    }
+}
+
+pub struct ChatServer {
+    listener: TcpListener,
+    local_addr: SocketAddr,
+    metrics_addr: Option<SocketAddr>,
+    websocket_addr: Option<SocketAddr>,
+    api_addr: Option<SocketAddr>,
+    shutdown_timeout: Duration,
+    shared: Arc<Shared>,
+}
+
+impl ChatServer {
+    pub fn builder() -> ChatServerBuilder {
+        ChatServerBuilder {
+            address: DEFAULT_ADDRESS.to_string(),
+            metrics_address: None,
+            websocket_address: None,
+            api_address: None,
+            api_tokens: HashMap::new(),
+            admin_token: None,
+            webhooks: Vec::new(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            limits: Limits::default(),
+            users: Box::new(MemoryUserStorage::new()),
+            moderation: Box::new(MemoryModerationStorage::new()),
+            direct_messages: Box::new(MemoryDirectMessageStorage::new()),
+            history: Box::new(MemoryHistoryStorage::new()),
+            audit: None,
+            hooks: Vec::new(),
+            bots: Vec::new(),
+        }
+    }
+
+    /// Адрес, на котором сервер на самом деле принимает соединения.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Адрес HTTP-эндпоинта с метриками, если он включен.
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+
+    /// Адрес, на котором принимаются соединения по WebSocket, если они включены.
+    pub fn websocket_addr(&self) -> Option<SocketAddr> {
+        self.websocket_addr
+    }
+
+    /// Адрес HTTP API, если оно включено.
+    pub fn api_addr(&self) -> Option<SocketAddr> {
+        self.api_addr
+    }
+
+    /// Принимает соединения до тех пор, пока не будет вызван `shutdown`, после чего
+    /// оповещает клиентов, закрывает соединения и сохраняет состояние.
+    /// Возвращает `Error::ShutdownTimeout`, если потоки соединений не завершились вовремя.
+    pub fn run(&self) -> Result<(), Error> {
+        let mut backoff = Duration::ZERO;
+
+        loop {
+            //
+            // Блокируем поток на вызове accept() до тех пор, пока какой-то из клиентов
+            // не попытается установить с нами TCP-соединение.
+            //
+
+            let accepted = self.listener.accept();
+
+            if self.shared.shutdown.load(Ordering::SeqCst) {
+                return self.finish();
+            }
+
+            //
+            // Ошибки accept() чаще всего временные (закончились файловые дескрипторы или память),
+            // поэтому не крутимся в цикле впустую, а ждем, пока ресурсы освободятся.
+            //
+
+            let (connection, address) = match accepted {
+                Err(error) => {
+                    backoff = (backoff * 2).clamp(MIN_ACCEPT_BACKOFF, MAX_ACCEPT_BACKOFF);
+                    warn!(%error, ?backoff, "accept failed");
+                    sleep(backoff);
+                    continue;
+                }
+                Ok(value) => {
+                    backoff = Duration::ZERO;
+                    value
+                }
+            };
+
+            accept_connection(&self.shared, connection, address, Framing::Lines);
+        }
+    }
+
+    /// Просит сервер остановиться. Сама остановка выполняется в `run`,
+    /// поэтому метод можно вызывать из другого потока (например, из обработчика сигнала).
+    pub fn shutdown(&self) {
+        if self.shared.shutdown.swap(true, Ordering::SeqCst) {
+            return;
+        }
 
         //
-        // Добавляем принятое подключение в общий список принятых подключений.
+        // Поток, выполняющий run, и потоки, отдающие метрики, принимающие соединения WebSocket
+        // и запросы к API, заблокированы на accept(), поэтому будим их, подключаясь к самим себе.
         //
 
-        accepted_connections.lock().unwrap().insert(
-            connection_id,
-            AcceptedConnection {
-                connection: connection_clone,
-                user_id: None,
-            },
-        );
+        for mut wake_address in [
+            Some(self.local_addr),
+            self.metrics_addr,
+            self.websocket_addr,
+            self.api_addr,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if wake_address.ip().is_unspecified() {
+                wake_address.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+            }
+            TcpStream::connect(wake_address).ok();
+        }
+    }
+
+    fn finish(&self) -> Result<(), Error> {
+        info!("shutting down");
 
         //
-        // Создаем новый поток, где будет обрабатываться принятое соединение.
-        // Поскольку перед closure, которую передаем в spawn, стоит ключевое слово move,
-        // closure принимает владение всеми переменными, которые используются в ее теле.
-        // Именно поэтому требуется склонировать указатель на accepted_connections.
+        // Предупреждаем всех клиентов об остановке и закрываем их соединения.
+        // Потоки, читающие из этих соединений, получат конец потока и завершатся.
         //
 
-        {
-            let connections = accepted_connections.clone();
-            spawn(move || {
-                handle_connection(connection_id, connection, &connections).ok();
-                connections.lock().unwrap().remove(&connection_id);
+        for conn in self.shared.connections.lock().unwrap().values_mut() {
+            conn.connection
+                .send(&Frame::text("%notice server shutting down"));
+            conn.connection.shutdown(Shutdown::Both).ok();
+        }
+
+        //
+        // Ждем завершения потоков, но не дольше shutdown_timeout.
+        //
+
+        let timed_out = self
+            .shared
+            .threads_finished
+            .wait_timeout_while(
+                self.shared.active_threads.lock().unwrap(),
+                self.shutdown_timeout,
+                |active| *active > 0,
+            )
+            .unwrap()
+            .1
+            .timed_out();
+
+        //
+        // Сохраняем состояние только после того, как потоки перестали его менять.
+        //
+
+        self.shared.users.flush()?;
+        self.shared.moderation.flush()?;
+        self.shared.direct_messages.flush()?;
+        self.shared.history.flush()?;
+
+        if timed_out {
+            warn!("connection threads did not finish in time");
+            return Err(Error::ShutdownTimeout);
+        }
+
+        Ok(())
+    }
+}
+
+//
+// Принимает соединение, пришедшее на любой из адресов сервера: проверяет баны и ограничения,
+// добавляет соединение в общий список и запускает поток, который его обслуживает.
+//
+
+fn accept_connection(
+    shared: &Arc<Shared>,
+    mut connection: TcpStream,
+    address: SocketAddr,
+    framing: Framing,
+) {
+    //
+    // С забаненных IP-адресов соединения не принимаем. Ограничиваем общее количество соединений
+    // и количество соединений с одного IP-адреса, чтобы один клиент не мог занять все потоки сервера.
+    // Клиенту WebSocket до рукопожатия ответить строкой нельзя, его соединение просто закрываем.
+    //
+
+    let rejected = match shared.moderation.is_banned(&BanTarget::Ip(address.ip())) {
+        Ok(true) => Err(Error::Banned),
+        _ => check_connection_limits(shared, address),
+    };
+    if let Err(error) = rejected {
+        warn!(%address, reason = %error, "connection rejected");
+        if framing == Framing::Lines {
+            connection.write_all(format!("{error}\n").as_bytes()).ok();
+        }
+        return;
+    }
+
+    //
+    // Генерируем уникальный идентификатор подключения.
+    //
+
+    let connection_id = Uuid::new_v4();
+
+    //
+    // Клонируем принятое TCP-соединение. Копии пишут в сеть под общей блокировкой, поэтому
+    // ответы клиенту, служебные кадры и рассылки других потоков не перемешиваются.
+    //
+
+    let connection = MeteredStream::new(connection, &shared.metrics, framing);
+    let connection_clone = match connection.try_clone() {
+        Err(error) => {
+            error!(%address, %error, "cannot clone connection");
+            return;
+        }
+        Ok(value) => value,
+    };
+
+    //
+    // Добавляем принятое подключение в общий список принятых подключений.
+    //
+
+    shared.connections.lock().unwrap().insert(
+        connection_id,
+        AcceptedConnection {
+            connection: connection_clone,
+            address,
+            user_id: None,
+        },
+    );
+
+    //
+    // Все события соединения логируются внутри span, где есть идентификатор подключения,
+    // а после логина - и идентификатор пользователя.
+    //
+
+    let span = info_span!(
+        "connection",
+        connection_id = %connection_id,
+        user_id = field::Empty
+    );
+    span.in_scope(|| info!(%address, "connected"));
+
+    run_hooks(shared, "on_connect", |hook| {
+        hook.on_connect(connection_id, address)
+    });
+
+    //
+    // Создаем новый поток, где будет обрабатываться принятое соединение.
+    // Поскольку перед closure, которую передаем в spawn, стоит ключевое слово move,
+    // closure принимает владение всеми переменными, которые используются в ее теле.
+    // Именно поэтому требуется склонировать указатель на общее состояние сервера.
+    //
+
+    *shared.active_threads.lock().unwrap() += 1;
+
+    let thread_span = span.clone();
+    let spawned = {
+        let shared = shared.clone();
+        thread::Builder::new().spawn(move || {
+            let _entered = thread_span.enter();
+
+            match handle_connection(connection_id, connection, address, framing, &shared) {
+                Ok(()) => info!("disconnected"),
+                Err(error) => info!(reason = %error, "disconnected"),
+            }
+
+            {
+                let mut connections = shared.connections.lock().unwrap();
+                if let Some(AcceptedConnection {
+                    user_id: Some(user_id),
+                    ..
+                }) = connections.remove(&connection_id)
+                {
+                    announce_leave(&mut connections, &shared.webhooks, &user_id);
+                }
+
+                for transfer in shared.transfers.disconnected(connection_id) {
+                    notify_transfer(
+                        &mut connections,
+                        &transfer,
+                        &format!("%file_failed {} disconnected", transfer.id),
+                        Some(connection_id),
+                    );
+                }
+            }
+
+            run_hooks(&shared, "on_disconnect", |hook| {
+                hook.on_disconnect(connection_id)
             });
+
+            *shared.active_threads.lock().unwrap() -= 1;
+            shared.threads_finished.notify_all();
+        })
+    };
+
+    //
+    // Если поток создать не удалось, соединение закрываем и откатываем все, что сделали выше.
+    //
+
+    if let Err(error) = spawned {
+        span.in_scope(|| error!(%error, "cannot spawn connection thread"));
+
+        if let Some(accepted) = shared.connections.lock().unwrap().remove(&connection_id) {
+            accepted.connection.shutdown(Shutdown::Both).ok();
         }
+
+        run_hooks(shared, "on_disconnect", |hook| {
+            hook.on_disconnect(connection_id)
+        });
+
+        *shared.active_threads.lock().unwrap() -= 1;
+        shared.threads_finished.notify_all();
+    }
+}
+
+fn check_connection_limits(shared: &Shared, address: SocketAddr) -> Result<(), Error> {
+    let limits = &shared.limits;
+    let connections = shared.connections.lock().unwrap();
+
+    let from_same_ip = connections
+        .values()
+        .filter(|accepted| accepted.address.ip() == address.ip())
+        .count();
+
+    if connections.len() >= limits.max_connections || from_same_ip >= limits.max_connections_per_ip
+    {
+        return Err(Error::TooManyConnections);
     }
+
+    Ok(())
+}
+
+//
+// Ошибка хранилища не должна разрывать соединение: клиент получает ее в ответ "%error <текст>",
+// команда считается невыполненной, и сервер переходит к следующей команде.
+//
+
+macro_rules! stored {
+    ($reader:expr, $result:expr) => {
+        match $result {
+            Ok(value) => value,
+            Err(error) => {
+                error!(%error, "storage error");
+                reply($reader, &format!("%error {error}"));
+                continue;
+            }
+        }
+    };
 }
 
 fn handle_connection(
     connection_id: Uuid,
-    connection: TcpStream,
-    all_connections: &AcceptedConnections,
+    connection: MeteredStream,
+    address: SocketAddr,
+    framing: Framing,
+    shared: &Shared,
 ) -> Result<(), Error> {
     //
     // Создаем экземпляр буфера для чтения данных из клиентского соединения.
@@ -103,13 +769,6 @@ fn handle_connection(
 
     let mut reader = BufReader::new(connection);
 
-    //
-    // Строка, куда будет временно записыватся каждая команда, поступающая от клиента.
-    // Эта строка нужна для метода read_line.
-    //
-
-    let mut message = String::new();
-
     //
     // Идентификатор (пока что имя) пользователя.
     // При выполнении команды Login имя пользователя записывается сюда.
@@ -118,120 +777,1703 @@ fn handle_connection(
 
     let mut user_id = None;
 
+    //
+    // Подтвердил ли клиент при входе токен администратора.
+    //
+
+    let mut authenticated = false;
+
+    //
+    // Сколько раз клиент еще может упереться в ограничение частоты, прежде чем мы его отключим.
+    //
+
+    let violations_rate = shared.rate_limiter.limits().violations;
+    let mut violations = TokenBucket::new(violations_rate, Instant::now());
+
+    //
+    // Клиент, который так и не залогинился, не должен занимать поток вечно.
+    //
+
+    let login_deadline = Instant::now() + shared.limits.login_timeout;
+
+    //
+    // Соединение, принятое на адресе для WebSocket, начинается с рукопожатия по HTTP.
+    // Оно тоже должно уложиться во время, отведенное на логин.
+    //
+
+    if framing == Framing::WebSocket {
+        reader
+            .get_ref()
+            .set_read_timeout(Some(shared.limits.login_timeout))
+            .map_err(Error::IO)?;
+        websocket::accept(&mut reader)?;
+    }
+
+    //
+    // Перейти на кадры и поздороваться (%hello) можно только первой строкой.
+    //
+
+    let mut first_frame = true;
+
     //
     // В бесконечном цикле читаем команды, поступающие от клиента...
     //
 
     loop {
         //
-        // Очищаю строку message, т.к. метод read_line не делает этого автоматически.
+        // Поток блокируется на чтении до тех пор, пока от клиента не придет целая команда:
+        // строка, оканчивающаяся служебным символом \n, блок %begin/%end или кадр, если клиент
+        // перешел на кадры. Если соединение разорвано, мы завершаем ф-цию handle_connection.
+        // Строки и блоки длиннее ограничений не читаем, чтобы клиент не мог занять всю память.
+        // Пока клиент не залогинился, чтение ограничено временем, оставшимся до login_deadline.
         //
 
-        message.clear();
+        let read_timeout = match user_id {
+            None => match login_deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => Some(remaining),
+                _ => {
+                    reply(&mut reader, &Error::LoginTimeout);
+                    return Err(Error::LoginTimeout);
+                }
+            },
+            Some(_) => None,
+        };
+        reader
+            .get_ref()
+            .set_read_timeout(read_timeout)
+            .map_err(Error::IO)?;
+
+        let framing = reader.get_ref().framing;
+        let frame = match framing.read(
+            &mut reader,
+            shared.limits.max_line_length,
+            shared.limits.max_block_length,
+        ) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Ok(()),
+            Err(Error::IO(e))
+                if user_id.is_none()
+                    && matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+            {
+                reply(&mut reader, &Error::LoginTimeout);
+                return Err(Error::LoginTimeout);
+            }
+            Err(Error::IO(e)) => return Err(Error::IO(e)),
+            Err(error) => {
+                reply(&mut reader, &error);
+                reader.get_mut().write_all(&framing.close(&error)).ok();
+                return Err(error);
+            }
+        };
 
         //
-        // Поток блокируется на вызове .read_line до тех пор, пока reader
-        // не прочтет полное сообщение от клиента, которое оканчивается служебным символом \n.
-        // Если read_line возвращает Ok, а внутри - 0 прочитанных байт, то это значит, что соединение разорвано.
-        // В случае разрыва соединения мы завершаем ф-цию handle_connection.
-        // Полученная от клиента команда запишется в message по мутабельной ссылке.
+        // Первой строкой клиент может попросить перейти на кадры. Подтверждаем еще построчно,
+        // а дальше и читаем, и отправляем этому соединению кадры. Клиентам WebSocket
+        // это не нужно: они и так обмениваются с сервером сообщениями.
         //
 
-        if reader.read_line(&mut message).map_err(|e| Error::IO(e))? == 0 {
-            return Ok(());
+        let is_first_frame = std::mem::take(&mut first_frame);
+        if is_first_frame && framing == Framing::Lines && frame == Frame::Line(FRAMED.to_string()) {
+            reply(&mut reader, &FRAMED);
+            use_frames(shared, &mut reader, connection_id);
+            continue;
         }
 
         //
-        // Передаем ссылку на message в конструктор Command, где происходит
-        // парсинг команды. Если парсинг не удался (неверная команда), то
-        // мы получаем мутабельную ссылку на пользовательское соединение из reader
-        // и отправляем ошибку клиенту.
-        // .ok() после write_all игнорирует возможную ошибку отправки данных в сеть.
+        // Разбираем команду. Если парсинг не удался (неверная команда), то
+        // отправляем ошибку клиенту.
+        //
+
+        let parsed = frame.to_command();
+
+        //
+        // Проверяем частоту: у обычных сообщений, сообщений с упоминаниями и команд свои бюджеты.
+        // Неразобранные строки тоже тратят бюджет команд, иначе им можно было бы флудить.
+        // Бюджет разобранной команды пока только проверяем: списывается бюджет команды,
+        // которую вернут обработчики, но тому, чей бюджет уже исчерпан, обработчики не вызываются.
         //
 
-        let cmd = match Command::new(&message) {
+        let limited = match &parsed {
+            Ok(cmd) => shared
+                .rate_limiter
+                .peek(user_id.as_deref(), address.ip(), Budget::of(cmd)),
+            Err(_) => shared
+                .rate_limiter
+                .check(user_id.as_deref(), address.ip(), Budget::Commands),
+        };
+        if let Err(retry_after) = limited {
+            throttle(&mut reader, &mut violations, violations_rate, retry_after)?;
+            continue;
+        }
+
+        let cmd = match parsed {
             Ok(value) => value,
             Err(error) => {
-                reader
-                    .get_mut()
-                    .write_all(format!("{error}\n").as_bytes())
-                    .ok();
+                info!(line = %frame, %error, "invalid command");
+                shared.metrics.parse_error(&error);
+                reply(&mut reader, &error);
                 continue;
             }
         };
 
         //
-        // Определяем что за команда пришла от клиента и выполняем ее.
+        // Обработчики могут заменить команду другой или отклонить ее. Проверки прав и все остальное
+        // относятся уже к команде, которую вернули обработчики.
         //
 
-        match cmd {
-            //
-            // Клиент хочет залогиниться, присылает свое имя (в будущем - ID).
-            // Мы записываем имя пользователя в переменную user_id.
-            // Также мы находим текущее подключение (connection) в мапе всех подключений по его ID,
-            // а затем также записываем имя пользователя в экземпляр структуры AcceptedConnection (это значение мапы, а ключ - connection_id);
-            //
-            Command::Login(cmd) => {
-                user_id = Some(cmd.id.clone());
-                all_connections
-                    .lock()
-                    .unwrap()
-                    .get_mut(&connection_id)
-                    .unwrap()
-                    .user_id = Some(cmd.id);
+        let sender = Sender {
+            user: user_id.as_deref(),
+            address,
+        };
+        let cmd = match before_command(shared, &sender, cmd) {
+            Ok(cmd) => cmd,
+            Err(error) => {
+                info!(line = %frame, %error, "command rejected by hook");
+                reply(&mut reader, &error);
+                continue;
             }
-            //
-            // Клиент прислал сообщение, которое нужно разослать всем остальным клиентам.
-            //
-            Command::Message(cmd) => {
-                //
-                // Если пользователь приславший сообщение не залогинен - ничего не делаем.
-                //
+        };
 
-                if user_id.is_none() {
-                    continue;
-                }
+        if let Err(retry_after) =
+            shared
+                .rate_limiter
+                .check(user_id.as_deref(), address.ip(), Budget::of(&cmd))
+        {
+            throttle(&mut reader, &mut violations, violations_rate, retry_after)?;
+            continue;
+        }
 
-                //
-                // Мы пробегаемся по всем существующим на данный момент клиентским соединениям.
-                // В каждое соединение, кроме текущего (которое мы сейчас обрабатываем в ф-ции handle_connection),
-                // мы должны отправить сообщение, только что полученное от пользователя.
-                //
+        //
+        // Административные команды (управление пользователями и модерация)
+        // могут выполнять только администраторы.
+        //
 
-                for conn in all_connections.lock().unwrap().values_mut() {
-                    //
-                    // Если пользователя, на чье соединение мы сейчас смотрим (conn) не залогинен - ничего не делаем.
-                    //
+        if cmd.is_admin_only()
+            && stored!(&mut reader, user_kind(shared, &user_id, authenticated)) != UserKind::Admin
+        {
+            warn!(command = %cmd, "permission denied");
+            reply(&mut reader, &Error::PermissionDenied);
+            continue;
+        }
 
-                    if conn.user_id.is_none() {
+        //
+        // Выполненные административные команды записываем в журнал аудита.
+        //
+
+        let audited =
+            (cmd.is_admin_only() && !matches!(cmd, Command::Stats(_))).then(|| cmd.clone());
+
+        //
+        // Выполненную команду передаем ботам, если они есть.
+        //
+
+        let for_bots = (!shared.bots.is_empty()).then(|| cmd.clone());
+
+        //
+        // Определяем что за команда пришла от клиента и выполняем ее.
+        //
+
+        match cmd {
+            //
+            // Клиент здоровается и называет версию протокола. Отвечаем своей версией, возможностями
+            // и ограничениями. С клиентом, который говорит на другой версии, дальше не разговариваем:
+            // сообщаем об ошибке и закрываем соединение. Иначе, если клиент умеет, переходим на кадры.
+            //
+            Command::Hello(_) if !is_first_frame => {
+                reply(&mut reader, &Error::InvalidInput);
+            }
+            Command::Hello(cmd) => {
+                reply(&mut reader, &hello(&shared.limits));
+                if cmd.version != Hello::VERSION {
+                    warn!(version = cmd.version, "unsupported protocol version");
+                    reply(&mut reader, &Error::UnsupportedVersion);
+                    return Err(Error::UnsupportedVersion);
+                }
+
+                if cmd.has(FRAMED_CAPABILITY) && framing == Framing::Lines {
+                    use_frames(shared, &mut reader, connection_id);
+                }
+            }
+            //
+            // Клиент хочет залогиниться, присылает свое имя (в будущем - ID).
+            // Мы записываем имя пользователя в переменную user_id.
+            // Также мы находим текущее подключение (connection) в мапе всех подключений по его ID,
+            // а затем также записываем имя пользователя в экземпляр структуры AcceptedConnection (это значение мапы, а ключ - connection_id);
+            //
+            Command::Login(cmd) => {
+                //
+                // Забаненный пользователь войти не может, соединение сразу закрывается.
+                //
+
+                if stored!(
+                    &mut reader,
+                    shared
+                        .moderation
+                        .is_banned(&BanTarget::User(cmd.id.clone()))
+                ) {
+                    warn!(user_id = %cmd.id, "banned user tried to log in");
+                    reply(&mut reader, &Error::Banned);
+                    return Ok(());
+                }
+
+                //
+                // Имена ботов заняты: под ними нельзя писать от лица бота.
+                //
+
+                if shared.bots.iter().any(|bot| bot.name() == cmd.id) {
+                    reply(&mut reader, &Error::PermissionDenied);
+                    continue;
+                }
+
+                //
+                // Под id администратора можно войти только с токеном администратора: id может знать
+                // кто угодно. Неверный токен - ошибка и для обычного пользователя.
+                //
+
+                let login_id = Some(cmd.id.clone());
+                let kind = stored!(&mut reader, user_kind(shared, &login_id, true));
+                let token_valid = cmd.token.is_some() && cmd.token == shared.admin_token;
+                if (kind == UserKind::Admin || cmd.token.is_some()) && !token_valid {
+                    warn!(user_id = %cmd.id, "invalid credentials");
+                    reply(
+                        &mut reader,
+                        &format!("%error {}", Error::InvalidCredentials),
+                    );
+                    continue;
+                }
+
+                authenticated = token_valid;
+                user_id = login_id;
+                Span::current().record("user_id", cmd.id.as_str());
+                info!("logged in");
+
+                let mut connections = shared.connections.lock().unwrap();
+                let previous = connections.get_mut(&connection_id).unwrap().user_id.take();
+
+                //
+                // Сообщаем остальным, что пользователь появился в сети
+                // (а если он перелогинился под другим именем - что старое имя вышло).
+                // Пока рассылается %leave, текущее соединение не залогинено и его не получит.
+                //
+
+                if let Some(previous) = previous.as_ref().filter(|previous| **previous != cmd.id) {
+                    announce_leave(&mut connections, &shared.webhooks, previous);
+                }
+
+                connections.get_mut(&connection_id).unwrap().user_id = Some(cmd.id.clone());
+
+                if previous.as_ref() != Some(&cmd.id) {
+                    announce_join(&mut connections, &shared.webhooks, &cmd.id);
+                }
+
+                drop(connections);
+                run_hooks(shared, "on_login", |hook| {
+                    hook.on_login(connection_id, &cmd.id)
+                });
+            }
+            //
+            // Клиент прислал сообщение, которое нужно разослать всем остальным клиентам.
+            //
+            Command::Message(cmd) => {
+                //
+                // Если пользователь приславший сообщение не залогинен - ничего не делаем.
+                //
+
+                let Some(from) = user_id.clone() else {
+                    continue;
+                };
+
+                //
+                // Сообщения пользователя, которому запретили писать, никому не рассылаются.
+                //
+
+                if stored!(&mut reader, shared.moderation.is_muted(&from)) {
+                    reply(&mut reader, &Error::Muted);
+                    continue;
+                }
+
+                //
+                // Сообщение сохраняется в историю и рассылается так же, как сообщения из HTTP API,
+                // а отправитель получит его обратно с пометкой "%sent", чтобы потом он мог исправить или удалить его.
+                //
+
+                stored!(
+                    &mut reader,
+                    post(shared, &mut reader, &from, &cmd.message, None, &[])
+                );
+            }
+            //
+            // Сообщение с упоминаниями рассылается всем, как обычное, но упомянутые получают его с пометкой.
+            //
+            Command::MessageWithMentions(cmd) => {
+                let Some(from) = user_id.clone() else {
+                    continue;
+                };
+
+                if stored!(&mut reader, shared.moderation.is_muted(&from)) {
+                    reply(&mut reader, &Error::Muted);
+                    continue;
+                }
+
+                stored!(
+                    &mut reader,
+                    post(
+                        shared,
+                        &mut reader,
+                        &from,
+                        &cmd.message,
+                        None,
+                        &cmd.user_names,
+                    )
+                );
+            }
+            //
+            // Ответ рассылается как обычное сообщение, но со ссылкой на сообщение, на которое отвечают.
+            // Упомянутые в ответе получают его с пометкой, как и сообщение с упоминаниями.
+            //
+            Command::Reply(cmd) => {
+                let Some(from) = user_id.clone() else {
+                    reply(&mut reader, &Error::NotLoggedIn);
+                    continue;
+                };
+
+                if stored!(&mut reader, shared.moderation.is_muted(&from)) {
+                    reply(&mut reader, &Error::Muted);
+                    continue;
+                }
+
+                if stored!(&mut reader, shared.history.get(cmd.id)).is_none() {
+                    reply(&mut reader, &Error::UnknownMessage);
+                    continue;
+                }
+
+                stored!(
+                    &mut reader,
+                    post(
+                        shared,
+                        &mut reader,
+                        &from,
+                        &cmd.message,
+                        Some(cmd.id),
+                        &mentions(&cmd.message),
+                    )
+                );
+            }
+            //
+            // Ветка обсуждения: сначала "%thread <количество>", затем сами сообщения от старых к новым.
+            //
+            Command::Thread(cmd) => {
+                if user_id.is_none() {
+                    reply(&mut reader, &Error::NotLoggedIn);
+                    continue;
+                }
+
+                let thread = stored!(&mut reader, shared.history.thread(cmd.id));
+                if thread.is_empty() {
+                    reply(&mut reader, &Error::UnknownMessage);
+                    continue;
+                }
+
+                send_messages(&mut reader, "%thread", &thread, "");
+            }
+            //
+            // Сообщения общего чата за промежуток времени: сначала "%history <количество>", затем сами сообщения.
+            // Если в промежутке больше MAX_HISTORY_REPLY сообщений, отправляются только последние из них.
+            //
+            Command::History(cmd) => {
+                if user_id.is_none() {
+                    reply(&mut reader, &Error::NotLoggedIn);
+                    continue;
+                }
+
+                let entries = stored!(
+                    &mut reader,
+                    shared
+                        .history
+                        .range(cmd.since, cmd.until, MAX_HISTORY_REPLY)
+                );
+                send_messages(&mut reader, "%history", &entries, "");
+            }
+            //
+            // Реакции не создают новых сообщений в чате: всем рассылается только событие
+            // "%reaction <id> <реакция> <количество>" с новым количеством таких реакций.
+            // Пользователь, которому запретили писать, реакции ставить и снимать тоже не может.
+            //
+            Command::React(cmd) => {
+                let Some(user) = user_id.as_deref() else {
+                    reply(&mut reader, &Error::NotLoggedIn);
+                    continue;
+                };
+
+                if stored!(&mut reader, shared.moderation.is_muted(user)) {
+                    reply(&mut reader, &Error::Muted);
+                    continue;
+                }
+                if stored!(&mut reader, shared.history.get(cmd.id)).is_none() {
+                    reply(&mut reader, &Error::UnknownMessage);
+                    continue;
+                }
+
+                if let Some(count) = stored!(
+                    &mut reader,
+                    shared.history.react(cmd.id, user, &cmd.reaction)
+                ) {
+                    broadcast(
+                        &mut shared.connections.lock().unwrap(),
+                        &reaction_event(cmd.id, &cmd.reaction, count),
+                        None,
+                    );
+                }
+            }
+            Command::Unreact(cmd) => {
+                let Some(user) = user_id.as_deref() else {
+                    reply(&mut reader, &Error::NotLoggedIn);
+                    continue;
+                };
+
+                if stored!(&mut reader, shared.moderation.is_muted(user)) {
+                    reply(&mut reader, &Error::Muted);
+                    continue;
+                }
+                if stored!(&mut reader, shared.history.get(cmd.id)).is_none() {
+                    reply(&mut reader, &Error::UnknownMessage);
+                    continue;
+                }
+
+                if let Some(count) = stored!(
+                    &mut reader,
+                    shared.history.unreact(cmd.id, user, &cmd.reaction)
+                ) {
+                    broadcast(
+                        &mut shared.connections.lock().unwrap(),
+                        &reaction_event(cmd.id, &cmd.reaction, count),
+                        None,
+                    );
+                }
+            }
+            //
+            // Непрочитанные упоминания: сначала "%mentions <количество>", затем сами упоминания.
+            //
+            Command::ShowMentions(_) => {
+                let Some(user) = user_id.as_deref() else {
+                    reply(&mut reader, &Error::NotLoggedIn);
+                    continue;
+                };
+
+                let mentions = shared.mentions.take(user);
+                send_messages(&mut reader, "%mentions", &mentions, "%mention ");
+            }
+            //
+            // Исправить сообщение может только его автор. Исправление применяется к истории
+            // и рассылается всем как событие "%edit <id> <text>".
+            //
+            Command::Edit(cmd) => {
+                let Some(user) = user_id.as_deref() else {
+                    reply(&mut reader, &Error::NotLoggedIn);
+                    continue;
+                };
+
+                if stored!(&mut reader, shared.moderation.is_muted(user)) {
+                    reply(&mut reader, &Error::Muted);
+                    continue;
+                }
+
+                match stored!(&mut reader, shared.history.get(cmd.id)) {
+                    None => {
+                        reply(&mut reader, &Error::UnknownMessage);
+                        continue;
+                    }
+                    Some(entry) if entry.from != user => {
+                        reply(&mut reader, &Error::PermissionDenied);
                         continue;
                     }
+                    Some(_) => {}
+                }
+
+                if stored!(&mut reader, shared.history.edit(cmd.id, &cmd.message)) {
+                    broadcast(
+                        &mut shared.connections.lock().unwrap(),
+                        &format!("%edit {} {}", cmd.id, cmd.message),
+                        None,
+                    );
+                }
+            }
+            //
+            // Удалить сообщение может его автор или администратор. Удаление чужого сообщения
+            // записывается в журнал аудита.
+            //
+            Command::Delete(cmd) => {
+                let Some(user) = user_id.as_deref() else {
+                    reply(&mut reader, &Error::NotLoggedIn);
+                    continue;
+                };
+
+                let Some(entry) = stored!(&mut reader, shared.history.get(cmd.id)) else {
+                    reply(&mut reader, &Error::UnknownMessage);
+                    continue;
+                };
+
+                let own = entry.from == user;
+                if !own
+                    && stored!(&mut reader, user_kind(shared, &user_id, authenticated))
+                        != UserKind::Admin
+                {
+                    reply(&mut reader, &Error::PermissionDenied);
+                    continue;
+                }
+
+                if stored!(&mut reader, shared.history.delete(cmd.id)) {
+                    broadcast(
+                        &mut shared.connections.lock().unwrap(),
+                        &format!("%delete {}", cmd.id),
+                        None,
+                    );
+
+                    if !own {
+                        audit(shared, user, address, Command::Delete(cmd));
+                    }
+                }
+            }
+            //
+            // Передача файла. Отправитель предлагает файл пользователю, который сейчас в сети,
+            // получатель соглашается или отказывается. После согласия сервер пересылает куски файла
+            // в соединение получателя, проверяя размер и контрольную сумму. Куски - обычные строки,
+            // поэтому сообщения чата между ними проходят как обычно.
+            //
+            Command::FileOffer(cmd) => {
+                let Some(from) = user_id.as_deref() else {
+                    reply(&mut reader, &Error::NotLoggedIn);
+                    continue;
+                };
+
+                if stored!(&mut reader, shared.moderation.is_muted(from)) {
+                    reply(&mut reader, &Error::Muted);
+                    continue;
+                }
 
-                    //
-                    // Если соединение текущей итерации (conn) - это соединение пользователя, приславшего сообщение - ничего не делаем.
-                    // Мы не хотим отправлять его же сообщение ему обратно.
-                    //
+                let mut connections = shared.connections.lock().unwrap();
+                if sessions(&connections, &cmd.user) == 0 {
+                    reply(&mut reader, &Error::UnknownUser);
+                    continue;
+                }
 
-                    if conn.user_id == user_id {
+                let transfer = match shared.transfers.offer(
+                    connection_id,
+                    from,
+                    &cmd,
+                    shared.limits.max_file_size,
+                ) {
+                    Ok(transfer) => transfer,
+                    Err(error) => {
+                        reply(&mut reader, &error);
                         continue;
                     }
+                };
+
+                let details = format!(
+                    "{} {} {} {}",
+                    transfer.id, transfer.size, transfer.checksum, transfer.name
+                );
+                send_to_user(
+                    &mut connections,
+                    &transfer.to,
+                    &format!("%file_offer {from} {details}"),
+                    Some(connection_id),
+                );
+                reply(
+                    &mut reader,
+                    &format!("%file_offered {} {details}", transfer.to),
+                );
+            }
+            Command::FileAccept(cmd) => {
+                let Some(user) = user_id.as_deref() else {
+                    reply(&mut reader, &Error::NotLoggedIn);
+                    continue;
+                };
+
+                match shared.transfers.accept(cmd.id, user, connection_id) {
+                    Ok(transfer) => send_to(
+                        &mut shared.connections.lock().unwrap(),
+                        transfer.sender,
+                        &format!("%file_accepted {}", transfer.id),
+                    ),
+                    Err(error) => reply(&mut reader, &error),
+                }
+            }
+            Command::FileDecline(cmd) => {
+                let Some(user) = user_id.as_deref() else {
+                    reply(&mut reader, &Error::NotLoggedIn);
+                    continue;
+                };
+
+                match shared.transfers.decline(cmd.id, user) {
+                    Ok(transfer) => send_to(
+                        &mut shared.connections.lock().unwrap(),
+                        transfer.sender,
+                        &format!("%file_declined {}", transfer.id),
+                    ),
+                    Err(error) => reply(&mut reader, &error),
+                }
+            }
+            Command::FileCancel(cmd) => {
+                let Some(user) = user_id.as_deref() else {
+                    reply(&mut reader, &Error::NotLoggedIn);
+                    continue;
+                };
+
+                match shared.transfers.cancel(cmd.id, user, connection_id) {
+                    Ok(transfer) => notify_transfer(
+                        &mut shared.connections.lock().unwrap(),
+                        &transfer,
+                        &format!("%file_cancelled {}", transfer.id),
+                        Some(connection_id),
+                    ),
+                    Err(error) => reply(&mut reader, &error),
+                }
+            }
+            Command::FileChunk(cmd) => {
+                if user_id.is_none() {
+                    reply(&mut reader, &Error::NotLoggedIn);
+                    continue;
+                }
+
+                //
+                // Получателю, который перешел на кадры, кусок уходит как есть, без base64.
+                //
+
+                match shared.transfers.chunk(cmd.id, connection_id, &cmd.data) {
+                    Ok(receiver) => {
+                        if let Some(conn) = shared.connections.lock().unwrap().get_mut(&receiver) {
+                            conn.connection.send(&Frame::FileChunk(cmd));
+                        }
+                    }
+                    Err(Error::UnknownTransfer) => reply(&mut reader, &Error::UnknownTransfer),
+                    Err(error) => fail_transfer(shared, cmd.id, &error),
+                }
+            }
+            Command::FileEnd(cmd) => {
+                if user_id.is_none() {
+                    reply(&mut reader, &Error::NotLoggedIn);
+                    continue;
+                }
+
+                match shared.transfers.end(cmd.id, connection_id) {
+                    Ok(transfer) => {
+                        let mut connections = shared.connections.lock().unwrap();
+                        if let Some(receiver) = transfer.receiver {
+                            send_to(&mut connections, receiver, &cmd.to_string());
+                        }
+                        send_to(
+                            &mut connections,
+                            transfer.sender,
+                            &format!("%file_sent {}", transfer.id),
+                        );
+                    }
+                    Err(Error::UnknownTransfer) => reply(&mut reader, &Error::UnknownTransfer),
+                    Err(error) => fail_transfer(shared, cmd.id, &error),
+                }
+            }
+            //
+            // Личное сообщение получают только сеансы адресата. В общий чат оно не попадает.
+            //
+            Command::DirectMessage(cmd) => {
+                let Some(from) = user_id.clone() else {
+                    reply(&mut reader, &Error::NotLoggedIn);
+                    continue;
+                };
+
+                if stored!(&mut reader, shared.moderation.is_muted(&from)) {
+                    reply(&mut reader, &Error::Muted);
+                    continue;
+                }
+
+                //
+                // Писать можно только тем, кто сейчас в сети или зарегистрирован: иначе сообщение
+                // осталось бы в переписке, которую никто никогда не прочитает.
+                //
+
+                if !stored!(&mut reader, user_exists(shared, &cmd.user)) {
+                    reply(&mut reader, &Error::UnknownUser);
+                    continue;
+                }
+
+                let entry = DirectMessageEntry {
+                    from,
+                    to: cmd.user,
+                    text: cmd.message,
+                };
+                let line = direct_message_line(&entry);
+                stored!(&mut reader, shared.direct_messages.add(entry.clone()));
+
+                //
+                // Отправитель получает сообщение обратно, как "%sent" в общем чате.
+                //
+
+                send_to_user(
+                    &mut shared.connections.lock().unwrap(),
+                    &entry.to,
+                    &line,
+                    Some(connection_id),
+                );
+                reply(&mut reader, &line);
+            }
+            //
+            // Без аргумента - список собеседников, с именем - переписка с этим собеседником.
+            //
+            Command::ShowDirectMessages(cmd) => {
+                let Some(user) = user_id.as_deref() else {
+                    reply(&mut reader, &Error::NotLoggedIn);
+                    continue;
+                };
+
+                match cmd.user {
+                    None => {
+                        let users =
+                            stored!(&mut reader, shared.direct_messages.conversations(user));
+                        reply(&mut reader, &format!("%dms {}", users.join(" ")));
+                    }
+                    Some(other) => {
+                        for entry in stored!(
+                            &mut reader,
+                            shared.direct_messages.conversation(user, &other)
+                        ) {
+                            reply(&mut reader, &direct_message_line(&entry));
+                        }
+                    }
+                }
+            }
+            //
+            // Регистрация и удаление пользователей.
+            //
+            Command::AddUser(cmd) => stored!(&mut reader, shared.users.add_user(cmd.id, cmd.kind)),
+            Command::RemoveUser(cmd) => {
+                stored!(&mut reader, shared.users.remove_user(cmd.id));
+            }
+            //
+            // Модерация. Отключенные пользователи получают уведомление с причиной,
+            // а их потоки завершатся сами, получив конец потока.
+            //
+            Command::Kick(cmd) => {
+                let notice = match &cmd.reason {
+                    Some(reason) => format!("%notice you were kicked: {reason}"),
+                    None => "%notice you were kicked".to_string(),
+                };
 
-                    //
-                    // Наконец мы отправляем только что полученное сообщение в некоторое соединение.
-                    // Ошибку игнорируем.
-                    //
+                let kicked = disconnect(&mut shared.connections.lock().unwrap(), &notice, |conn| {
+                    conn.user_id.as_deref() == Some(cmd.user.as_str())
+                });
 
-                    conn.connection
-                        .write_all(
-                            format!("{}: {}", user_id.clone().unwrap(), cmd.message).as_bytes(),
-                        )
-                        .ok();
+                if kicked == 0 {
+                    reply(&mut reader, &Error::UnknownUser);
+                    continue;
                 }
             }
+            Command::Ban(cmd) => {
+                let until = cmd.duration.map(|duration| SystemTime::now() + duration);
+                stored!(
+                    &mut reader,
+                    shared.moderation.ban(cmd.target.clone(), until)
+                );
+
+                disconnect(
+                    &mut shared.connections.lock().unwrap(),
+                    "%notice you were banned",
+                    |conn| match &cmd.target {
+                        BanTarget::User(user) => conn.user_id.as_ref() == Some(user),
+                        BanTarget::Ip(ip) => conn.address.ip() == *ip,
+                    },
+                );
+            }
+            Command::Unban(cmd) => {
+                stored!(&mut reader, shared.moderation.unban(&cmd.target));
+            }
+            Command::Mute(cmd) => {
+                let until = cmd.duration.map(|duration| SystemTime::now() + duration);
+                stored!(&mut reader, shared.moderation.mute(cmd.user, until));
+            }
+            Command::Unmute(cmd) => {
+                stored!(&mut reader, shared.moderation.unmute(&cmd.user));
+            }
+            //
+            // Клиент завершает сеанс: выходим из handle_connection, соединение закроется.
             //
-            // TODO: обработать остальные команды.
+            Command::Bye(_) => return Ok(()),
             //
-            _ => continue,
+            // Отправляем клиенту список всех залогиненных пользователей одной строкой.
+            //
+            Command::ShowUsers(_) => {
+                let users = online_users(shared);
+                reply(&mut reader, &format!("%users {}", users.join(" ")));
+            }
+            //
+            // Статистика сервера для администраторов: те же метрики, что отдаются по HTTP.
+            //
+            Command::Stats(_) => {
+                let summary = shared.metrics.summary(gauges(shared));
+                reply(&mut reader, &format!("%stats {summary}"));
+            }
+        }
+
+        if let Some(cmd) = audited {
+            audit(shared, user_id.as_deref().unwrap_or_default(), address, cmd);
+        }
+
+        if let Some(cmd) = for_bots {
+            let sender = Sender {
+                user: user_id.as_deref(),
+                address,
+            };
+            run_bots(shared, &sender, &cmd);
         }
     }
 }
+
+//
+// Логирует административную команду и записывает ее в журнал аудита.
+// Если журнал недоступен, команда уже выполнена, поэтому соединение не разрываем.
+//
+
+fn audit(shared: &Shared, actor: &str, address: SocketAddr, command: Command) {
+    info!(command = %command, "admin action");
+
+    let Some(audit) = &shared.audit else {
+        return;
+    };
+
+    let entry = AuditEntry {
+        time: SystemTime::now(),
+        actor: actor.to_string(),
+        address: address.ip(),
+        command,
+    };
+
+    if let Err(error) = audit.record(&entry) {
+        error!(%error, "cannot write audit log");
+    }
+}
+
+//
+// Отправляет строку всем залогиненным пользователям, кроме skip_user. Ошибки отправки игнорируются.
+//
+
+fn broadcast(
+    connections: &mut HashMap<Uuid, AcceptedConnection>,
+    line: &str,
+    skip_user: Option<&str>,
+) {
+    for conn in connections.values_mut() {
+        if conn.user_id.is_none() || conn.user_id.as_deref() == skip_user {
+            continue;
+        }
+
+        conn.connection.send(&Frame::text(line));
+    }
+}
+
+//
+// Отправляет уведомление и закрывает все соединения, подходящие под условие.
+// Возвращает количество закрытых соединений.
+//
+
+fn disconnect(
+    connections: &mut HashMap<Uuid, AcceptedConnection>,
+    notice: &str,
+    condition: impl Fn(&AcceptedConnection) -> bool,
+) -> usize {
+    let mut count = 0;
+
+    for conn in connections.values_mut().filter(|conn| condition(conn)) {
+        conn.connection.send(&Frame::text(notice));
+        conn.connection.shutdown(Shutdown::Both).ok();
+        count += 1;
+    }
+
+    count
+}
+
+//
+// События присутствия. У пользователя может быть несколько соединений, поэтому
+// %join отправляется только для первого из них, а %leave - только после закрытия последнего.
+//
+
+fn sessions(connections: &HashMap<Uuid, AcceptedConnection>, user_id: &str) -> usize {
+    connections
+        .values()
+        .filter(|conn| conn.user_id.as_deref() == Some(user_id))
+        .count()
+}
+
+//
+// Отправляет строку в одно соединение.
+//
+
+fn send_to(connections: &mut HashMap<Uuid, AcceptedConnection>, connection_id: Uuid, line: &str) {
+    if let Some(conn) = connections.get_mut(&connection_id) {
+        conn.connection.send(&Frame::text(line));
+    }
+}
+
+//
+// Отправляет строку во все сеансы пользователя, кроме соединения skip.
+//
+
+fn send_to_user(
+    connections: &mut HashMap<Uuid, AcceptedConnection>,
+    user_id: &str,
+    line: &str,
+    skip: Option<Uuid>,
+) {
+    for (id, conn) in connections.iter_mut() {
+        if conn.user_id.as_deref() == Some(user_id) && Some(*id) != skip {
+            conn.connection.send(&Frame::text(line));
+        }
+    }
+}
+
+//
+// Сообщает участникам передачи файла о ее завершении. Если получатель еще не согласился
+// принять файл, сообщаем всем его сеансам: в каждом из них видно предложение.
+//
+
+fn notify_transfer(
+    connections: &mut HashMap<Uuid, AcceptedConnection>,
+    transfer: &Transfer,
+    line: &str,
+    skip: Option<Uuid>,
+) {
+    if Some(transfer.sender) != skip {
+        send_to(connections, transfer.sender, line);
+    }
+
+    match transfer.receiver {
+        Some(receiver) if Some(receiver) != skip => send_to(connections, receiver, line),
+        Some(_) => {}
+        None => send_to_user(connections, &transfer.to, line, skip),
+    }
+}
+
+//
+// Прерывает передачу файла после ошибки и сообщает об этом обоим участникам.
+//
+
+fn fail_transfer(shared: &Shared, id: u64, error: &Error) {
+    if let Some(transfer) = shared.transfers.remove(id) {
+        notify_transfer(
+            &mut shared.connections.lock().unwrap(),
+            &transfer,
+            &format!("%file_failed {id} {error}"),
+            None,
+        );
+    }
+}
+
+fn announce_join(
+    connections: &mut HashMap<Uuid, AcceptedConnection>,
+    webhooks: &Webhooks,
+    user_id: &str,
+) {
+    if sessions(connections, user_id) == 1 {
+        broadcast(connections, &format!("%join {user_id}"), Some(user_id));
+        webhooks.join(user_id);
+    }
+}
+
+fn announce_leave(
+    connections: &mut HashMap<Uuid, AcceptedConnection>,
+    webhooks: &Webhooks,
+    user_id: &str,
+) {
+    if sessions(connections, user_id) == 0 {
+        broadcast(connections, &format!("%leave {user_id}"), Some(user_id));
+        webhooks.leave(user_id);
+    }
+}
+
+//
+// Тип пользователя. Администратором считается только тот, кто залогинился
+// под id, зарегистрированным в хранилище как admin, и подтвердил вход токеном администратора
+// (authenticated). Тип проверяется при каждом обращении, поэтому удаленный администратор
+// сразу теряет свои права.
+//
+
+fn user_kind(
+    shared: &Shared,
+    user_id: &Option<String>,
+    authenticated: bool,
+) -> Result<UserKind, Error> {
+    if !authenticated {
+        return Ok(UserKind::Normal);
+    }
+
+    let id = match user_id.as_deref().map(Uuid::parse_str) {
+        Some(Ok(id)) => id,
+        _ => return Ok(UserKind::Normal),
+    };
+
+    Ok(shared.users.user_kind(id)?.unwrap_or(UserKind::Normal))
+}
+
+//
+// Сохраняет сообщение общего чата в историю и рассылает его всем, кроме отправителя.
+// Упомянутые получают сообщение с пометкой "%mention" и, кроме того, оно попадает в их список
+// непрочитанных упоминаний. Отправитель получает сообщение обратно с пометкой "%sent".
+//
+
+fn post(
+    shared: &Shared,
+    reader: &mut BufReader<MeteredStream>,
+    from: &str,
+    text: &str,
+    parent: Option<u64>,
+    mentioned: &[String],
+) -> Result<(), Error> {
+    let line = publish(shared, from, text, parent, mentioned)?;
+    reply(reader, &format!("%sent {line}"));
+
+    Ok(())
+}
+
+//
+// Сохраняет сообщение в историю и рассылает его всем, кроме отправителя, - и тем, кто подключен
+// по TCP, и тем, кто подключен по WebSocket. Возвращает строку сообщения, которую получили клиенты.
+//
+
+fn publish(
+    shared: &Shared,
+    from: &str,
+    text: &str,
+    parent: Option<u64>,
+    mentioned: &[String],
+) -> Result<String, Error> {
+    //
+    // Список соединений не отпускаем, пока сообщение не разослано: так сообщения уходят клиентам
+    // в порядке идентификаторов и все клиенты видят их в одном и том же порядке.
+    //
+
+    let mut connections = shared.connections.lock().unwrap();
+
+    //
+    // Упоминания сохраняем только тем, кто сейчас в сети или зарегистрирован: иначе любое @имя
+    // заводило бы в памяти новый список упоминаний, которые никто никогда не прочитает.
+    //
+
+    let mut inboxes = Vec::new();
+    for user in mentioned.iter().filter(|user| *user != from) {
+        if sessions(&connections, user) > 0 || registered(shared, user)? {
+            inboxes.push(user);
+        }
+    }
+
+    let entry = shared.history.add(from, text, parent)?;
+    for user in inboxes {
+        shared.mentions.add(user, entry.clone());
+    }
+
+    let started = Instant::now();
+    let line = chat_line(&entry);
+    let frame = Frame::text(line.clone());
+    let flagged = Frame::text(format!("%mention {line}"));
+
+    for conn in connections.values_mut() {
+        let Some(recipient) = &conn.user_id else {
+            continue;
+        };
+        if *recipient == from {
+            continue;
+        }
+
+        let line = if mentioned.contains(recipient) {
+            &flagged
+        } else {
+            &frame
+        };
+        conn.connection.send(line);
+    }
+
+    shared.metrics.message();
+    shared.metrics.broadcast(started.elapsed());
+    shared.webhooks.message(&entry, mentioned);
+
+    drop(connections);
+    run_hooks(shared, "after_broadcast", |hook| {
+        hook.after_broadcast(&entry, mentioned)
+    });
+
+    Ok(line)
+}
+
+//
+// Вызывает обработчики по порядку. Паника в обработчике - ошибка обработчика, а не клиента,
+// поэтому соединение из-за нее не разрываем, а только логируем и переходим к следующему.
+//
+
+fn run_hooks(shared: &Shared, event: &str, mut call: impl FnMut(&dyn Hook)) {
+    for (index, hook) in shared.hooks.iter().enumerate() {
+        if catch_unwind(AssertUnwindSafe(|| call(hook.as_ref()))).is_err() {
+            error!(hook = index, event, "hook panicked");
+        }
+    }
+}
+
+//
+// Пропускает команду через before_command всех обработчиков. Возвращает команду, которую
+// нужно выполнить, или Error::Rejected, если ее отклонил один из обработчиков.
+//
+
+fn before_command(
+    shared: &Shared,
+    sender: &Sender,
+    mut command: Command,
+) -> Result<Command, Error> {
+    for (index, hook) in shared.hooks.iter().enumerate() {
+        match catch_unwind(AssertUnwindSafe(|| hook.before_command(sender, &command))) {
+            Ok(Action::Continue) => {}
+            Ok(Action::Rewrite(rewritten)) => command = rewritten,
+            Ok(Action::Reject(reason)) => return Err(Error::Rejected(reason)),
+            Err(_) => error!(hook = index, event = "before_command", "hook panicked"),
+        }
+    }
+
+    Ok(command)
+}
+
+//
+// Передает команду ботам и выполняет их ответы. Сообщения ботов рассылаются так же,
+// как сообщения пользователей, только ботам они уже не передаются. Если ответ выполнить
+// не удалось, это ошибка бота, а не того, кто прислал команду, поэтому ее только логируем.
+// Личные сообщения и передачи файлов касаются только их участников, ботам их не передаем,
+// а вход передаем без токена администратора.
+//
+
+fn run_bots(shared: &Shared, sender: &Sender, command: &Command) {
+    let login;
+    let command = match command {
+        Command::DirectMessage(_)
+        | Command::FileOffer(_)
+        | Command::FileAccept(_)
+        | Command::FileDecline(_)
+        | Command::FileCancel(_)
+        | Command::FileChunk(_)
+        | Command::FileEnd(_) => return,
+        Command::Login(cmd) => {
+            login = Command::Login(Login {
+                id: cmd.id.clone(),
+                token: None,
+            });
+            &login
+        }
+        _ => command,
+    };
+
+    for bot in &shared.bots {
+        for bot_reply in bot.on_command(sender, command) {
+            let result = match (bot_reply, sender.user) {
+                (BotReply::Message(text), _) => {
+                    publish(shared, bot.name(), &text, None, &mentions(&text)).map(drop)
+                }
+                (BotReply::Mention(text), Some(user)) => publish(
+                    shared,
+                    bot.name(),
+                    &format!("@{user} {text}"),
+                    None,
+                    &[user.to_string()],
+                )
+                .map(drop),
+                (BotReply::Notice(text), Some(user)) => {
+                    send_to_user(
+                        &mut shared.connections.lock().unwrap(),
+                        user,
+                        &format!("%notice {text}"),
+                        None,
+                    );
+                    Ok(())
+                }
+                (BotReply::Mention(_) | BotReply::Notice(_), None) => Ok(()),
+            };
+
+            if let Err(error) = result {
+                warn!(bot = bot.name(), %error, "cannot send bot reply");
+            }
+        }
+    }
+}
+
+//
+// Пользователь существует, если он сейчас в сети или зарегистрирован.
+//
+
+fn user_exists(shared: &Shared, user_id: &str) -> Result<bool, Error> {
+    if sessions(&shared.connections.lock().unwrap(), user_id) > 0 {
+        return Ok(true);
+    }
+
+    registered(shared, user_id)
+}
+
+fn registered(shared: &Shared, user_id: &str) -> Result<bool, Error> {
+    match Uuid::parse_str(user_id) {
+        Ok(id) => Ok(shared.users.user_kind(id)?.is_some()),
+        Err(_) => Ok(false),
+    }
+}
+
+//
+// Пользователи, которые сейчас в чате, и боты - по алфавиту и без повторов.
+//
+
+fn online_users(shared: &Shared) -> Vec<String> {
+    let mut users: Vec<String> = shared
+        .connections
+        .lock()
+        .unwrap()
+        .values()
+        .filter_map(|conn| conn.user_id.clone())
+        .collect();
+    users.extend(shared.bots.iter().map(|bot| bot.name().to_string()));
+    users.sort();
+    users.dedup();
+    users
+}
+
+//
+// Клиент уперся в ограничение частоты: сообщаем, когда можно повторить. Тех, кто продолжает
+// флудить после предупреждений, отключаем: возвращаем ошибку, и соединение закрывается.
+//
+
+fn throttle(
+    reader: &mut BufReader<MeteredStream>,
+    violations: &mut TokenBucket,
+    violations_rate: Rate,
+    retry_after: Duration,
+) -> Result<(), Error> {
+    if violations.take(violations_rate, Instant::now()).is_err() {
+        warn!("disconnected for flooding");
+        reply(reader, &"%notice disconnected for flooding");
+        return Err(Error::RateLimited(retry_after));
+    }
+
+    reply(reader, &Error::RateLimited(retry_after));
+    Ok(())
+}
+
+//
+// Отправляет строку в соединение, из которого читает reader.
+// Ошибка отправки данных в сеть игнорируется, как и в MeteredStream::send.
+//
+
+fn reply(reader: &mut BufReader<MeteredStream>, message: &impl std::fmt::Display) {
+    reader.get_mut().send(&Frame::text(message.to_string()));
+}
+
+//
+// Дальше и читаем, и отправляем этому соединению кадры.
+//
+
+fn use_frames(shared: &Shared, reader: &mut BufReader<MeteredStream>, connection_id: Uuid) {
+    reader.get_mut().framing = Framing::Frames;
+    if let Some(conn) = shared.connections.lock().unwrap().get_mut(&connection_id) {
+        conn.connection.framing = Framing::Frames;
+    }
+}
+
+//
+// Ответ сервера на %hello: версия, возможности и ограничения, о которых клиенту полезно знать заранее.
+//
+
+fn hello(limits: &Limits) -> Hello {
+    let mut capabilities: Vec<String> = CAPABILITIES.iter().map(|c| c.to_string()).collect();
+    capabilities.extend([
+        format!("max_line_length={}", limits.max_line_length),
+        format!("max_block_length={}", limits.max_block_length),
+        format!("max_file_size={}", limits.max_file_size),
+    ]);
+
+    Hello {
+        version: Hello::VERSION,
+        capabilities,
+    }
+}
+
+//
+// Сообщение общего чата в том виде, в котором его получают клиенты: "#<id> <время> <от кого>: <текст>",
+// а ответ на сообщение - "#<id> <время> ^<на какое сообщение отвечают> <от кого>: <текст>".
+// Время - в UTC, в формате RFC 3339.
+//
+
+fn chat_line(entry: &HistoryEntry) -> String {
+    let time = format_time(entry.time);
+
+    match entry.parent {
+        Some(parent) => format!(
+            "#{} {time} ^{parent} {}: {}",
+            entry.id, entry.from, entry.text
+        ),
+        None => format!("#{} {time} {}: {}", entry.id, entry.from, entry.text),
+    }
+}
+
+//
+// Отправляет клиенту список сообщений общего чата: сначала "<заголовок> <количество>", затем сами сообщения,
+// а вслед за ними - текущие реакции на эти сообщения в виде тех же событий, что рассылаются
+// при каждом изменении реакций.
+//
+
+fn send_messages(
+    reader: &mut BufReader<MeteredStream>,
+    header: &str,
+    entries: &[HistoryEntry],
+    flag: &str,
+) {
+    reply(reader, &format!("{header} {}", entries.len()));
+    for entry in entries {
+        reply(reader, &format!("{flag}{}", chat_line(entry)));
+    }
+
+    for entry in entries {
+        for (reaction, users) in &entry.reactions {
+            reply(reader, &reaction_event(entry.id, reaction, users.len()));
+        }
+    }
+}
+
+//
+// Событие об изменении реакций на сообщение: "%reaction <id> <реакция> <сколько пользователей ее поставили>".
+//
+
+fn reaction_event(id: u64, reaction: &str, count: usize) -> String {
+    format!("%reaction {id} {reaction} {count}")
+}
+
+//
+// Личное сообщение в том виде, в котором его получает клиент: "%dm <от кого> <кому> <текст>".
+//
+
+fn direct_message_line(entry: &DirectMessageEntry) -> String {
+    format!("%dm {} {} {}", entry.from, entry.to, entry.text)
+}
+
+fn gauges(shared: &Shared) -> Gauges {
+    let connections = shared.connections.lock().unwrap();
+
+    let mut users: Vec<&String> = connections
+        .values()
+        .filter_map(|conn| conn.user_id.as_ref())
+        .collect();
+    users.sort();
+    users.dedup();
+
+    Gauges {
+        connections: connections.len(),
+        users: users.len(),
+    }
+}
+
+//
+// Отдает метрики по HTTP, пока сервер не остановится. Запросы обрабатываются по одному:
+// это служебный эндпоинт, который опрашивает только система мониторинга.
+//
+
+fn serve_metrics(listener: TcpListener, shared: &Shared) {
+    for connection in listener.incoming() {
+        if shared.shutdown.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let Ok(connection) = connection else {
+            continue;
+        };
+        connection
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .ok();
+
+        let result = http::read_request(&connection).and_then(|request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/metrics") => http::write_response(
+                    &connection,
+                    "200 OK",
+                    "text/plain; version=0.0.4",
+                    &shared.metrics.render(gauges(shared)),
+                ),
+                _ => {
+                    http::write_response(&connection, "404 Not Found", "text/plain", "not found\n")
+                }
+            }
+        });
+
+        if let Err(error) = result {
+            warn!(%error, "cannot serve metrics");
+        }
+    }
+}
+
+//
+// HTTP API обслуживает запросы по одному, как и метрики: запросы короткие, а их авторы - скрипты.
+//
+
+//
+// Каждый запрос к API обслуживается в своем потоке, чтобы медленный клиент не задерживал остальных.
+// Одновременных запросов не больше, чем разрешено соединений с чатом.
+//
+
+fn serve_api(listener: TcpListener, shared: &Arc<Shared>) {
+    let in_flight = Arc::new(AtomicUsize::new(0));
+
+    for connection in listener.incoming() {
+        if shared.shutdown.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let Ok(connection) = connection else {
+            continue;
+        };
+        connection
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .ok();
+        connection
+            .set_write_timeout(Some(Duration::from_secs(5)))
+            .ok();
+
+        if in_flight.fetch_add(1, Ordering::SeqCst) >= shared.limits.max_connections {
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            warn!("too many api requests");
+            let body = format!("{}\n", Error::TooManyConnections);
+            http::write_response(
+                &connection,
+                "503 Service Unavailable",
+                "text/plain; charset=utf-8",
+                &body,
+            )
+            .ok();
+            continue;
+        }
+
+        let spawned = {
+            let shared = shared.clone();
+            let in_flight = in_flight.clone();
+            thread::Builder::new().spawn(move || {
+                serve_api_request(&connection, &shared);
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+            })
+        };
+        if let Err(error) = spawned {
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            error!(%error, "cannot spawn api thread");
+        }
+    }
+}
+
+fn serve_api_request(connection: &TcpStream, shared: &Shared) {
+    let (status, body) = match handle_api_request(connection, shared) {
+        Ok(response) => response,
+        Err(error) => {
+            warn!(%error, "api request failed");
+            (api_status(&error), format!("{error}\n"))
+        }
+    };
+
+    if let Err(error) = http::write_response(connection, status, "text/plain; charset=utf-8", &body)
+    {
+        warn!(%error, "cannot send api response");
+    }
+}
+
+//
+// Общий чат у сервера один, и в API он называется комнатой general.
+// Сообщение, отправленное через API, рассылается так же, как сообщение от клиента,
+// только отправитель не получает его обратно с пометкой "%sent", а получает в ответе.
+// История отдается так же, как клиентам: многострочные сообщения - блоками %begin/%end.
+//
+
+fn handle_api_request(
+    connection: &TcpStream,
+    shared: &Shared,
+) -> Result<(&'static str, String), Error> {
+    let mut reader = BufReader::new(connection);
+    let request = http::read_head(&mut reader)?;
+
+    let user = request
+        .header("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|token| shared.api_tokens.get(token.trim()));
+    let Some(user) = user else {
+        return Ok((
+            "401 Unauthorized",
+            "invalid or missing api token\n".to_string(),
+        ));
+    };
+
+    let path: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    match (request.method.as_str(), path.as_slice()) {
+        ("POST", ["rooms", ROOM, "messages"]) => {
+            let text = http::read_body(&mut reader, &request, shared.limits.max_block_length)?;
+            let text = text.trim_end_matches(['\r', '\n']);
+            if text.is_empty() {
+                return Err(Error::MissingArgument);
+            }
+
+            //
+            // Сообщение из API проходит через обработчики так же, как сообщение от клиента,
+            // но заменить его можно только другим сообщением. Частоту проверяем до обработчиков,
+            // а бюджет списываем за сообщение, которое они вернули.
+            //
+
+            let address = connection.peer_addr().map_err(Error::IO)?;
+            let sender = Sender {
+                user: Some(user),
+                address,
+            };
+            let cmd = Command::text(text.to_string());
+            shared
+                .rate_limiter
+                .peek(Some(user), address.ip(), Budget::of(&cmd))
+                .map_err(Error::RateLimited)?;
+            let cmd = before_command(shared, &sender, cmd)?;
+            shared
+                .rate_limiter
+                .check(Some(user), address.ip(), Budget::of(&cmd))
+                .map_err(Error::RateLimited)?;
+            if shared.moderation.is_muted(user)? {
+                return Err(Error::Muted);
+            }
+
+            let line = match &cmd {
+                Command::MessageWithMentions(cmd) => {
+                    publish(shared, user, &cmd.message, None, &cmd.user_names)?
+                }
+                Command::Message(cmd) => publish(shared, user, &cmd.message, None, &[])?,
+                _ => return Err(Error::InvalidInput),
+            };
+            info!(%user, "message posted via api");
+
+            run_bots(shared, &sender, &cmd);
+            Ok(("201 Created", format!("{line}\n")))
+        }
+        ("POST", ["rooms", _, "messages"]) => Ok(("404 Not Found", "unknown room\n".to_string())),
+        ("GET", ["users"]) => {
+            let users = online_users(shared);
+            Ok((
+                "200 OK",
+                users.iter().map(|user| format!("{user}\n")).collect(),
+            ))
+        }
+        ("GET", ["history"]) => {
+            let since = request.query("since").map(parse_time).transpose()?;
+            let until = request.query("until").map(parse_time).transpose()?;
+            let entries = shared.history.range(since, until, MAX_HISTORY_REPLY)?;
+
+            let body: Vec<u8> = entries
+                .iter()
+                .flat_map(|entry| Framing::Lines.encode(&Frame::text(chat_line(entry))))
+                .collect();
+            Ok(("200 OK", String::from_utf8(body).unwrap()))
+        }
+        _ => Ok(("404 Not Found", "not found\n".to_string())),
+    }
+}
+
+fn api_status(error: &Error) -> &'static str {
+    match error {
+        Error::InvalidInput | Error::MissingArgument | Error::InvalidTime => "400 Bad Request",
+        Error::Muted | Error::Rejected(_) => "403 Forbidden",
+        Error::MessageTooLong => "413 Content Too Large",
+        Error::RateLimited(_) => "429 Too Many Requests",
+        _ => "500 Internal Server Error",
+    }
+}
+
+fn serve_websocket(listener: TcpListener, shared: &Arc<Shared>) {
+    for accepted in listener.incoming() {
+        if shared.shutdown.load(Ordering::SeqCst) {
+            return;
+        }
+
+        match accepted.and_then(|connection| Ok((connection.peer_addr()?, connection))) {
+            Ok((address, connection)) => {
+                accept_connection(shared, connection, address, Framing::WebSocket)
+            }
+            Err(error) => warn!(%error, "websocket accept failed"),
+        }
+    }
+}
+
+//
+// TCP-соединение, которое учитывает в метриках все принятые и отправленные через него байты.
+// Оно же помнит, строками или кадрами общается клиент, и в этом виде отправляет ему данные.
+//
+
+struct MeteredStream {
+    stream: TcpStream,
+    metrics: Arc<Metrics>,
+    framing: Framing,
+    /// Блокировка записи, общая для всех копий соединения: строка или кадр
+    /// записывается в сеть целиком, не перемешиваясь с записями других копий.
+    writing: Arc<Mutex<()>>,
+}
+
+impl MeteredStream {
+    fn new(stream: TcpStream, metrics: &Arc<Metrics>, framing: Framing) -> Self {
+        Self {
+            stream,
+            metrics: metrics.clone(),
+            framing,
+            writing: Arc::new(Mutex::new(())),
+        }
+    }
+
+    fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(Self {
+            stream: self.stream.try_clone()?,
+            metrics: self.metrics.clone(),
+            framing: self.framing,
+            writing: self.writing.clone(),
+        })
+    }
+
+    //
+    // .ok() после write_all игнорирует возможную ошибку отправки данных в сеть.
+    //
+
+    fn send(&mut self, frame: &Frame) {
+        let bytes = self.framing.encode(frame);
+        self.write_all(&bytes).ok();
+    }
+
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        self.stream.shutdown(how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+}
+
+impl Read for MeteredStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.stream.read(buf)?;
+        self.metrics.received(read);
+        Ok(read)
+    }
+}
+
+impl Write for MeteredStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let _writing = self.writing.lock().unwrap();
+        let written = self.stream.write(buf)?;
+        self.metrics.sent(written);
+        Ok(written)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        let _writing = self.writing.lock().unwrap();
+        self.stream.write_all(buf)?;
+        self.metrics.sent(buf.len());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
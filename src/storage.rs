@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use uuid::Uuid;
+
+use crate::commands::UserKind;
+use crate::error::Error;
+use crate::files;
+
+/// Хранилище зарегистрированных пользователей (тех, что добавляются командой `%add_user`).
+pub trait UserStorage: Send + Sync {
+    fn add_user(&self, id: Uuid, kind: UserKind) -> Result<(), Error>;
+
+    /// Возвращает `false`, если такого пользователя не было.
+    fn remove_user(&self, id: Uuid) -> Result<bool, Error>;
+
+    fn user_kind(&self, id: Uuid) -> Result<Option<UserKind>, Error>;
+
+    fn users(&self) -> Result<Vec<(Uuid, UserKind)>, Error>;
+
+    /// Сохраняет накопленные изменения. Вызывается при остановке сервера.
+    fn flush(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<T: UserStorage + ?Sized> UserStorage for Box<T> {
+    fn add_user(&self, id: Uuid, kind: UserKind) -> Result<(), Error> {
+        (**self).add_user(id, kind)
+    }
+
+    fn remove_user(&self, id: Uuid) -> Result<bool, Error> {
+        (**self).remove_user(id)
+    }
+
+    fn user_kind(&self, id: Uuid) -> Result<Option<UserKind>, Error> {
+        (**self).user_kind(id)
+    }
+
+    fn users(&self) -> Result<Vec<(Uuid, UserKind)>, Error> {
+        (**self).users()
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        (**self).flush()
+    }
+}
+
+/// Хранилище пользователей в памяти, теряется при перезапуске сервера.
+#[derive(Default)]
+pub struct MemoryUserStorage {
+    users: Mutex<HashMap<Uuid, UserKind>>,
+}
+
+impl MemoryUserStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl UserStorage for MemoryUserStorage {
+    fn add_user(&self, id: Uuid, kind: UserKind) -> Result<(), Error> {
+        self.users.lock().unwrap().insert(id, kind);
+        Ok(())
+    }
+
+    fn remove_user(&self, id: Uuid) -> Result<bool, Error> {
+        Ok(self.users.lock().unwrap().remove(&id).is_some())
+    }
+
+    fn user_kind(&self, id: Uuid) -> Result<Option<UserKind>, Error> {
+        Ok(self.users.lock().unwrap().get(&id).copied())
+    }
+
+    fn users(&self) -> Result<Vec<(Uuid, UserKind)>, Error> {
+        Ok(self
+            .users
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, kind)| (*id, *kind))
+            .collect())
+    }
+}
+
+/// Хранилище пользователей в текстовом файле: по одному пользователю на строку в виде `<uuid> <kind>`.
+/// Каждое изменение сразу записывается на диск.
+pub struct FileUserStorage {
+    path: PathBuf,
+    users: MemoryUserStorage,
+    writing: Mutex<()>,
+}
+
+impl FileUserStorage {
+    /// Открывает файл с пользователями. Если файла нет, хранилище будет пустым.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let path = path.into();
+        let users = MemoryUserStorage::new();
+
+        let content = files::load(&path)?;
+
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            let (id, kind) = line.split_once(' ').ok_or(Error::InvalidInput)?;
+            let id = Uuid::parse_str(id).map_err(|_| Error::InvalidUuid)?;
+            users.add_user(id, kind.parse()?)?;
+        }
+
+        Ok(Self {
+            path,
+            users,
+            writing: Mutex::new(()),
+        })
+    }
+
+    //
+    // Меняет пользователя в памяти (`None` - удаляет) и переписывает файл целиком. Пока файл пишется,
+    // другие потоки ждут, поэтому последним на диск попадает самое свежее состояние. Если записать
+    // файл не удалось, изменение в памяти откатывается, чтобы память и файл не расходились.
+    // Возвращает прежний тип пользователя.
+    //
+
+    fn change(&self, id: Uuid, kind: Option<UserKind>) -> Result<Option<UserKind>, Error> {
+        let _writing = self.writing.lock().unwrap();
+        let set = |kind: Option<UserKind>| {
+            let mut users = self.users.users.lock().unwrap();
+            match kind {
+                Some(kind) => users.insert(id, kind),
+                None => users.remove(&id),
+            }
+        };
+
+        let previous = set(kind);
+        if previous == kind {
+            return Ok(previous);
+        }
+
+        let mut content = String::new();
+        for (id, kind) in self.users()? {
+            content.push_str(&format!("{id} {kind}\n"));
+        }
+
+        if let Err(error) = files::save(&self.path, &content) {
+            set(previous);
+            return Err(error);
+        }
+        Ok(previous)
+    }
+}
+
+impl UserStorage for FileUserStorage {
+    fn add_user(&self, id: Uuid, kind: UserKind) -> Result<(), Error> {
+        self.change(id, Some(kind)).map(drop)
+    }
+
+    fn remove_user(&self, id: Uuid) -> Result<bool, Error> {
+        Ok(self.change(id, None)?.is_some())
+    }
+
+    fn user_kind(&self, id: Uuid) -> Result<Option<UserKind>, Error> {
+        self.users.user_kind(id)
+    }
+
+    fn users(&self) -> Result<Vec<(Uuid, UserKind)>, Error> {
+        self.users.users()
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use sha2::Digest;
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::commands::FileOffer;
+use crate::error::Error;
+
+/// Сколько передач файлов одновременно может начать одно соединение.
+pub const MAX_TRANSFERS_PER_CONNECTION: usize = 4;
+
+/// Передача файла от одного пользователя другому. Сервер файл не хранит: он пересылает куски
+/// получателю, а сам только считает их размер и контрольную сумму.
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    pub id: u64,
+    pub from: String,
+    /// Соединение отправителя.
+    pub sender: Uuid,
+    pub to: String,
+    /// Соединение получателя, который согласился принять файл. До согласия - `None`.
+    pub receiver: Option<Uuid>,
+    pub size: u64,
+    pub checksum: String,
+    pub name: String,
+    received: u64,
+    hasher: Sha256,
+}
+
+impl Transfer {
+    fn is_sent_by(&self, connection: Uuid) -> bool {
+        self.sender == connection
+    }
+
+    fn is_received_by(&self, connection: Uuid) -> bool {
+        self.receiver == Some(connection)
+    }
+}
+
+struct State {
+    next_id: u64,
+    transfers: HashMap<u64, Transfer>,
+}
+
+/// Передачи файлов, которые сейчас идут через сервер. Методы, которые возвращают ошибку,
+/// передачу не трогают: прервать ее после ошибки должен вызывающий, методом `remove`.
+pub struct Transfers {
+    state: Mutex<State>,
+}
+
+impl Default for Transfers {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(State {
+                next_id: 1,
+                transfers: HashMap::new(),
+            }),
+        }
+    }
+}
+
+impl Transfers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Регистрирует предложение передать файл и присваивает передаче идентификатор.
+    pub fn offer(
+        &self,
+        sender: Uuid,
+        from: &str,
+        offer: &FileOffer,
+        max_size: u64,
+    ) -> Result<Transfer, Error> {
+        if offer.size > max_size {
+            return Err(Error::FileTooLarge);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let started = state
+            .transfers
+            .values()
+            .filter(|transfer| transfer.is_sent_by(sender))
+            .count();
+        if started >= MAX_TRANSFERS_PER_CONNECTION {
+            return Err(Error::TooManyTransfers);
+        }
+
+        let transfer = Transfer {
+            id: state.next_id,
+            from: from.to_string(),
+            sender,
+            to: offer.user.clone(),
+            receiver: None,
+            size: offer.size,
+            checksum: offer.checksum.clone(),
+            name: offer.name.clone(),
+            received: 0,
+            hasher: Sha256::new(),
+        };
+        state.next_id += 1;
+        state.transfers.insert(transfer.id, transfer.clone());
+
+        Ok(transfer)
+    }
+
+    /// Получатель соглашается принять файл в соединение `connection`.
+    pub fn accept(&self, id: u64, user: &str, connection: Uuid) -> Result<Transfer, Error> {
+        let mut state = self.state.lock().unwrap();
+
+        match state.transfers.get_mut(&id) {
+            Some(transfer) if transfer.to == user && transfer.receiver.is_none() => {
+                transfer.receiver = Some(connection);
+                Ok(transfer.clone())
+            }
+            _ => Err(Error::UnknownTransfer),
+        }
+    }
+
+    /// Получатель отказывается от файла. Передача удаляется.
+    pub fn decline(&self, id: u64, user: &str) -> Result<Transfer, Error> {
+        let mut state = self.state.lock().unwrap();
+
+        match state.transfers.get(&id) {
+            Some(transfer) if transfer.to == user && transfer.receiver.is_none() => {
+                Ok(state.transfers.remove(&id).unwrap())
+            }
+            _ => Err(Error::UnknownTransfer),
+        }
+    }
+
+    /// Отправитель или получатель прерывает передачу. Передача удаляется.
+    pub fn cancel(&self, id: u64, user: &str, connection: Uuid) -> Result<Transfer, Error> {
+        let mut state = self.state.lock().unwrap();
+
+        match state.transfers.get(&id) {
+            Some(transfer)
+                if transfer.is_sent_by(connection)
+                    || transfer.is_received_by(connection)
+                    || (transfer.to == user && transfer.receiver.is_none()) =>
+            {
+                Ok(state.transfers.remove(&id).unwrap())
+            }
+            _ => Err(Error::UnknownTransfer),
+        }
+    }
+
+    /// Учитывает очередной кусок файла и возвращает соединение получателя, которому его нужно переслать.
+    pub fn chunk(&self, id: u64, connection: Uuid, data: &[u8]) -> Result<Uuid, Error> {
+        let mut state = self.state.lock().unwrap();
+
+        let Some(transfer) = state.transfers.get_mut(&id) else {
+            return Err(Error::UnknownTransfer);
+        };
+        let Some(receiver) = transfer
+            .receiver
+            .filter(|_| transfer.is_sent_by(connection))
+        else {
+            return Err(Error::UnknownTransfer);
+        };
+
+        if transfer.received + data.len() as u64 > transfer.size {
+            return Err(Error::FileTooLarge);
+        }
+
+        transfer.received += data.len() as u64;
+        transfer.hasher.update(data);
+        Ok(receiver)
+    }
+
+    /// Отправитель передал все куски. Если размер и контрольная сумма сошлись, передача удаляется.
+    pub fn end(&self, id: u64, connection: Uuid) -> Result<Transfer, Error> {
+        let mut state = self.state.lock().unwrap();
+
+        let Some(transfer) = state.transfers.get(&id) else {
+            return Err(Error::UnknownTransfer);
+        };
+        if !transfer.is_sent_by(connection) || transfer.receiver.is_none() {
+            return Err(Error::UnknownTransfer);
+        }
+
+        let checksum = format!("{:x}", transfer.hasher.clone().finalize());
+        if transfer.received != transfer.size || checksum != transfer.checksum {
+            return Err(Error::ChecksumMismatch);
+        }
+
+        Ok(state.transfers.remove(&id).unwrap())
+    }
+
+    /// Прерывает передачу после ошибки.
+    pub fn remove(&self, id: u64) -> Option<Transfer> {
+        self.state.lock().unwrap().transfers.remove(&id)
+    }
+
+    /// Удаляет передачи, в которых участвовало закрытое соединение, и возвращает их.
+    pub fn disconnected(&self, connection: Uuid) -> Vec<Transfer> {
+        let mut state = self.state.lock().unwrap();
+
+        let ids: Vec<u64> = state
+            .transfers
+            .values()
+            .filter(|transfer| {
+                transfer.is_sent_by(connection) || transfer.is_received_by(connection)
+            })
+            .map(|transfer| transfer.id)
+            .collect();
+
+        ids.iter()
+            .filter_map(|id| state.transfers.remove(id))
+            .collect()
+    }
+}
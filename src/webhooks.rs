@@ -0,0 +1,317 @@
+use std::fmt::Write;
+use std::sync::mpsc::sync_channel;
+use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::TrySendError;
+use std::thread;
+use std::thread::sleep;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use tracing::warn;
+
+use crate::commands::format_time;
+use crate::error::Error;
+use crate::history::HistoryEntry;
+use crate::http;
+use crate::http::Url;
+
+/// Общий чат - единственная комната сервера, так она называется в вебхуках и в HTTP API.
+pub const ROOM: &str = "general";
+
+/// Сколько ждать подключения к адресу вебхука и его ответа.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Больше этого между попытками не ждем, как бы ни росла задержка.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Сколько событий может ждать доставки на один адрес. Пока адрес недоступен, новые события
+/// сверх этого отбрасываются, чтобы очередь не заняла всю память.
+const MAX_QUEUE_LENGTH: usize = 1000;
+
+/// Адрес, на который сервер отправляет события чата (POST с JSON), и какие события туда нужны.
+/// По умолчанию отправляются все сообщения общего чата, а входы и выходы пользователей - нет.
+/// Фильтры сообщений (`room`, `mention`, `keyword`) сужают выборку: сообщение отправляется,
+/// только если подходит под все заданные фильтры.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    url: Url,
+    messages: bool,
+    presence: bool,
+    room: Option<String>,
+    mention: Option<String>,
+    keyword: Option<String>,
+    attempts: u32,
+    backoff: Duration,
+}
+
+impl Webhook {
+    /// Вебхук на адрес вида `http://<host>[:<port>][/<path>]`.
+    pub fn new(url: &str) -> Result<Self, Error> {
+        Ok(Self {
+            url: Url::parse(url)?,
+            messages: true,
+            presence: false,
+            room: None,
+            mention: None,
+            keyword: None,
+            attempts: 5,
+            backoff: Duration::from_millis(500),
+        })
+    }
+
+    /// Отправлять ли сообщения общего чата.
+    pub fn messages(mut self, enabled: bool) -> Self {
+        self.messages = enabled;
+        self
+    }
+
+    /// Отправлять ли входы и выходы пользователей.
+    pub fn presence(mut self, enabled: bool) -> Self {
+        self.presence = enabled;
+        self
+    }
+
+    /// Только сообщения из этой комнаты. Комната у сервера пока одна - `general`.
+    pub fn room(mut self, room: impl Into<String>) -> Self {
+        self.room = Some(room.into());
+        self
+    }
+
+    /// Только сообщения, в которых упомянут этот пользователь.
+    pub fn mention(mut self, user: impl Into<String>) -> Self {
+        self.mention = Some(user.into());
+        self
+    }
+
+    /// Только сообщения, в тексте которых есть это слово (без учета регистра).
+    pub fn keyword(mut self, keyword: impl Into<String>) -> Self {
+        self.keyword = Some(keyword.into().to_lowercase());
+        self
+    }
+
+    /// Сколько раз пытаться доставить событие и сколько ждать перед второй попыткой.
+    /// Перед каждой следующей попыткой ждем вдвое дольше.
+    pub fn retry(mut self, attempts: u32, backoff: Duration) -> Self {
+        self.attempts = attempts.max(1);
+        self.backoff = backoff;
+        self
+    }
+
+    fn matches(&self, event: &Event) -> bool {
+        match event {
+            Event::Message { entry, mentioned } => {
+                self.messages
+                    && self.room.as_ref().is_none_or(|room| room == ROOM)
+                    && self
+                        .mention
+                        .as_ref()
+                        .is_none_or(|user| mentioned.contains(user))
+                    && self
+                        .keyword
+                        .as_ref()
+                        .is_none_or(|keyword| entry.text.to_lowercase().contains(keyword))
+            }
+            Event::Join(_) | Event::Leave(_) => self.presence,
+        }
+    }
+
+    //
+    // Доставляет событие, повторяя попытки с растущей задержкой, пока адрес не ответит кодом 2xx.
+    //
+
+    fn deliver(&self, body: &str) {
+        let mut backoff = self.backoff;
+
+        for attempt in 1..=self.attempts {
+            let result = http::post(&self.url, "application/json", body, TIMEOUT);
+            match result {
+                Ok(200..=299) => return,
+                Ok(status) => warn!(url = %self.url, attempt, status, "webhook rejected event"),
+                Err(error) => warn!(url = %self.url, attempt, %error, "webhook delivery failed"),
+            }
+
+            if attempt < self.attempts {
+                sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+
+        warn!(url = %self.url, "webhook event dropped");
+    }
+}
+
+enum Event<'a> {
+    Message {
+        entry: &'a HistoryEntry,
+        mentioned: &'a [String],
+    },
+    Join(&'a str),
+    Leave(&'a str),
+}
+
+impl Event<'_> {
+    fn to_json(&self) -> String {
+        let time = format_time(SystemTime::now());
+
+        match self {
+            Self::Message { entry, mentioned } => {
+                let mentions: Vec<String> =
+                    mentioned.iter().map(|user| json_string(user)).collect();
+                let parent = entry
+                    .parent
+                    .map_or("null".to_string(), |parent| parent.to_string());
+                format!(
+                    r#"{{"event":"message","room":{},"id":{},"time":{},"parent":{parent},"from":{},"text":{},"mentions":[{}]}}"#,
+                    json_string(ROOM),
+                    entry.id,
+                    json_string(&format_time(entry.time)),
+                    json_string(&entry.from),
+                    json_string(&entry.text),
+                    mentions.join(",")
+                )
+            }
+            Self::Join(user) => format!(
+                r#"{{"event":"join","user":{},"time":{}}}"#,
+                json_string(user),
+                json_string(&time)
+            ),
+            Self::Leave(user) => format!(
+                r#"{{"event":"leave","user":{},"time":{}}}"#,
+                json_string(user),
+                json_string(&time)
+            ),
+        }
+    }
+}
+
+//
+// Строка JSON в кавычках, с экранированными кавычками, обратной косой чертой и управляющими символами.
+//
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Вебхуки сервера. У каждого вебхука свой поток доставки и своя очередь событий, поэтому
+/// недоступный адрес не задерживает ни потоки соединений, ни остальные вебхуки.
+/// События, которые не успели доставить до остановки сервера, теряются.
+/// Если очередь вебхука переполнена, новые события для него тоже теряются.
+#[derive(Default)]
+pub struct Webhooks {
+    queues: Vec<(Webhook, SyncSender<String>)>,
+}
+
+impl Webhooks {
+    /// Запускает потоки доставки.
+    pub fn start(webhooks: Vec<Webhook>) -> Result<Self, Error> {
+        let mut queues = Vec::with_capacity(webhooks.len());
+
+        for webhook in webhooks {
+            let (sender, receiver) = sync_channel::<String>(MAX_QUEUE_LENGTH);
+            let worker = webhook.clone();
+            thread::Builder::new()
+                .spawn(move || {
+                    for body in receiver {
+                        worker.deliver(&body);
+                    }
+                })
+                .map_err(Error::IO)?;
+            queues.push((webhook, sender));
+        }
+
+        Ok(Self { queues })
+    }
+
+    /// Новое сообщение общего чата.
+    pub fn message(&self, entry: &HistoryEntry, mentioned: &[String]) {
+        self.send(&Event::Message { entry, mentioned });
+    }
+
+    /// Пользователь вошел в чат (первым соединением).
+    pub fn join(&self, user: &str) {
+        self.send(&Event::Join(user));
+    }
+
+    /// Пользователь вышел из чата (закрыл последнее соединение).
+    pub fn leave(&self, user: &str) {
+        self.send(&Event::Leave(user));
+    }
+
+    fn send(&self, event: &Event) {
+        let mut body = None;
+
+        for (webhook, queue) in &self.queues {
+            if webhook.matches(event) {
+                let body = body.get_or_insert_with(|| event.to_json());
+                if let Err(TrySendError::Full(_)) = queue.try_send(body.clone()) {
+                    warn!(url = %webhook.url, "webhook queue is full, event dropped");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    #[test]
+    fn filters_and_json() {
+        let entry = HistoryEntry {
+            id: 7,
+            time: UNIX_EPOCH,
+            parent: None,
+            from: "ci".to_string(),
+            text: "Deploy \"prod\"\nfailed".to_string(),
+            edited: false,
+            reactions: BTreeMap::new(),
+        };
+        let mentioned = ["roma".to_string()];
+        let message = Event::Message {
+            entry: &entry,
+            mentioned: &mentioned,
+        };
+
+        let webhook = Webhook::new("http://localhost:9000/hook").unwrap();
+        assert!(webhook.matches(&message));
+        assert!(!webhook.matches(&Event::Join("roma")));
+        assert!(webhook
+            .clone()
+            .presence(true)
+            .matches(&Event::Leave("roma")));
+        assert!(webhook
+            .clone()
+            .keyword("DEPLOY")
+            .mention("roma")
+            .matches(&message));
+        assert!(!webhook.clone().keyword("release").matches(&message));
+        assert!(!webhook.clone().mention("alex").matches(&message));
+        assert!(!webhook.clone().room("random").matches(&message));
+        assert!(!webhook.messages(false).matches(&message));
+
+        assert_eq!(
+            message.to_json(),
+            r#"{"event":"message","room":"general","id":7,"time":"1970-01-01T00:00:00.000Z","parent":null,"from":"ci","text":"Deploy \"prod\"\nfailed","mentions":["roma"]}"#
+        );
+
+        assert!(Webhook::new("https://localhost/hook").is_err());
+        assert!(Webhook::new("http://:80/hook").is_err());
+    }
+}
@@ -0,0 +1,330 @@
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use sha1::Digest;
+use sha1::Sha1;
+
+use crate::error::Error;
+use crate::http;
+
+//
+// Минимальный WebSocket (RFC 6455) для браузерных клиентов: рукопожатие по HTTP/1.1,
+// текстовые и двоичные сообщения, в том числе разбитые на части. Расширения (сжатие) не поддерживаются.
+//
+
+/// Строка, которую по стандарту дописывают к ключу клиента перед вычислением `Sec-WebSocket-Accept`.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/// Код закрытия соединения из-за нарушения протокола.
+pub const PROTOCOL_ERROR: u16 = 1002;
+
+/// Код закрытия соединения из-за слишком длинного сообщения.
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+
+/// Больше этого служебный кадр (ping, pong, close) быть не может.
+const MAX_CONTROL_LENGTH: usize = 125;
+
+/// Сообщение WebSocket. Служебные кадры (ping, pong, close) наружу не выдаются.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Значение `Sec-WebSocket-Accept` для ключа `Sec-WebSocket-Key` клиента.
+pub fn accept_key(key: &str) -> String {
+    BASE64_STANDARD.encode(Sha1::digest(format!("{key}{GUID}")))
+}
+
+/// Принимает рукопожатие клиента и отвечает `101 Switching Protocols`. На запрос,
+/// который не просит перейти на WebSocket, отвечает `400 Bad Request` и возвращает ошибку.
+pub(crate) fn accept<S: Read + Write>(reader: &mut BufReader<S>) -> Result<(), Error> {
+    let request = http::read_head(reader)?;
+
+    let upgrade = request
+        .header("Upgrade")
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+    let key = match request.header("Sec-WebSocket-Key") {
+        Some(key) if request.method == "GET" && upgrade => key,
+        _ => {
+            let response =
+                "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+            reader
+                .get_mut()
+                .write_all(response.as_bytes())
+                .map_err(Error::IO)?;
+            return Err(Error::InvalidInput);
+        }
+    };
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    reader
+        .get_mut()
+        .write_all(response.as_bytes())
+        .map_err(Error::IO)
+}
+
+/// Сообщение одним кадром. Клиент обязан маскировать свои кадры (`mask`), сервер - нет.
+pub fn encode(message: &Message, mask: Option<[u8; 4]>) -> Vec<u8> {
+    match message {
+        Message::Text(text) => encode_frame(TEXT, text.as_bytes(), mask),
+        Message::Binary(data) => encode_frame(BINARY, data, mask),
+    }
+}
+
+/// Кадр close от сервера с кодом причины.
+pub fn close(code: u16) -> Vec<u8> {
+    encode_frame(CLOSE, &code.to_be_bytes(), None)
+}
+
+/// Код close для ошибки чтения сообщения.
+pub fn close_code(error: &Error) -> u16 {
+    match error {
+        Error::MessageTooLong | Error::LineTooLong => MESSAGE_TOO_BIG,
+        _ => PROTOCOL_ERROR,
+    }
+}
+
+fn encode_frame(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    let mut bytes = vec![0x80 | opcode];
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        length @ 0..=125 => bytes.push(mask_bit | length as u8),
+        length @ 126..=0xFFFF => {
+            bytes.push(mask_bit | 126);
+            bytes.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            bytes.push(mask_bit | 127);
+            bytes.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+
+    match mask {
+        Some(mask) => {
+            bytes.extend_from_slice(&mask);
+            bytes.extend(
+                payload
+                    .iter()
+                    .enumerate()
+                    .map(|(i, byte)| byte ^ mask[i % 4]),
+            );
+        }
+        None => bytes.extend_from_slice(payload),
+    }
+    bytes
+}
+
+/// Читает следующее сообщение клиента, собирая его из частей. На ping сразу отвечает pong,
+/// на close - таким же close, после чего возвращает `None`, как и при закрытии соединения.
+/// Немаскированный кадр - ошибка `InvalidInput`, сообщение длиннее `max_length` байт - `MessageTooLong`.
+/// После ошибки соединение нужно закрыть кадром [`close`] с кодом [`close_code`].
+pub fn read<S: Read + Write>(
+    reader: &mut BufReader<S>,
+    max_length: usize,
+) -> Result<Option<Message>, Error> {
+    let mut message: Option<(u8, Vec<u8>)> = None;
+
+    loop {
+        let mut head = [0; 2];
+        if !read_exact(reader, &mut head)? {
+            return Ok(None);
+        }
+
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        let masked = head[1] & 0x80 != 0;
+
+        let length = match head[1] & 0x7F {
+            126 => {
+                let mut length = [0; 2];
+                if !read_exact(reader, &mut length)? {
+                    return Ok(None);
+                }
+                u16::from_be_bytes(length) as u64
+            }
+            127 => {
+                let mut length = [0; 8];
+                if !read_exact(reader, &mut length)? {
+                    return Ok(None);
+                }
+                u64::from_be_bytes(length)
+            }
+            length => length as u64,
+        };
+
+        //
+        // Клиент обязан маскировать каждый кадр. Служебные кадры не делятся на части
+        // и не учитываются в длине сообщения.
+        //
+
+        if !masked {
+            return Err(Error::InvalidInput);
+        }
+        let received = message.as_ref().map_or(0, |(_, payload)| payload.len());
+        if opcode & 0x8 != 0 {
+            if !fin || length > MAX_CONTROL_LENGTH as u64 {
+                return Err(Error::InvalidInput);
+            }
+        } else if received as u64 + length > max_length as u64 {
+            return Err(Error::MessageTooLong);
+        }
+
+        let mut mask = [0; 4];
+        if !read_exact(reader, &mut mask)? {
+            return Ok(None);
+        }
+        let mut payload = vec![0; length as usize];
+        if !read_exact(reader, &mut payload)? {
+            return Ok(None);
+        }
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+
+        //
+        // Служебные кадры могут приходить между частями сообщения. В ответном close -
+        // только код причины из кадра клиента, без текста.
+        //
+
+        match (opcode, message.as_mut()) {
+            (CLOSE, _) => {
+                let code = payload.get(..2).unwrap_or_default();
+                send(reader, &encode_frame(CLOSE, code, None))?;
+                return Ok(None);
+            }
+            (PING, _) => {
+                send(reader, &encode_frame(PONG, &payload, None))?;
+                continue;
+            }
+            (PONG, _) => continue,
+            (TEXT | BINARY, None) => message = Some((opcode, payload)),
+            (CONTINUATION, Some((_, data))) => data.extend_from_slice(&payload),
+            _ => return Err(Error::InvalidInput),
+        }
+
+        if fin {
+            let (opcode, payload) = message.take().unwrap();
+            return match opcode {
+                TEXT => String::from_utf8(payload)
+                    .map(|text| Some(Message::Text(text)))
+                    .map_err(|_| Error::InvalidInput),
+                _ => Ok(Some(Message::Binary(payload))),
+            };
+        }
+    }
+}
+
+fn send<S: Read + Write>(reader: &mut BufReader<S>, bytes: &[u8]) -> Result<(), Error> {
+    reader.get_mut().write_all(bytes).map_err(Error::IO)
+}
+
+//
+// Соединение, закрытое посреди кадра, - то же, что закрытое между кадрами.
+//
+
+fn read_exact(reader: &mut impl BufRead, buf: &mut [u8]) -> Result<bool, Error> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(Error::IO(e)),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Соединение для тестов: отдает заготовленные байты и запоминает все, что в него записали.
+    pub(crate) struct Duplex {
+        input: Cursor<Vec<u8>>,
+        pub(crate) output: Vec<u8>,
+    }
+
+    impl Duplex {
+        pub(crate) fn reader(input: Vec<u8>) -> BufReader<Self> {
+            BufReader::new(Self {
+                input: Cursor::new(input),
+                output: Vec::new(),
+            })
+        }
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rfc_example() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+
+        //
+        // "Hello" из двух частей, между которыми пришел ping. Сервер отвечает на него pong
+        // и повторяет close клиента.
+        //
+
+        let mask = Some([1, 2, 3, 4]);
+        let mut first = encode_frame(TEXT, b"Hel", mask);
+        first[0] &= 0x7F;
+        let bytes = [
+            first,
+            encode_frame(PING, b"ping", mask),
+            encode_frame(CONTINUATION, b"lo", mask),
+            encode_frame(CLOSE, &1000u16.to_be_bytes(), mask),
+        ]
+        .concat();
+        let mut reader = Duplex::reader(bytes.clone());
+
+        assert_eq!(
+            read(&mut reader, 16).unwrap(),
+            Some(Message::Text("Hello".to_string()))
+        );
+        assert_eq!(reader.get_ref().output, encode_frame(PONG, b"ping", None));
+        assert_eq!(read(&mut reader, 16).unwrap(), None);
+        assert!(reader.get_ref().output.ends_with(&close(1000)));
+        assert!(matches!(
+            read(&mut Duplex::reader(bytes), 2),
+            Err(Error::MessageTooLong)
+        ));
+
+        //
+        // Немаскированный кадр клиента - нарушение протокола.
+        //
+
+        let unmasked = encode(&Message::Text("Hello".to_string()), None);
+        let error = read(&mut Duplex::reader(unmasked), 16).unwrap_err();
+        assert_eq!(close_code(&error), PROTOCOL_ERROR);
+    }
+}
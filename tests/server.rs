@@ -0,0 +1,1933 @@
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::thread::spawn;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use sha2::Digest;
+use sha2::Sha256;
+use simple_chat::audit::FileAuditLog;
+use simple_chat::bots::Bot;
+use simple_chat::bots::BotReply;
+use simple_chat::bots::EchoBot;
+use simple_chat::bots::Sender;
+use simple_chat::bots::TimeBot;
+use simple_chat::codec::Frame;
+use simple_chat::codec::Framing;
+use simple_chat::codec::FRAMED;
+use simple_chat::commands::parse_time;
+use simple_chat::commands::BanTarget;
+use simple_chat::commands::Command;
+use simple_chat::commands::FileChunk;
+use simple_chat::commands::Hello;
+use simple_chat::commands::UserKind;
+use simple_chat::direct_messages::DirectMessageEntry;
+use simple_chat::direct_messages::DirectMessageStorage;
+use simple_chat::direct_messages::FileDirectMessageStorage;
+use simple_chat::error::Error;
+use simple_chat::history::FileHistoryStorage;
+use simple_chat::history::HistoryEntry;
+use simple_chat::history::HistoryStorage;
+use simple_chat::hooks::Action;
+use simple_chat::hooks::Hook;
+use simple_chat::moderation::FileModerationStorage;
+use simple_chat::moderation::ModerationStorage;
+use simple_chat::rate_limit::Rate;
+use simple_chat::rate_limit::RateLimits;
+use simple_chat::rate_limit::Rates;
+use simple_chat::server::ChatServer;
+use simple_chat::server::ChatServerBuilder;
+use simple_chat::server::Limits;
+use simple_chat::storage::FileUserStorage;
+use simple_chat::storage::MemoryUserStorage;
+use simple_chat::storage::UserStorage;
+use simple_chat::webhooks::Webhook;
+use simple_chat::websocket;
+use simple_chat::websocket::Message;
+use uuid::Uuid;
+
+struct Client {
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    fn connect(server: &ChatServer) -> Self {
+        let connection = TcpStream::connect(server.local_addr()).unwrap();
+        connection
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        Self {
+            reader: BufReader::new(connection),
+        }
+    }
+
+    fn send(&mut self, line: &str) {
+        self.reader
+            .get_mut()
+            .write_all(format!("{line}\n").as_bytes())
+            .unwrap();
+    }
+
+    /// Логинится и дожидается, пока сервер обработает команду.
+    fn login(&mut self, id: &str) {
+        self.send(&format!("%login {id}"));
+        self.send("%show_users");
+        self.receive();
+    }
+
+    /// Строка от сервера. Время в сообщениях общего чата (`#<id> <время> ...`) заранее неизвестно,
+    /// поэтому оно вырезается; строку целиком возвращает `receive_raw`.
+    fn receive(&mut self) -> String {
+        let line = self.receive_raw();
+
+        let Some(start) = line.find('#') else {
+            return line;
+        };
+        let (head, rest) = line.split_at(start);
+        let mut parts = rest.splitn(3, ' ');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(id), Some(time), Some(rest)) if parse_time(time).is_ok() => {
+                format!("{head}{id} {rest}")
+            }
+            _ => line,
+        }
+    }
+
+    fn receive_raw(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        line.trim_end().to_string()
+    }
+}
+
+/// Клиент WebSocket, прошедший рукопожатие.
+fn websocket_client(address: SocketAddr) -> Client {
+    let connection = TcpStream::connect(address).unwrap();
+    connection
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut client = Client {
+        reader: BufReader::new(connection),
+    };
+    client
+        .reader
+        .get_mut()
+        .write_all(
+            b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+    assert_eq!(client.receive_raw(), "HTTP/1.1 101 Switching Protocols");
+    while !client.receive_raw().is_empty() {}
+    client
+}
+
+/// Кадр от сервера: первый байт (FIN и opcode) и данные. Сервер кадры не маскирует.
+fn websocket_frame(client: &mut Client) -> (u8, Vec<u8>) {
+    let mut head = [0; 2];
+    client.reader.read_exact(&mut head).unwrap();
+    let length = match head[1] {
+        126 => {
+            let mut length = [0; 2];
+            client.reader.read_exact(&mut length).unwrap();
+            u16::from_be_bytes(length) as usize
+        }
+        127 => {
+            let mut length = [0; 8];
+            client.reader.read_exact(&mut length).unwrap();
+            u64::from_be_bytes(length) as usize
+        }
+        length => length as usize,
+    };
+    let mut payload = vec![0; length];
+    client.reader.read_exact(&mut payload).unwrap();
+    (head[0], payload)
+}
+
+/// Сервер на свободном порту. Все тестовые клиенты подключаются с одного адреса,
+/// но у каждого пользователя свой бюджет, поэтому ограничения частоты - как по умолчанию.
+fn builder() -> ChatServerBuilder {
+    ChatServer::builder()
+        .address("127.0.0.1:0")
+        .admin_token(ADMIN_TOKEN)
+}
+
+/// Токен, которым в тестах входят администраторы.
+const ADMIN_TOKEN: &str = "secret";
+
+fn start(server: ChatServer) -> (Arc<ChatServer>, JoinHandle<()>) {
+    let server = Arc::new(server);
+    let handle = {
+        let server = server.clone();
+        spawn(move || server.run().unwrap())
+    };
+    (server, handle)
+}
+
+#[test]
+fn broadcast_and_show_users() {
+    let (server, handle) = start(builder().build().unwrap());
+    assert_ne!(server.local_addr().port(), 0);
+
+    let mut alex = Client::connect(&server);
+    let mut roma = Client::connect(&server);
+    alex.login("alex");
+    roma.login("roma");
+
+    roma.send("%show_users");
+    assert_eq!(roma.receive(), "%users alex roma");
+
+    alex.send("Привет!");
+    assert_eq!(alex.receive(), "%join roma");
+    assert_eq!(alex.receive(), "%sent #1 alex: Привет!");
+    assert_eq!(roma.receive(), "#1 alex: Привет!");
+
+    server.shutdown();
+    handle.join().unwrap();
+    assert_eq!(roma.receive(), "%notice server shutting down");
+    assert_eq!(roma.receive(), "");
+}
+
+#[test]
+fn only_admins_manage_users() {
+    let admin = Uuid::new_v4();
+    let users = MemoryUserStorage::new();
+    users.add_user(admin, UserKind::Admin).unwrap();
+
+    let (server, handle) = start(builder().user_storage(users).build().unwrap());
+
+    let mut client = Client::connect(&server);
+    client.send("%login guest");
+    client.send(&format!("%add_user {} normal", Uuid::new_v4()));
+    assert_eq!(client.receive(), "permission denied");
+
+    // знать uuid администратора недостаточно: без токена или с чужим токеном войти нельзя
+    client.send(&format!("%login {admin}"));
+    assert_eq!(client.receive(), "%error invalid credentials");
+    client.send(&format!("%login {admin} guess"));
+    assert_eq!(client.receive(), "%error invalid credentials");
+    client.send("%kick guest");
+    assert_eq!(client.receive(), "permission denied");
+    client.send("%stats");
+    assert_eq!(client.receive(), "permission denied");
+
+    client.login(&format!("{admin} {ADMIN_TOKEN}"));
+    client.send(&format!("%remove_user {admin}"));
+    client.send(&format!("%add_user {} normal", Uuid::new_v4()));
+    assert_eq!(client.receive(), "permission denied");
+
+    server.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn users_are_saved() {
+    let path = std::env::temp_dir().join(format!("simple-chat-users-{}", Uuid::new_v4()));
+    let admin = Uuid::new_v4();
+    let user = Uuid::new_v4();
+
+    let users = FileUserStorage::open(&path).unwrap();
+    users.add_user(admin, UserKind::Admin).unwrap();
+
+    let (server, handle) = start(builder().user_storage(users).build().unwrap());
+
+    let mut client = Client::connect(&server);
+    client.login(&format!("{admin} {ADMIN_TOKEN}"));
+    client.send(&format!("%add_user {user} normal"));
+    client.send("%show_users");
+    client.receive();
+
+    // пользователь записан на диск сразу, еще до остановки сервера
+    let saved = FileUserStorage::open(&path).unwrap();
+    assert_eq!(saved.user_kind(user).unwrap(), Some(UserKind::Normal));
+
+    server.shutdown();
+    handle.join().unwrap();
+
+    let users = FileUserStorage::open(&path).unwrap();
+    assert_eq!(users.user_kind(admin).unwrap(), Some(UserKind::Admin));
+    assert_eq!(users.user_kind(user).unwrap(), Some(UserKind::Normal));
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Пользователи, которых нельзя изменить: любая запись заканчивается ошибкой.
+struct ReadOnlyUsers(MemoryUserStorage);
+
+impl UserStorage for ReadOnlyUsers {
+    fn add_user(&self, _id: Uuid, _kind: UserKind) -> Result<(), Error> {
+        Err(Error::IO(std::io::Error::other("disk is full")))
+    }
+
+    fn remove_user(&self, _id: Uuid) -> Result<bool, Error> {
+        Err(Error::IO(std::io::Error::other("disk is full")))
+    }
+
+    fn user_kind(&self, id: Uuid) -> Result<Option<UserKind>, Error> {
+        self.0.user_kind(id)
+    }
+
+    fn users(&self) -> Result<Vec<(Uuid, UserKind)>, Error> {
+        self.0.users()
+    }
+}
+
+#[test]
+fn storage_errors() {
+    let admin = Uuid::new_v4();
+    let users = MemoryUserStorage::new();
+    users.add_user(admin, UserKind::Admin).unwrap();
+
+    let (server, handle) = start(
+        builder()
+            .user_storage(ReadOnlyUsers(users))
+            .build()
+            .unwrap(),
+    );
+
+    // ошибка хранилища приходит в ответ, а соединение остается открытым
+    let mut client = Client::connect(&server);
+    client.login(&format!("{admin} {ADMIN_TOKEN}"));
+    client.send(&format!("%add_user {} normal", Uuid::new_v4()));
+    assert_eq!(client.receive(), "%error input/output error: disk is full");
+    client.send("%show_users");
+    assert_eq!(client.receive(), format!("%users {admin}"));
+
+    server.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn audit_log() {
+    let path = std::env::temp_dir().join(format!("simple-chat-audit-{}", Uuid::new_v4()));
+    let admin = Uuid::new_v4();
+    let user = Uuid::new_v4();
+
+    let users = MemoryUserStorage::new();
+    users.add_user(admin, UserKind::Admin).unwrap();
+
+    let (server, handle) = start(
+        builder()
+            .user_storage(users)
+            .audit_log(FileAuditLog::open(&path).unwrap())
+            .build()
+            .unwrap(),
+    );
+
+    let mut client = Client::connect(&server);
+    client.login(&format!("{admin} {ADMIN_TOKEN}"));
+    client.send(&format!("%add_user {user} normal"));
+    client.send(&format!("%remove_user {user}"));
+    client.send("%kick nobody");
+    assert_eq!(client.receive(), "unknown user");
+
+    // чужие попытки выполнить административную команду в журнал не попадают
+    let mut other = Client::connect(&server);
+    other.login(&user.to_string());
+    other.send(&format!("%remove_user {admin}"));
+    assert_eq!(other.receive(), "permission denied");
+
+    server.shutdown();
+    handle.join().unwrap();
+
+    let content = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = content.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].ends_with(&format!("Z {admin} 127.0.0.1 %add_user {user} normal")));
+    assert!(lines[1].ends_with(&format!("Z {admin} 127.0.0.1 %remove_user {user}")));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn presence_events() {
+    let (server, handle) = start(builder().build().unwrap());
+
+    let mut alex = Client::connect(&server);
+    let mut roma = Client::connect(&server);
+    alex.login("alex");
+    roma.login("roma");
+    assert_eq!(alex.receive(), "%join roma");
+
+    roma.send("%bye");
+    assert_eq!(roma.receive(), "");
+    assert_eq!(alex.receive(), "%leave roma");
+
+    server.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn moderation() {
+    let admin = Uuid::new_v4();
+    let users = MemoryUserStorage::new();
+    users.add_user(admin, UserKind::Admin).unwrap();
+
+    let (server, handle) = start(builder().user_storage(users).build().unwrap());
+
+    let mut moderator = Client::connect(&server);
+    let mut alex = Client::connect(&server);
+    moderator.login(&format!("{admin} {ADMIN_TOKEN}"));
+    alex.login("alex");
+    assert_eq!(moderator.receive(), "%join alex");
+
+    alex.send("%kick alex");
+    assert_eq!(alex.receive(), "permission denied");
+
+    moderator.send("%mute alex 10m");
+    moderator.send("%show_users");
+    moderator.receive();
+
+    alex.send("Привет!");
+    assert_eq!(alex.receive(), "muted");
+
+    moderator.send("%kick alex флуд");
+    assert_eq!(alex.receive(), "%notice you were kicked: флуд");
+    assert_eq!(alex.receive(), "");
+    assert_eq!(moderator.receive(), "%leave alex");
+
+    moderator.send("%ban alex 1h");
+    moderator.send("%show_users");
+    moderator.receive();
+
+    let mut alex = Client::connect(&server);
+    alex.send("%login alex");
+    assert_eq!(alex.receive(), "banned");
+    assert_eq!(alex.receive(), "");
+
+    server.shutdown();
+    handle.join().unwrap();
+}
+
+/// Баны и мьюты записываются на диск сразу, а не только при остановке сервера.
+#[test]
+fn moderation_survives_crash() {
+    let path = std::env::temp_dir().join(format!("simple-chat-moderation-{}", Uuid::new_v4()));
+
+    let moderation = FileModerationStorage::open(&path).unwrap();
+    moderation
+        .ban(BanTarget::User("alex".to_string()), None)
+        .unwrap();
+    moderation.mute("roma".to_string(), None).unwrap();
+    moderation.mute("vova".to_string(), None).unwrap();
+    moderation.unmute("vova").unwrap();
+
+    // без flush, как после аварийной остановки
+    let reopened = FileModerationStorage::open(&path).unwrap();
+    assert!(reopened
+        .is_banned(&BanTarget::User("alex".to_string()))
+        .unwrap());
+    assert!(reopened.is_muted("roma").unwrap());
+    assert!(!reopened.is_muted("vova").unwrap());
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Если файл записать не удалось, изменение не остается и в памяти.
+#[test]
+fn failed_saves_roll_back() {
+    let path = std::env::temp_dir()
+        .join(format!("simple-chat-missing-{}", Uuid::new_v4()))
+        .join("storage");
+    let user = Uuid::new_v4();
+
+    let users = FileUserStorage::open(&path).unwrap();
+    assert!(users.add_user(user, UserKind::Normal).is_err());
+    assert_eq!(users.user_kind(user).unwrap(), None);
+
+    let moderation = FileModerationStorage::open(&path).unwrap();
+    let alex = BanTarget::User("alex".to_string());
+    assert!(moderation.ban(alex.clone(), None).is_err());
+    assert!(!moderation.is_banned(&alex).unwrap());
+    assert!(moderation.mute("roma".to_string(), None).is_err());
+    assert!(!moderation.is_muted("roma").unwrap());
+}
+
+/// Личные сообщения дописываются в файл сразу, а `flush` только переписывает его заново.
+#[test]
+fn direct_messages_survive_crash() {
+    let path = std::env::temp_dir().join(format!("simple-chat-dms-{}", Uuid::new_v4()));
+    let entry = |from: &str, to: &str, text: &str| DirectMessageEntry {
+        from: from.to_string(),
+        to: to.to_string(),
+        text: text.to_string(),
+    };
+
+    let dms = FileDirectMessageStorage::open(&path).unwrap();
+    dms.add(entry("alex", "roma", "привет")).unwrap();
+    dms.add(entry("roma", "alex", "и тебе привет")).unwrap();
+    assert!(matches!(
+        dms.add(entry("ci bot", "alex", "испортил бы файл")),
+        Err(Error::InvalidInput)
+    ));
+
+    let reopened = FileDirectMessageStorage::open(&path).unwrap();
+    assert_eq!(
+        reopened.conversation("alex", "roma").unwrap(),
+        [
+            entry("alex", "roma", "привет"),
+            entry("roma", "alex", "и тебе привет")
+        ]
+    );
+
+    reopened.add(entry("alex", "vova", "как дела?")).unwrap();
+    reopened.flush().unwrap();
+    let reopened = FileDirectMessageStorage::open(&path).unwrap();
+    assert_eq!(reopened.conversations("alex").unwrap(), ["roma", "vova"]);
+    assert_eq!(reopened.conversation("roma", "alex").unwrap().len(), 2);
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Изменения истории дописываются в файл сразу и применяются по порядку при открытии.
+#[test]
+fn history_survives_crash() {
+    let path = std::env::temp_dir().join(format!("simple-chat-history-{}", Uuid::new_v4()));
+
+    let history = FileHistoryStorage::open(&path).unwrap();
+    history.add("alex", "Привет", None).unwrap();
+    history.add("roma", "Пицца\nили суши?", Some(1)).unwrap();
+    history.add("alex", "удалю", None).unwrap();
+    history.react(2, "alex", "🍕").unwrap();
+    history.react(2, "alex", "🍣").unwrap();
+    history.unreact(2, "alex", "🍣").unwrap();
+    history.edit(2, "Пицца\nили роллы?").unwrap();
+    history.delete(3).unwrap();
+    assert!(matches!(
+        history.add("ci bot", "испортил бы файл", None),
+        Err(Error::InvalidInput)
+    ));
+
+    // без flush, как после аварийной остановки
+    let reopened = FileHistoryStorage::open(&path).unwrap();
+    let entry = reopened.get(2).unwrap().unwrap();
+    assert_eq!(entry.text, "Пицца\nили роллы?");
+    assert!(entry.edited);
+    assert_eq!(entry.parent, Some(1));
+    assert_eq!(entry.reactions.keys().collect::<Vec<_>>(), ["🍕"]);
+    assert!(reopened.get(3).unwrap().is_none());
+    assert_eq!(reopened.add("vova", "я тут", None).unwrap().id, 4);
+
+    reopened.flush().unwrap();
+    let reopened = FileHistoryStorage::open(&path).unwrap();
+    assert_eq!(reopened.range(None, None, 10).unwrap().len(), 3);
+    assert_eq!(reopened.get(2).unwrap().unwrap(), entry);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn rate_limits() {
+    let (server, handle) = start(
+        ChatServer::builder()
+            .address("127.0.0.1:0")
+            .limits(Limits {
+                rate: RateLimits {
+                    user: Rates {
+                        messages: Rate {
+                            burst: 2,
+                            per_second: 0.5,
+                        },
+                        ..RateLimits::default().user
+                    },
+                    violations: Rate {
+                        burst: 2,
+                        per_second: 0.0,
+                    },
+                    ..RateLimits::default()
+                },
+                ..Limits::default()
+            })
+            .build()
+            .unwrap(),
+    );
+
+    let mut alex = Client::connect(&server);
+    alex.login("alex");
+    let mut roma = Client::connect(&server);
+    roma.login("roma");
+    assert_eq!(alex.receive(), "%join roma");
+
+    for id in 1..=2 {
+        alex.send("флуд");
+        assert!(alex.receive().starts_with("%sent "));
+        assert_eq!(roma.receive(), format!("#{id} alex: флуд"));
+    }
+
+    alex.send("флуд");
+    assert_eq!(alex.receive(), "rate limited, retry after 2s");
+
+    // у пользователя с того же адреса свой бюджет
+    roma.send("а я могу");
+    assert_eq!(roma.receive(), "%sent #3 roma: а я могу");
+    assert_eq!(alex.receive(), "#3 roma: а я могу");
+
+    alex.send("флуд");
+    assert!(alex.receive().starts_with("rate limited"));
+
+    alex.send("флуд");
+    assert_eq!(alex.receive(), "%notice disconnected for flooding");
+    assert_eq!(alex.receive(), "");
+
+    server.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn rewritten_command_budget() {
+    let (server, handle) = start(
+        builder()
+            .hook(Summon)
+            .limits(Limits {
+                rate: RateLimits {
+                    user: Rates {
+                        mentions: Rate {
+                            burst: 1,
+                            per_second: 0.0,
+                        },
+                        ..RateLimits::default().user
+                    },
+                    ..RateLimits::default()
+                },
+                ..Limits::default()
+            })
+            .build()
+            .unwrap(),
+    );
+
+    let mut alex = Client::connect(&server);
+    alex.login("alex");
+
+    // команда, которую вернул обработчик, тратит бюджет упоминаний, а не обычных сообщений
+    alex.send("!all");
+    assert_eq!(alex.receive(), "%sent #1 alex: @roma, зайди");
+    alex.send("!all");
+    assert!(alex.receive().starts_with("rate limited"));
+    alex.send("привет");
+    assert_eq!(alex.receive(), "%sent #2 alex: привет");
+
+    server.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn connection_limits() {
+    let (server, handle) = start(
+        builder()
+            .limits(Limits {
+                max_connections_per_ip: 2,
+                login_timeout: Duration::from_millis(300),
+                ..Limits::default()
+            })
+            .build()
+            .unwrap(),
+    );
+
+    let mut alex = Client::connect(&server);
+    alex.login("alex");
+    let mut silent = Client::connect(&server);
+
+    let mut rejected = Client::connect(&server);
+    assert_eq!(rejected.receive(), "too many connections");
+    assert_eq!(rejected.receive(), "");
+
+    // кто не залогинился вовремя, того отключают, и место освобождается
+    assert_eq!(silent.receive(), "login timed out");
+    assert_eq!(silent.receive(), "");
+
+    let mut roma = Client::connect(&server);
+    roma.login("roma");
+    alex.send("%show_users");
+    assert_eq!(alex.receive(), "%join roma");
+    assert_eq!(alex.receive(), "%users alex roma");
+
+    server.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn metrics() {
+    let admin = Uuid::new_v4();
+    let users = MemoryUserStorage::new();
+    users.add_user(admin, UserKind::Admin).unwrap();
+
+    let (server, handle) = start(
+        builder()
+            .user_storage(users)
+            .metrics_address("127.0.0.1:0")
+            .build()
+            .unwrap(),
+    );
+
+    let mut moderator = Client::connect(&server);
+    let mut alex = Client::connect(&server);
+    moderator.login(&format!("{admin} {ADMIN_TOKEN}"));
+    alex.login("alex");
+    assert_eq!(moderator.receive(), "%join alex");
+
+    alex.send("%nope");
+    assert_eq!(alex.receive(), "unknown command");
+    alex.send("Привет!");
+    assert_eq!(alex.receive(), "%sent #1 alex: Привет!");
+    assert_eq!(moderator.receive(), "#1 alex: Привет!");
+
+    alex.send("%stats");
+    assert_eq!(alex.receive(), "permission denied");
+
+    moderator.send("%stats");
+    let stats = moderator.receive();
+    assert!(
+        stats.starts_with("%stats connections=2 users=2 messages=1 "),
+        "{stats}"
+    );
+    assert!(stats.contains(" parse_errors_unknown_command=1"), "{stats}");
+
+    let mut http = TcpStream::connect(server.metrics_addr().unwrap()).unwrap();
+    http.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    http.read_to_string(&mut response).unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    for line in [
+        "chat_connections 2",
+        "chat_users 2",
+        "chat_messages_total 1",
+        "chat_parse_errors_total{error=\"unknown_command\"} 1",
+        "chat_broadcast_duration_seconds_count 1",
+    ] {
+        assert!(response.lines().any(|l| l == line), "{line}");
+    }
+
+    server.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn direct_messages() {
+    let (server, handle) = start(builder().build().unwrap());
+
+    let mut anonymous = Client::connect(&server);
+    let mut alex = Client::connect(&server);
+    let mut roma = Client::connect(&server);
+    let mut vova = Client::connect(&server);
+    alex.login("alex");
+    roma.login("roma");
+    vova.login("vova");
+
+    anonymous.send("%dm roma привет");
+    assert_eq!(anonymous.receive(), "not logged in");
+
+    alex.send("%dm roma только для тебя");
+    assert_eq!(roma.receive(), "%join vova");
+    assert_eq!(roma.receive(), "%dm alex roma только для тебя");
+
+    // остальные личное сообщение не видят
+    vova.send("%show_users");
+    assert_eq!(vova.receive(), "%users alex roma vova");
+
+    roma.send("%dm alex понял");
+    assert_eq!(roma.receive(), "%dm roma alex понял");
+    assert_eq!(alex.receive(), "%join roma");
+    assert_eq!(alex.receive(), "%join vova");
+    assert_eq!(alex.receive(), "%dm alex roma только для тебя");
+    assert_eq!(alex.receive(), "%dm roma alex понял");
+
+    // писать можно только тем, кто в сети или зарегистрирован
+    alex.send("%dm nobody привет");
+    assert_eq!(alex.receive(), "unknown user");
+
+    alex.send("%dms");
+    assert_eq!(alex.receive(), "%dms roma");
+    vova.send("%dms");
+    assert_eq!(vova.receive(), "%dms");
+
+    roma.send("%dms alex");
+    assert_eq!(roma.receive(), "%dm alex roma только для тебя");
+    assert_eq!(roma.receive(), "%dm roma alex понял");
+
+    server.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn mentions() {
+    let (server, handle) = start(builder().build().unwrap());
+
+    let mut alex = Client::connect(&server);
+    let mut roma = Client::connect(&server);
+    let mut vova = Client::connect(&server);
+    alex.login("alex");
+    roma.login("roma");
+    vova.login("vova");
+    assert_eq!(roma.receive(), "%join vova");
+
+    alex.send("Привет, @roma! Зайди к @nobody");
+    assert_eq!(
+        roma.receive(),
+        "%mention #1 alex: Привет, @roma! Зайди к @nobody"
+    );
+    assert_eq!(vova.receive(), "#1 alex: Привет, @roma! Зайди к @nobody");
+
+    roma.send("%mentions");
+    assert_eq!(roma.receive(), "%mentions 1");
+    assert_eq!(
+        roma.receive(),
+        "%mention #1 alex: Привет, @roma! Зайди к @nobody"
+    );
+
+    // прочитанные упоминания повторно не выдаются
+    roma.send("%mentions");
+    assert_eq!(roma.receive(), "%mentions 0");
+
+    // тому, кого не было в сети и кто не зарегистрирован, упоминания не сохраняются
+    let mut nobody = Client::connect(&server);
+    nobody.login("nobody");
+    nobody.send("%mentions");
+    assert_eq!(nobody.receive(), "%mentions 0");
+
+    server.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn edit_and_delete() {
+    let admin = Uuid::new_v4();
+    let users = MemoryUserStorage::new();
+    users.add_user(admin, UserKind::Admin).unwrap();
+
+    let (server, handle) = start(builder().user_storage(users).build().unwrap());
+
+    let mut moderator = Client::connect(&server);
+    let mut alex = Client::connect(&server);
+    let mut roma = Client::connect(&server);
+    moderator.login(&format!("{admin} {ADMIN_TOKEN}"));
+    alex.login("alex");
+    roma.login("roma");
+    assert_eq!(alex.receive(), "%join roma");
+
+    alex.send("Привет, мир!");
+    assert_eq!(alex.receive(), "%sent #1 alex: Привет, мир!");
+    assert_eq!(roma.receive(), "#1 alex: Привет, мир!");
+    roma.send("А я второй");
+    assert_eq!(roma.receive(), "%sent #2 roma: А я второй");
+    assert_eq!(alex.receive(), "#2 roma: А я второй");
+
+    // исправлять и удалять чужие сообщения нельзя
+    roma.send("%edit 1 взлом");
+    assert_eq!(roma.receive(), "permission denied");
+    roma.send("%delete 1");
+    assert_eq!(roma.receive(), "permission denied");
+    roma.send("%edit 100 нет такого");
+    assert_eq!(roma.receive(), "unknown message");
+
+    alex.send("%edit 1 Привет, чат!");
+    assert_eq!(alex.receive(), "%edit 1 Привет, чат!");
+    assert_eq!(roma.receive(), "%edit 1 Привет, чат!");
+
+    alex.send("%delete 1");
+    assert_eq!(alex.receive(), "%delete 1");
+    assert_eq!(roma.receive(), "%delete 1");
+
+    // администратор может удалить любое сообщение
+    moderator.send("%delete 2");
+    assert_eq!(roma.receive(), "%delete 2");
+    moderator.send("%delete 2");
+    assert_eq!(moderator.receive(), "%join alex");
+    assert_eq!(moderator.receive(), "%join roma");
+    assert_eq!(moderator.receive(), "#1 alex: Привет, мир!");
+    assert_eq!(moderator.receive(), "#2 roma: А я второй");
+    assert_eq!(moderator.receive(), "%edit 1 Привет, чат!");
+    assert_eq!(moderator.receive(), "%delete 1");
+    assert_eq!(moderator.receive(), "%delete 2");
+    assert_eq!(moderator.receive(), "unknown message");
+
+    server.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn replies_and_threads() {
+    let (server, handle) = start(builder().build().unwrap());
+
+    let mut alex = Client::connect(&server);
+    let mut roma = Client::connect(&server);
+    alex.login("alex");
+    roma.login("roma");
+    assert_eq!(alex.receive(), "%join roma");
+
+    alex.send("Кто идет обедать?");
+    assert_eq!(alex.receive(), "%sent #1 alex: Кто идет обедать?");
+    assert_eq!(roma.receive(), "#1 alex: Кто идет обедать?");
+    roma.send("Отдельная тема");
+    assert_eq!(roma.receive(), "%sent #2 roma: Отдельная тема");
+    assert_eq!(alex.receive(), "#2 roma: Отдельная тема");
+
+    roma.send("%reply 1 Я иду");
+    assert_eq!(roma.receive(), "%sent #3 ^1 roma: Я иду");
+    assert_eq!(alex.receive(), "#3 ^1 roma: Я иду");
+
+    // упомянутые в ответе получают его с пометкой
+    alex.send("%reply 3 @roma тогда в час");
+    assert_eq!(alex.receive(), "%sent #4 ^3 alex: @roma тогда в час");
+    assert_eq!(roma.receive(), "%mention #4 ^3 alex: @roma тогда в час");
+
+    roma.send("%reply 100 нет такого");
+    assert_eq!(roma.receive(), "unknown message");
+
+    // ветку можно запросить по любому сообщению из нее
+    roma.send("%thread 4");
+    assert_eq!(roma.receive(), "%thread 3");
+    assert_eq!(roma.receive(), "#1 alex: Кто идет обедать?");
+    assert_eq!(roma.receive(), "#3 ^1 roma: Я иду");
+    assert_eq!(roma.receive(), "#4 ^3 alex: @roma тогда в час");
+
+    roma.send("%thread 2");
+    assert_eq!(roma.receive(), "%thread 1");
+    assert_eq!(roma.receive(), "#2 roma: Отдельная тема");
+
+    server.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn reactions() {
+    let (server, handle) = start(builder().build().unwrap());
+
+    let mut alex = Client::connect(&server);
+    let mut roma = Client::connect(&server);
+    alex.login("alex");
+    roma.login("roma");
+    assert_eq!(alex.receive(), "%join roma");
+
+    alex.send("Пицца или суши?");
+    assert_eq!(alex.receive(), "%sent #1 alex: Пицца или суши?");
+    assert_eq!(roma.receive(), "#1 alex: Пицца или суши?");
+
+    roma.send("%react 1 🍕");
+    assert_eq!(roma.receive(), "%reaction 1 🍕 1");
+    assert_eq!(alex.receive(), "%reaction 1 🍕 1");
+
+    // каждой реакцией пользователь отмечает сообщение только один раз
+    roma.send("%react 1 🍕");
+    alex.send("%react 1 🍕");
+    assert_eq!(alex.receive(), "%reaction 1 🍕 2");
+    assert_eq!(roma.receive(), "%reaction 1 🍕 2");
+
+    roma.send("%react 100 🍕");
+    assert_eq!(roma.receive(), "unknown message");
+
+    roma.send("%unreact 1 🍕");
+    assert_eq!(roma.receive(), "%reaction 1 🍕 1");
+    assert_eq!(alex.receive(), "%reaction 1 🍕 1");
+
+    // текущие реакции приходят вместе с веткой
+    roma.send("%thread 1");
+    assert_eq!(roma.receive(), "%thread 1");
+    assert_eq!(roma.receive(), "#1 alex: Пицца или суши?");
+    assert_eq!(roma.receive(), "%reaction 1 🍕 1");
+
+    server.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn history_by_time() {
+    let (server, handle) = start(builder().build().unwrap());
+
+    let mut alex = Client::connect(&server);
+    alex.login("alex");
+
+    //
+    // Сервер ставит время на каждое сообщение. Между сообщениями ждем, чтобы время у них было разным.
+    //
+
+    let mut times = Vec::new();
+    for text in ["раз", "два", "три"] {
+        alex.send(text);
+        let line = alex.receive_raw();
+        let time = line.split(' ').nth(2).unwrap().to_string();
+        assert!(parse_time(&time).is_ok(), "{line}");
+        times.push(time);
+        sleep(Duration::from_millis(20));
+    }
+    assert!(parse_time(&times[0]).unwrap() < parse_time(&times[1]).unwrap());
+
+    alex.send("%history");
+    assert_eq!(alex.receive(), "%history 3");
+    assert_eq!(alex.receive(), "#1 alex: раз");
+    assert_eq!(alex.receive(), "#2 alex: два");
+    assert_eq!(alex.receive(), "#3 alex: три");
+
+    alex.send(&format!("%history {}", times[1]));
+    assert_eq!(alex.receive(), "%history 2");
+    assert_eq!(alex.receive(), "#2 alex: два");
+    assert_eq!(alex.receive(), "#3 alex: три");
+
+    alex.send(&format!("%history - {}", times[1]));
+    assert_eq!(alex.receive(), "%history 1");
+    assert_eq!(alex.receive(), "#1 alex: раз");
+
+    alex.send(&format!("%history {} {}", times[0], times[2]));
+    assert_eq!(alex.receive(), "%history 2");
+    assert_eq!(alex.receive(), "#1 alex: раз");
+    assert_eq!(alex.receive(), "#2 alex: два");
+
+    alex.send("%history вчера");
+    assert_eq!(alex.receive(), "invalid time");
+
+    server.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn same_order_for_everyone() {
+    //
+    // Каждый из пишущих отправляет больше сообщений, чем пользователю разрешено подряд по умолчанию.
+    //
+
+    let unlimited = Rate {
+        burst: 1000,
+        per_second: 1000.0,
+    };
+    let (server, handle) = start(
+        builder()
+            .limits(Limits {
+                rate: RateLimits {
+                    user: Rates {
+                        messages: unlimited,
+                        ..RateLimits::default().user
+                    },
+                    ..RateLimits::default()
+                },
+                ..Limits::default()
+            })
+            .build()
+            .unwrap(),
+    );
+
+    let mut observers = [Client::connect(&server), Client::connect(&server)];
+    observers[0].login("first");
+    observers[1].login("second");
+    assert_eq!(observers[0].receive(), "%join second");
+
+    //
+    // Двое пишут одновременно, а оба наблюдателя должны получить сообщения в порядке идентификаторов.
+    //
+
+    let writers: Vec<_> = ["alex", "roma"]
+        .into_iter()
+        .map(|name| {
+            let mut writer = Client::connect(&server);
+            writer.login(name);
+            spawn(move || {
+                for i in 0..20 {
+                    writer.send(&format!("{name} {i}"));
+                }
+                writer
+            })
+        })
+        .collect();
+    let writers: Vec<Client> = writers.into_iter().map(|w| w.join().unwrap()).collect();
+
+    for observer in &mut observers {
+        let mut ids = Vec::new();
+        while ids.len() < 40 {
+            let line = observer.receive();
+            if let Some(id) = line.strip_prefix('#') {
+                ids.push(id.split(' ').next().unwrap().parse::<u64>().unwrap());
+            }
+        }
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]), "{ids:?}");
+    }
+
+    drop(writers);
+    server.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn file_transfer() {
+    let (server, handle) = start(builder().build().unwrap());
+
+    let mut alex = Client::connect(&server);
+    let mut roma = Client::connect(&server);
+    alex.login("alex");
+    roma.login("roma");
+    assert_eq!(alex.receive(), "%join roma");
+
+    let data = b"hello world";
+    let checksum = format!("{:x}", Sha256::digest(data));
+    let chunk = |id, data: &[u8]| {
+        FileChunk {
+            id,
+            data: data.to_vec(),
+        }
+        .to_string()
+    };
+
+    alex.send(&format!("%file_offer roma 11 {checksum} hello.txt"));
+    assert_eq!(
+        alex.receive(),
+        format!("%file_offered roma 1 11 {checksum} hello.txt")
+    );
+    assert_eq!(
+        roma.receive(),
+        format!("%file_offer alex 1 11 {checksum} hello.txt")
+    );
+
+    // принять файл может только получатель, а куски до его согласия не пересылаются
+    alex.send("%file_accept 1");
+    assert_eq!(alex.receive(), "unknown transfer");
+    alex.send(&chunk(1, data));
+    assert_eq!(alex.receive(), "unknown transfer");
+
+    roma.send("%file_accept 1");
+    assert_eq!(alex.receive(), "%file_accepted 1");
+    alex.send(&chunk(1, data));
+    alex.send("Файл отправил");
+    alex.send("%file_end 1");
+    assert_eq!(roma.receive(), chunk(1, data));
+    assert_eq!(roma.receive(), "#1 alex: Файл отправил");
+    assert_eq!(roma.receive(), "%file_end 1");
+    assert_eq!(alex.receive(), "%sent #1 alex: Файл отправил");
+    assert_eq!(alex.receive(), "%file_sent 1");
+
+    // поврежденный файл не проходит проверку контрольной суммы
+    alex.send(&format!("%file_offer roma 11 {checksum} hello.txt"));
+    assert!(alex.receive().starts_with("%file_offered roma 2 "));
+    assert!(roma.receive().starts_with("%file_offer alex 2 "));
+    roma.send("%file_accept 2");
+    assert_eq!(alex.receive(), "%file_accepted 2");
+    alex.send(&chunk(2, b"hello there"));
+    alex.send("%file_end 2");
+    assert_eq!(roma.receive(), chunk(2, b"hello there"));
+    assert_eq!(roma.receive(), "%file_failed 2 checksum mismatch");
+    assert_eq!(alex.receive(), "%file_failed 2 checksum mismatch");
+
+    alex.send(&format!("%file_offer roma 11 {checksum} hello.txt"));
+    assert!(alex.receive().starts_with("%file_offered roma 3 "));
+    assert!(roma.receive().starts_with("%file_offer alex 3 "));
+    roma.send("%file_decline 3");
+    assert_eq!(alex.receive(), "%file_declined 3");
+
+    alex.send(&format!("%file_offer roma 100000000 {checksum} big.log"));
+    assert_eq!(alex.receive(), "file too large");
+    alex.send(&format!("%file_offer nobody 11 {checksum} hello.txt"));
+    assert_eq!(alex.receive(), "unknown user");
+
+    server.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn multi_line_messages() {
+    let path = std::env::temp_dir().join(format!("simple-chat-history-{}", Uuid::new_v4()));
+    let history = FileHistoryStorage::open(&path).unwrap();
+    let (server, handle) = start(builder().history_storage(history).build().unwrap());
+
+    let mut alex = Client::connect(&server);
+    alex.login("alex");
+    let mut roma = Client::connect(&server);
+    roma.login("roma");
+    alex.receive();
+
+    //
+    // Блок %begin/%end - одно сообщение. Строки, начинающиеся с '%', экранируются еще одним '%'.
+    //
+
+    for line in [
+        "%begin",
+        "panicked at src/main.rs:",
+        "  %x",
+        "%%end",
+        "",
+        "%end",
+    ] {
+        alex.send(line);
+    }
+
+    assert_eq!(roma.receive(), "%begin");
+    assert_eq!(roma.receive(), "#1 alex: panicked at src/main.rs:");
+    assert_eq!(roma.receive(), "  %x");
+    assert_eq!(roma.receive(), "%%end");
+    assert_eq!(roma.receive(), "");
+    assert_eq!(roma.receive(), "%end");
+
+    assert_eq!(alex.receive(), "%begin");
+    assert_eq!(alex.receive(), "%%sent #1 alex: panicked at src/main.rs:");
+    for line in ["  %x", "%%end", "", "%end"] {
+        assert_eq!(alex.receive(), line);
+    }
+
+    //
+    // Одна строка, начинающаяся с '%', в блоке - тоже обычное сообщение.
+    //
+
+    for line in ["%begin", "%%show_users", "%end"] {
+        alex.send(line);
+    }
+    assert_eq!(roma.receive(), "#2 alex: %show_users");
+
+    server.shutdown();
+    handle.join().unwrap();
+
+    let history = FileHistoryStorage::open(&path).unwrap();
+    assert_eq!(
+        history.get(1).unwrap().unwrap().text,
+        "panicked at src/main.rs:\n  %x\n%end\n"
+    );
+    std::fs::remove_file(&path).unwrap();
+
+    //
+    // Слишком длинный блок: сервер сообщает об ошибке и закрывает соединение.
+    //
+
+    let (server, handle) = start(
+        ChatServer::builder()
+            .address("127.0.0.1:0")
+            .limits(Limits {
+                max_block_length: 64,
+                ..Limits::default()
+            })
+            .build()
+            .unwrap(),
+    );
+
+    let mut alex = Client::connect(&server);
+    alex.login("alex");
+    alex.send("%begin");
+    for _ in 0..4 {
+        alex.send("0123456789012345678901234567890");
+    }
+    assert_eq!(alex.receive(), "message too long");
+    assert_eq!(alex.receive_raw(), "");
+
+    server.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn framed_transport() {
+    let (server, handle) = start(builder().build().unwrap());
+
+    let mut roma = Client::connect(&server);
+    roma.login("roma");
+
+    //
+    // Клиент просит перейти на кадры первой строкой, а дальше обменивается с сервером только кадрами.
+    //
+
+    let mut alex = Client::connect(&server);
+    alex.send(FRAMED);
+    assert_eq!(alex.receive_raw(), FRAMED);
+
+    let send = |client: &mut Client, frame: Frame| {
+        client
+            .reader
+            .get_mut()
+            .write_all(&Framing::Frames.encode(&frame))
+            .unwrap();
+    };
+    let receive = |client: &mut Client| {
+        Framing::Frames
+            .read(&mut client.reader, 4096, 1024 * 1024)
+            .unwrap()
+            .unwrap()
+    };
+
+    send(&mut alex, Frame::Line("%login alex".to_string()));
+    assert_eq!(roma.receive(), "%join alex");
+
+    // текст с переводами строк передается как есть, без экранирования
+    send(&mut alex, Frame::Text("%end\n  at main.rs".to_string()));
+    assert_eq!(roma.receive(), "%begin");
+    assert_eq!(roma.receive(), "#1 alex: %end");
+    assert_eq!(roma.receive(), "  at main.rs");
+    assert_eq!(roma.receive(), "%end");
+
+    let Frame::Text(sent) = receive(&mut alex) else {
+        panic!("expected text frame");
+    };
+    assert!(sent.starts_with("%sent #1 "));
+    assert!(sent.ends_with(" alex: %end\n  at main.rs"));
+
+    roma.send("hi alex");
+    assert_eq!(roma.receive(), "%sent #2 roma: hi alex");
+    let Frame::Line(line) = receive(&mut alex) else {
+        panic!("expected line frame");
+    };
+    assert!(line.starts_with("#2 ") && line.ends_with(" roma: hi alex"));
+
+    //
+    // Куски файла получатель с кадрами получает двоичными, без base64.
+    //
+
+    let data = b"hello world";
+    let checksum = format!("{:x}", Sha256::digest(data));
+    let chunk = FileChunk {
+        id: 1,
+        data: data.to_vec(),
+    };
+
+    roma.send(&format!("%file_offer alex 11 {checksum} hello.txt"));
+    roma.receive();
+    assert_eq!(
+        receive(&mut alex),
+        Frame::Line(format!("%file_offer roma 1 11 {checksum} hello.txt"))
+    );
+    send(&mut alex, Frame::Line("%file_accept 1".to_string()));
+    assert_eq!(roma.receive(), "%file_accepted 1");
+    roma.send(&chunk.to_string());
+    roma.send("%file_end 1");
+    assert_eq!(receive(&mut alex), Frame::FileChunk(chunk));
+    assert_eq!(receive(&mut alex), Frame::Line("%file_end 1".to_string()));
+
+    //
+    // Неизвестный тип кадра - ошибка, после которой сервер закрывает соединение.
+    //
+
+    alex.reader.get_mut().write_all(&[0, 0, 0, 1, 9]).unwrap();
+    assert_eq!(
+        receive(&mut alex),
+        Frame::Line("invalid or empty input".to_string())
+    );
+    assert_eq!(
+        Framing::Frames
+            .read(&mut alex.reader, 4096, 1024 * 1024)
+            .unwrap(),
+        None
+    );
+
+    server.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn hello() {
+    let (server, handle) = start(builder().build().unwrap());
+
+    //
+    // Сервер отвечает своей версией, возможностями и ограничениями.
+    //
+
+    let mut alex = Client::connect(&server);
+    alex.send("%hello 1 multi_line files");
+    let Command::Hello(hello) = Command::new(&alex.receive()).unwrap() else {
+        panic!("expected %hello");
+    };
+    assert_eq!(hello.version, Hello::VERSION);
+    assert!(hello.has("history") && hello.has("framed"));
+    assert_eq!(hello.limit("max_line_length"), Some(4096));
+
+    // поздороваться можно только первой строкой
+    alex.login("alex");
+    alex.send("%hello 1");
+    assert_eq!(alex.receive(), "invalid or empty input");
+
+    //
+    // Клиенту другой версии сервер сообщает свою версию и ошибку и закрывает соединение.
+    //
+
+    let mut roma = Client::connect(&server);
+    roma.send("%hello 2 multi_line");
+    assert!(roma.receive().starts_with("%hello 1 "));
+    assert_eq!(roma.receive(), "unsupported protocol version");
+    assert_eq!(roma.receive_raw(), "");
+
+    //
+    // Если клиент называет возможность framed, после приветствия обе стороны переходят на кадры.
+    //
+
+    let mut roma = Client::connect(&server);
+    roma.send("%hello 1 framed");
+    assert!(roma.receive().starts_with("%hello 1 "));
+    roma.reader
+        .get_mut()
+        .write_all(&Framing::Frames.encode(&Frame::Line("%login roma".to_string())))
+        .unwrap();
+    assert_eq!(alex.receive(), "%join roma");
+
+    server.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn websocket_gateway() {
+    let (server, handle) = start(builder().websocket_address("127.0.0.1:0").build().unwrap());
+    let websocket_addr = server.websocket_addr().unwrap();
+
+    //
+    // Рукопожатие по HTTP. Ключ и ответ на него - пример из RFC 6455.
+    //
+
+    let connection = TcpStream::connect(websocket_addr).unwrap();
+    connection
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut roma = Client {
+        reader: BufReader::new(connection),
+    };
+    roma.reader
+        .get_mut()
+        .write_all(
+            b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+    assert_eq!(roma.receive_raw(), "HTTP/1.1 101 Switching Protocols");
+    let mut headers = Vec::new();
+    loop {
+        let header = roma.receive_raw();
+        if header.is_empty() {
+            break;
+        }
+        headers.push(header);
+    }
+    assert!(headers.contains(&"Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_string()));
+
+    let send = |client: &mut Client, text: &str| {
+        let message = Message::Text(text.to_string());
+        client
+            .reader
+            .get_mut()
+            .write_all(&websocket::encode(&message, Some([1, 2, 3, 4])))
+            .unwrap();
+    };
+    let receive = |client: &mut Client| match websocket_frame(client) {
+        (0x81, payload) => String::from_utf8(payload).unwrap(),
+        other => panic!("expected text message, got {other:?}"),
+    };
+
+    //
+    // Клиенты WebSocket и TCP видят друг друга и переписываются в одном чате.
+    //
+
+    let mut alex = Client::connect(&server);
+    alex.login("alex");
+
+    send(&mut roma, "%login roma");
+    assert_eq!(alex.receive(), "%join roma");
+
+    send(&mut roma, "привет из браузера");
+    assert_eq!(alex.receive(), "#1 roma: привет из браузера");
+    assert!(receive(&mut roma).ends_with(" roma: привет из браузера"));
+
+    alex.send("hi roma");
+    alex.receive();
+    assert!(receive(&mut roma).ends_with(" alex: hi roma"));
+
+    // сообщение с переводами строк - многострочное сообщение
+    send(&mut roma, "%first\nsecond");
+    assert_eq!(alex.receive(), "%begin");
+    assert_eq!(alex.receive(), "#3 roma: %first");
+    assert_eq!(alex.receive(), "second");
+    assert_eq!(alex.receive(), "%end");
+    assert!(receive(&mut roma).ends_with(" roma: %first\nsecond"));
+
+    send(&mut roma, "%show_users");
+    let users = receive(&mut roma);
+    assert!(users.contains("alex") && users.contains("roma"));
+
+    //
+    // На ping сервер отвечает pong, на close - таким же close.
+    //
+
+    roma.reader
+        .get_mut()
+        .write_all(&[0x89, 0x82, 1, 2, 3, 4, b'h' ^ 1, b'i' ^ 2])
+        .unwrap();
+    assert_eq!(websocket_frame(&mut roma), (0x8A, b"hi".to_vec()));
+
+    roma.reader
+        .get_mut()
+        .write_all(&[0x88, 0x82, 1, 2, 3, 4, 0x03 ^ 1, 0xE8 ^ 2])
+        .unwrap();
+    assert_eq!(websocket_frame(&mut roma), (0x88, vec![0x03, 0xE8]));
+    assert_eq!(alex.receive(), "%leave roma");
+
+    //
+    // Немаскированный кадр клиента - нарушение протокола: ошибка и close с кодом 1002.
+    //
+
+    let mut vova = websocket_client(websocket_addr);
+    vova.reader
+        .get_mut()
+        .write_all(&websocket::encode(
+            &Message::Text("%login vova".to_string()),
+            None,
+        ))
+        .unwrap();
+    assert_eq!(
+        websocket_frame(&mut vova),
+        (0x81, b"invalid or empty input".to_vec())
+    );
+    assert_eq!(websocket_frame(&mut vova), (0x88, vec![0x03, 0xEA]));
+
+    //
+    // Обычный HTTP-запрос на этот адрес - не рукопожатие.
+    //
+
+    let mut browser = TcpStream::connect(websocket_addr).unwrap();
+    browser
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    browser.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+
+    server.shutdown();
+    handle.join().unwrap();
+}
+
+#[test]
+fn http_api() {
+    let (server, handle) = start(
+        builder()
+            .api_address("127.0.0.1:0")
+            .api_token("secret", "ci")
+            .build()
+            .unwrap(),
+    );
+    let api_addr = server.api_addr().unwrap();
+
+    //
+    // Ответ на запрос к API: строка статуса и тело.
+    //
+
+    let request = |method: &str, path: &str, token: &str, body: &str| {
+        let mut http = TcpStream::connect(api_addr).unwrap();
+        write!(
+            http,
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {token}\r\n\
+             Content-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+        let mut response = String::new();
+        http.read_to_string(&mut response).unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.lines().next().unwrap().to_string(), body.to_string())
+    };
+
+    let mut alex = Client::connect(&server);
+    alex.login("alex");
+    let mut roma = Client::connect(&server);
+    roma.login("roma");
+    alex.receive();
+
+    //
+    // Сообщение из API клиенты получают так же, как сообщение от другого клиента.
+    //
+
+    let (status, body) = request(
+        "POST",
+        "/rooms/general/messages",
+        "secret",
+        "build #42 passed\n",
+    );
+    assert_eq!(status, "HTTP/1.1 201 Created");
+    assert!(body.starts_with("#1 ") && body.ends_with(" ci: build #42 passed\n"));
+    assert_eq!(alex.receive(), "#1 ci: build #42 passed");
+    assert_eq!(roma.receive(), "#1 ci: build #42 passed");
+
+    let (status, _) = request(
+        "POST",
+        "/rooms/general/messages",
+        "secret",
+        "@roma глянь логи",
+    );
+    assert_eq!(status, "HTTP/1.1 201 Created");
+    assert_eq!(alex.receive(), "#2 ci: @roma глянь логи");
+    assert_eq!(roma.receive(), "%mention #2 ci: @roma глянь логи");
+
+    let (status, body) = request("GET", "/users", "secret", "");
+    assert_eq!(
+        (status.as_str(), body.as_str()),
+        ("HTTP/1.1 200 OK", "alex\nroma\n")
+    );
+
+    let (status, body) = request(
+        "GET",
+        "/history?since=2000-01-01T00%3A00%3A00Z",
+        "secret",
+        "",
+    );
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(body.lines().count(), 2);
+    assert!(body
+        .lines()
+        .last()
+        .unwrap()
+        .ends_with(" ci: @roma глянь логи"));
+
+    //
+    // Клиент, который не дописал запрос, не задерживает остальных.
+    //
+
+    let mut slow = TcpStream::connect(api_addr).unwrap();
+    write!(slow, "GET /users HTTP/1.1\r\n").unwrap();
+    let (status, _) = request("GET", "/users", "secret", "");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    drop(slow);
+
+    //
+    // Без токена, в несуществующую комнату и пустое сообщение - ошибки.
+    //
+
+    let (status, _) = request("GET", "/users", "wrong", "");
+    assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+    let (status, _) = request("POST", "/rooms/random/messages", "secret", "hi");
+    assert_eq!(status, "HTTP/1.1 404 Not Found");
+    let (status, body) = request("POST", "/rooms/general/messages", "secret", "");
+    assert_eq!(
+        (status.as_str(), body.as_str()),
+        ("HTTP/1.1 400 Bad Request", "missing argument\n")
+    );
+    let (status, _) = request("GET", "/history?since=yesterday", "secret", "");
+    assert_eq!(status, "HTTP/1.1 400 Bad Request");
+
+    server.shutdown();
+    handle.join().unwrap();
+
+    // имя с пробелом испортило бы файл истории
+    assert!(matches!(
+        builder().api_token("secret", "ci bot").build(),
+        Err(Error::InvalidInput)
+    ));
+}
+
+/// HTTP-заглушка для вебхуков: отдает тела всех запросов в канал и отвечает на них
+/// по очереди кодами из `statuses`, а когда они кончатся - `204 No Content`.
+fn webhook_stub(statuses: Vec<&'static str>) -> (String, std::sync::mpsc::Receiver<String>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    spawn(move || {
+        let mut statuses = statuses.into_iter();
+        for connection in listener.incoming() {
+            let connection = connection.unwrap();
+            let mut reader = BufReader::new(&connection);
+
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim_end().is_empty() {
+                    break;
+                }
+                if let Some(value) = header.strip_prefix("Content-Length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+
+            let status = statuses.next().unwrap_or("204 No Content");
+            write!(
+                &connection,
+                "HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n"
+            )
+            .unwrap();
+            if sender.send(String::from_utf8(body).unwrap()).is_err() {
+                return;
+            }
+        }
+    });
+
+    (url, receiver)
+}
+
+#[test]
+fn webhooks() {
+    let (all_url, all) = webhook_stub(vec!["500 Internal Server Error"]);
+    let (deploys_url, deploys) = webhook_stub(vec![]);
+
+    let (server, handle) = start(
+        builder()
+            .webhook(
+                Webhook::new(&all_url)
+                    .unwrap()
+                    .presence(true)
+                    .retry(3, Duration::from_millis(10)),
+            )
+            .webhook(Webhook::new(&deploys_url).unwrap().keyword("deploy"))
+            .build()
+            .unwrap(),
+    );
+    let receive = |events: &std::sync::mpsc::Receiver<String>| {
+        events.recv_timeout(Duration::from_secs(5)).unwrap()
+    };
+
+    let mut alex = Client::connect(&server);
+    alex.login("alex");
+    let mut roma = Client::connect(&server);
+    roma.login("roma");
+    assert_eq!(alex.receive(), "%join roma");
+
+    alex.send("hello");
+    assert_eq!(alex.receive(), "%sent #1 alex: hello");
+    assert_eq!(roma.receive(), "#1 alex: hello");
+    roma.send("@alex Deploy done");
+    assert_eq!(alex.receive(), "%mention #2 roma: @alex Deploy done");
+
+    //
+    // Первую попытку заглушка отклонила, событие доставлено со второй.
+    //
+
+    let join = receive(&all);
+    assert!(join.starts_with(r#"{"event":"join","user":"alex","time":""#));
+    assert_eq!(receive(&all), join);
+    assert!(receive(&all).starts_with(r#"{"event":"join","user":"roma","#));
+
+    let hello = receive(&all);
+    assert!(hello.starts_with(r#"{"event":"message","room":"general","id":1,"#));
+    assert!(hello.ends_with(r#","parent":null,"from":"alex","text":"hello","mentions":[]}"#));
+
+    let deploy = receive(&all);
+    assert!(deploy.ends_with(r#""from":"roma","text":"@alex Deploy done","mentions":["alex"]}"#));
+
+    // второй вебхук получает только сообщения со словом deploy
+    assert_eq!(receive(&deploys), deploy);
+
+    roma.send("%bye");
+    assert_eq!(alex.receive(), "%leave roma");
+    assert!(receive(&all).starts_with(r#"{"event":"leave","user":"roma","#));
+    assert!(deploys.try_recv().is_err());
+
+    server.shutdown();
+    handle.join().unwrap();
+}
+
+/// Бот, который приветствует каждого, кто залогинился.
+struct Greeter;
+
+impl Bot for Greeter {
+    fn name(&self) -> &str {
+        "greeter"
+    }
+
+    fn on_command(&self, sender: &Sender, command: &Command) -> Vec<BotReply> {
+        match (command, sender.user) {
+            (Command::Login(_), Some(user)) => vec![BotReply::Notice(format!("welcome, {user}"))],
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Бот, который запоминает все команды, которые ему передали.
+struct Eavesdropper(Arc<Mutex<Vec<String>>>);
+
+impl Bot for Eavesdropper {
+    fn name(&self) -> &str {
+        "eavesdropper"
+    }
+
+    fn on_command(&self, _sender: &Sender, command: &Command) -> Vec<BotReply> {
+        self.0.lock().unwrap().push(command.to_string());
+        Vec::new()
+    }
+}
+
+#[test]
+fn bots() {
+    let overheard = Arc::new(Mutex::new(Vec::new()));
+    let (server, handle) = start(
+        builder()
+            .bot(EchoBot::new("echo"))
+            .bot(TimeBot)
+            .bot(Greeter)
+            .bot(Eavesdropper(overheard.clone()))
+            .build()
+            .unwrap(),
+    );
+
+    let mut alex = Client::connect(&server);
+    alex.send("%login alex");
+    assert_eq!(alex.receive(), "%notice welcome, alex");
+    let mut roma = Client::connect(&server);
+    roma.send("%login roma");
+    assert_eq!(roma.receive(), "%notice welcome, roma");
+    assert_eq!(alex.receive(), "%join roma");
+
+    // боты видны как пользователи
+    roma.send("%show_users");
+    assert_eq!(
+        roma.receive(),
+        "%users alex eavesdropper echo greeter roma time"
+    );
+
+    alex.send("@echo как дела?");
+    assert_eq!(alex.receive(), "%sent #1 alex: @echo как дела?");
+    assert_eq!(alex.receive(), "%mention #2 echo: @alex как дела?");
+    assert_eq!(roma.receive(), "#1 alex: @echo как дела?");
+    assert_eq!(roma.receive(), "#2 echo: @alex как дела?");
+
+    roma.send("!time");
+    assert_eq!(roma.receive(), "%sent #3 roma: !time");
+    let time = roma.receive();
+    let time = time.strip_prefix("%mention #4 time: @roma ").unwrap();
+    assert!(parse_time(time).is_ok());
+    assert_eq!(alex.receive(), "#3 roma: !time");
+    assert!(alex.receive().starts_with("#4 time: @roma "));
+
+    // под именем бота залогиниться нельзя
+    let mut impostor = Client::connect(&server);
+    impostor.send("%login echo");
+    assert_eq!(impostor.receive(), "permission denied");
+
+    // личные сообщения и передачи файлов ботам не передаются, а вход - без токена
+    alex.send("%dm roma секрет");
+    assert_eq!(alex.receive(), "%dm alex roma секрет");
+    assert_eq!(roma.receive(), "%dm alex roma секрет");
+    let mut admin = Client::connect(&server);
+    admin.send(&format!("%login vova {ADMIN_TOKEN}"));
+    admin.receive();
+
+    server.shutdown();
+    handle.join().unwrap();
+
+    let overheard = overheard.lock().unwrap();
+    assert!(overheard.contains(&"%login vova".to_string()));
+    assert!(!overheard
+        .iter()
+        .any(|command| command.contains("секрет") || command.contains(ADMIN_TOKEN)));
+}
+
+/// Заменяет бранное слово звездочками.
+struct Censor;
+
+impl Hook for Censor {
+    fn before_command(&self, _sender: &Sender, command: &Command) -> Action {
+        match command {
+            Command::Message(cmd) if cmd.message.contains("damn") => {
+                Action::Rewrite(Command::text(cmd.message.replace("damn", "****")))
+            }
+            _ => Action::Continue,
+        }
+    }
+}
+
+/// Запрещает личные сообщения.
+struct NoDirectMessages;
+
+impl Hook for NoDirectMessages {
+    fn before_command(&self, _sender: &Sender, command: &Command) -> Action {
+        match command {
+            Command::DirectMessage(_) => Action::Reject("direct messages are disabled".to_string()),
+            _ => Action::Continue,
+        }
+    }
+}
+
+/// Обработчик с ошибкой: паникует на сообщении "boom".
+struct Panicky;
+
+impl Hook for Panicky {
+    fn before_command(&self, _sender: &Sender, command: &Command) -> Action {
+        if *command == Command::text("boom".to_string()) {
+            panic!("boom");
+        }
+        Action::Continue
+    }
+}
+
+/// Превращает "!all" в сообщение с упоминанием roma.
+struct Summon;
+
+impl Hook for Summon {
+    fn before_command(&self, _sender: &Sender, command: &Command) -> Action {
+        match command {
+            Command::Message(cmd) if cmd.message == "!all" => {
+                Action::Rewrite(Command::text("@roma, зайди".to_string()))
+            }
+            _ => Action::Continue,
+        }
+    }
+}
+
+/// Записывает события, которые до него дошли.
+struct Recorder(Arc<Mutex<Vec<String>>>);
+
+impl Hook for Recorder {
+    fn before_command(&self, _sender: &Sender, command: &Command) -> Action {
+        if let Command::Message(cmd) = command {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("message {}", cmd.message));
+        }
+        Action::Continue
+    }
+
+    fn after_broadcast(&self, entry: &HistoryEntry, _mentioned: &[String]) {
+        self.0
+            .lock()
+            .unwrap()
+            .push(format!("broadcast #{}", entry.id));
+    }
+
+    fn on_login(&self, _connection_id: Uuid, user: &str) {
+        self.0.lock().unwrap().push(format!("login {user}"));
+    }
+
+    fn on_disconnect(&self, _connection_id: Uuid) {
+        self.0.lock().unwrap().push("disconnect".to_string());
+    }
+}
+
+#[test]
+fn hooks() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let (server, handle) = start(
+        builder()
+            .hook(Censor)
+            .hook(NoDirectMessages)
+            .hook(Panicky)
+            .hook(Recorder(events.clone()))
+            .build()
+            .unwrap(),
+    );
+
+    let mut alex = Client::connect(&server);
+    alex.login("alex");
+    let mut roma = Client::connect(&server);
+    roma.login("roma");
+    assert_eq!(alex.receive(), "%join roma");
+
+    alex.send("damn it");
+    assert_eq!(alex.receive(), "%sent #1 alex: **** it");
+    assert_eq!(roma.receive(), "#1 alex: **** it");
+
+    // паника в обработчике не разрывает соединение, остальные обработчики вызываются
+    alex.send("boom");
+    assert_eq!(alex.receive(), "%sent #2 alex: boom");
+
+    alex.send("%dm roma привет");
+    assert_eq!(alex.receive(), "rejected: direct messages are disabled");
+
+    roma.send("%bye");
+    assert_eq!(alex.receive(), "%leave roma");
+
+    server.shutdown();
+    handle.join().unwrap();
+
+    assert_eq!(
+        *events.lock().unwrap(),
+        [
+            "login alex",
+            "login roma",
+            "message **** it",
+            "broadcast #1",
+            "message boom",
+            "broadcast #2",
+            "disconnect",
+            "disconnect",
+        ]
+    );
+}